@@ -0,0 +1,324 @@
+//! Headless command line interface. Nothing in here touches GTK.
+
+use crate::error::{Error, Result};
+use crate::project::{self, ProjectState};
+use crate::smf::MidiWorkspace;
+use crate::smf::division::{Division, SmpteFps};
+use crate::smf::play::{self, MidiMessage, MidiReceiver};
+use crate::smf::render;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const USAGE: &str = "\
+usage: midie [FILE]
+       midie -- FILE                         open a file called like a command
+       midie <command> [args...]
+
+commands:
+  info <in.mid>                              print format, tracks and length
+  dump <in.mid> [track]                      print every event
+  play <in.mid> [--port N | --synth]         play through a midi port (default 0) or the built-in synth
+  convert <in.mid> <out.mid|out.wav> [--format 0|1] [--division PPQ|smpte:FPS:TICKS]
+                                             rewrite a file, possibly in another format or time division,
+                                             or render it to wav. FPS is 24, 25, 29.97 or 30
+  transpose <in.mid> <out.mid> <semitones> [track]
+  quantize <in.mid> <out.mid> <ticks> [track]
+  resolution <in.mid> <out.mid> <ppq>        change ticks per quarter note, reporting rounded events
+  merge <in.mid> <out.mid> [track...]        merge tracks (default: all) into one
+  split <in.mid> <out.mid> <track>           split a track into one track per channel
+
+options:
+  --lenient                                  repair a broken in.mid instead of refusing it,
+                                             printing what was repaired
+
+in.mid and out.mid may also be midie projects (.midie). Muted tracks of a project are
+not played or rendered, and a project plays on the port its tracks are routed to.";
+
+const COMMANDS: &[&str] = &["info", "dump", "play", "convert", "transpose", "quantize", "resolution", "merge", "split", "help"];
+
+/// whether the first argument names a subcommand rather than something for the GUI.
+pub fn is_command(arg: &str) -> bool {
+    COMMANDS.contains(&arg) || arg == "--help" || arg == "-h"
+}
+
+/// runs the subcommand given in `args` (without the program name).
+pub fn run(args: &[String]) -> Result<()> {
+    let command = args.get(0).map(|s| s.as_str()).unwrap_or("help");
+    // the only option every command takes, wherever it is given
+    let lenient = args.iter().any(|a| a == "--lenient");
+    let args: Vec<String> = args.iter().skip(1).filter(|a| *a != "--lenient").cloned().collect();
+    let args = &args[..];
+    let load = |path: &str| load(path, lenient);
+    match command {
+        "info" => info(&load(arg(args, 0, "input file")?)?.0),
+        "dump" => dump(&load(arg(args, 0, "input file")?)?.0, opt_number(args, 1, "track")?),
+        "play" => {
+            let (ws, state) = load(arg(args, 0, "input file")?)?;
+            play(&ws, &state, &args[1.min(args.len())..])
+        },
+        "convert" => {
+            let (mut ws, mut state) = load(arg(args, 0, "input file")?)?;
+            let mut i = 2;
+            while i < args.len() {
+                match args[i].as_str() {
+                    "--format" => {
+                        let format = match arg(args, i + 1, "format")? {
+                            "0" => rimd::SMFFormat::Single,
+                            "1" => rimd::SMFFormat::MultiTrack,
+                            other => return Err(Error::InvalidArgument(format!("invalid format: {}", other)))
+                        };
+                        project::convert_format(&mut ws, &mut state, format)?;
+                    },
+                    "--division" => ws.convert_division(division(arg(args, i + 1, "division")?)?)?,
+                    other => return Err(Error::InvalidArgument(format!("unknown option: {}", other)))
+                }
+                i += 2;
+            }
+            save(&mut ws, &state, arg(args, 1, "output file")?)
+        },
+        "transpose" => {
+            let (mut ws, state) = load(arg(args, 0, "input file")?)?;
+            let semitones = number(arg(args, 2, "semitones")?, "semitones")?;
+            ws.transpose(opt_number(args, 3, "track")?, semitones)?;
+            save(&mut ws, &state, arg(args, 1, "output file")?)
+        },
+        "quantize" => {
+            let (mut ws, state) = load(arg(args, 0, "input file")?)?;
+            let grid = number(arg(args, 2, "ticks")?, "ticks")?;
+            ws.quantize(opt_number(args, 3, "track")?, grid)?;
+            save(&mut ws, &state, arg(args, 1, "output file")?)
+        },
+        "resolution" => {
+            let (mut ws, state) = load(arg(args, 0, "input file")?)?;
+            let report = ws.set_resolution(number(arg(args, 2, "ppq")?, "ppq")?)?;
+            println!("{}", report);
+            for rounded in &report.rounded {
+                println!("  track {}: tick {} -> {}", rounded.track, rounded.from_tick, rounded.to_tick);
+            }
+            save(&mut ws, &state, arg(args, 1, "output file")?)
+        },
+        "merge" => {
+            let (mut ws, mut state) = load(arg(args, 0, "input file")?)?;
+            let tracks = if args.len() > 2 {
+                args[2..].iter().map(|t| number(t, "track")).collect::<Result<Vec<usize>>>()?
+            } else {
+                (0..ws.track_count()).collect()
+            };
+            project::merge_tracks(&mut ws, &mut state, &tracks)?;
+            save(&mut ws, &state, arg(args, 1, "output file")?)
+        },
+        "split" => {
+            let (mut ws, mut state) = load(arg(args, 0, "input file")?)?;
+            let track = number(arg(args, 2, "track")?, "track")?;
+            let count = project::split_track_by_channel(&mut ws, &mut state, track)?;
+            println!("track {} split into {} tracks", track, count);
+            save(&mut ws, &state, arg(args, 1, "output file")?)
+        },
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => Err(Error::InvalidArgument(format!("unknown command: {}\n{}", command, USAGE)))
+    }
+}
+
+fn arg<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str> {
+    args.get(index).map(|s| s.as_str()).ok_or_else(|| Error::InvalidArgument(format!("missing {}\n{}", name, USAGE)))
+}
+
+fn number<T: std::str::FromStr>(value: &str, name: &str) -> Result<T> {
+    value.parse().map_err(|_| Error::InvalidArgument(format!("invalid {}: {}", name, value)))
+}
+
+fn opt_number<T: std::str::FromStr>(args: &[String], index: usize, name: &str) -> Result<Option<T>> {
+    args.get(index).map(|v| number(v, name)).transpose()
+}
+
+/// "480" or "smpte:25:40"
+fn division(value: &str) -> Result<Division> {
+    let parts: Vec<&str> = value.split(':').collect();
+    match parts[..] {
+        [ppq] => Ok(Division::TicksPerQuarter(number(ppq, "division")?)),
+        ["smpte", fps, ticks_per_frame] => {
+            let fps = match fps {
+                "24" => SmpteFps::Fps24,
+                "25" => SmpteFps::Fps25,
+                "29.97" | "29" => SmpteFps::Fps29_97,
+                "30" => SmpteFps::Fps30,
+                _ => return Err(Error::InvalidArgument(format!("invalid frame rate: {}", fps)))
+            };
+            Ok(Division::Smpte(fps, number(ticks_per_frame, "ticks per frame")?))
+        },
+        _ => Err(Error::InvalidArgument(format!("invalid division: {}", value)))
+    }
+}
+
+/// the workspace and, for a project, what is kept with it. A broken midi file is an error
+/// unless `lenient`; then what was repaired is printed, and the repaired file is used.
+fn load(path: &str, lenient: bool) -> Result<(MidiWorkspace, ProjectState)> {
+    if project::is_project_path(Path::new(path)) {
+        return project::open(path);
+    }
+    if !lenient {
+        let ws = MidiWorkspace::from_smf_file(path).map_err(|e| match e {
+            Error::Parse(message) => Error::Parse(format!("{}: {} (--lenient repairs what it can)", path, message)),
+            e => e
+        })?;
+        return Ok((ws, ProjectState::default()));
+    }
+    let (ws, warnings) = MidiWorkspace::from_smf_file_lenient(path)?;
+    for warning in warnings {
+        eprintln!("midie: {}: {}", path, warning);
+    }
+    Ok((ws, ProjectState::default()))
+}
+
+/// writes a midi file, a project or, leaving out muted tracks, a wav file
+fn save(ws: &mut MidiWorkspace, state: &ProjectState, path: &str) -> Result<()> {
+    let is_wav = Path::new(path).extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("wav"));
+    if is_wav {
+        return render::render_to_wav_file(&project::audible(ws, state)?, path, render::DEFAULT_SAMPLE_RATE);
+    }
+    if project::is_project_path(Path::new(path)) {
+        return project::save(path, ws, state, false);
+    }
+    crate::smf::save::write_atomic(Path::new(path), false, |writer| ws.write_all(writer))
+}
+
+fn info(ws: &MidiWorkspace) -> Result<()> {
+    println!("format: {:?}", ws.format());
+    println!("division: {}", ws.division());
+    let length = ws.timed_midi_events()?.last().map(|(secs, _)| *secs).unwrap_or(0.0);
+    println!("length: {:.2}s", length);
+    if ws.track_count() > 0 {
+        for (tick, usec) in &ws.create_tempo_info(0)?.changes {
+            println!("tempo: {} bpm at tick {}", crate::smf::tempo::format_bpm(*usec), tick);
+        }
+        for (tick, ts) in &ws.create_time_signature_info(0)?.changes {
+            println!("time signature: {} at tick {} ({} clocks per click)", ts, tick, ts.clocks_per_click);
+        }
+        for (tick, key) in &ws.create_key_signature_info(0)?.changes {
+            println!("key: {} at tick {}", key, tick);
+        }
+        for marker in &ws.create_marker_info(0)?.markers {
+            println!("{}: {:?} at tick {}", marker.kind.name().to_lowercase(), marker.text, marker.abs_tick);
+        }
+    }
+    println!("tracks: {}", ws.track_count());
+    for i in 0..ws.track_count() {
+        let track = ws.events_abs_tick(i).unwrap();
+        let notes = track.events().iter()
+            .filter(|e| match &e.track_event.event {
+                rimd::Event::Midi(msg) => crate::smf::util::note_on(msg).is_some(),
+                _ => false
+            })
+            .count();
+        println!("  [{}] {} - {} events, {} notes", i, ws.track_name(i).unwrap_or(""), track.events().len(), notes);
+    }
+    Ok(())
+}
+
+fn dump(ws: &MidiWorkspace, track: Option<usize>) -> Result<()> {
+    let tracks = match track {
+        Some(track) if track < ws.track_count() => vec![track],
+        Some(track) => return Err(Error::NoSuchTrack(track)),
+        None => (0..ws.track_count()).collect()
+    };
+    for i in tracks {
+        println!("track {}", i);
+        for event in ws.events_abs_tick(i).unwrap().events() {
+            let (r#type, data) = crate::smf::util::describe_event(&event.track_event.event);
+            match ws.timecode(event.abs_time) {
+                Some(timecode) => println!("{}\t{}\t{}\t{}", event.abs_time, timecode, r#type, data),
+                None => println!("{}\t{}\t{}", event.abs_time, r#type, data)
+            }
+        }
+    }
+    Ok(())
+}
+
+/// plays the audible tracks, on the port they are routed to unless another output is given
+fn play(ws: &MidiWorkspace, state: &ProjectState, options: &[String]) -> Result<()> {
+    let output = match options.get(0).map(|s| s.as_str()) {
+        None => match state.output_port(ws.track_count()) {
+            Some(port) => MidiMessage::ConnectPort(String::from(port)),
+            None => MidiMessage::ChangePort(0)
+        },
+        Some("--synth") => MidiMessage::BuiltinSynth,
+        Some("--port") => MidiMessage::ChangePort(number(arg(options, 1, "port number")?, "port number")?),
+        Some(other) => return Err(Error::InvalidArgument(format!("unknown option: {}", other)))
+    };
+    // check the file before an output is opened
+    ws.timed_midi_events()?;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (errors_tx, errors_rx) = std::sync::mpsc::channel();
+    let receiver = std::thread::spawn(move || MidiReceiver::start_with_errors(rx, errors_tx));
+    let send = |msg| tx.send(msg).map_err(|_| Error::Port(String::from("midi receiver is gone")));
+
+    send(output)?;
+    // the output failed to open; nothing would be heard
+    if let Ok(e) = errors_rx.recv_timeout(Duration::from_millis(100)) {
+        send(MidiMessage::Close)?;
+        return Err(e);
+    }
+    let start = Instant::now() + Duration::from_millis(100);
+    let messages = play::playback_messages(&project::audible(ws, state)?, start)?;
+    let end = messages.iter()
+        .filter_map(|m| match m {
+            MidiMessage::MidiAt(at, _) => Some(*at),
+            _ => None
+        })
+        .max()
+        .unwrap_or(start);
+    for msg in messages {
+        send(msg)?;
+    }
+
+    let now = Instant::now();
+    if end > now {
+        std::thread::sleep(end - now);
+    }
+    // let the last notes ring for a moment
+    std::thread::sleep(Duration::from_millis(500));
+    send(MidiMessage::Close)?;
+    drop(tx);
+    receiver.join().map_err(|_| Error::Port(String::from("midi receiver panicked")))
+}
+
+#[test]
+fn transpose_command() {
+    let out = std::env::temp_dir().join(format!("midie-cli-transpose-{}.mid", std::process::id()));
+    let out_str = out.to_str().unwrap().to_string();
+    let args: Vec<String> = vec!["transpose", "test_midi0.mid", &out_str, "12", "1"]
+        .into_iter().map(String::from).collect();
+    run(&args).unwrap();
+
+    let notes = |ws: &MidiWorkspace| -> Vec<u8> {
+        ws.events_abs_tick(1).unwrap().events().iter()
+            .filter_map(|e| match &e.track_event.event {
+                rimd::Event::Midi(msg) => crate::smf::util::note_on(msg).map(|(_, note, _)| note),
+                _ => None
+            })
+            .collect()
+    };
+    let before = notes(&load("test_midi0.mid", false).unwrap().0);
+    let after = notes(&load(&out_str, false).unwrap().0);
+    assert_eq!(after, before.iter().map(|n| (n + 12).min(127)).collect::<Vec<u8>>());
+    std::fs::remove_file(&out).unwrap();
+}
+
+#[test]
+fn broken_input_needs_lenient() {
+    let out = std::env::temp_dir().join(format!("midie-cli-lenient-{}.mid", std::process::id()));
+    let out_str = out.to_str().unwrap().to_string();
+    let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| String::from(*a)).collect() };
+    let input = "test_corpus/stray_running_status.mid";
+    assert!(matches!(run(&args(&["convert", input, &out_str])), Err(Error::Parse(_))));
+    assert!(!out.exists());
+    run(&args(&["convert", "--lenient", input, &out_str])).unwrap();
+    // what was written is the repaired file, which reads without complaint
+    assert!(load(&out_str, false).is_ok());
+    std::fs::remove_file(&out).unwrap();
+}
@@ -0,0 +1,22 @@
+//! Where midie keeps its files, following the XDG base directory specification.
+
+use std::path::PathBuf;
+
+/// `$XDG_CONFIG_HOME/midie`, or `~/.config/midie`
+pub fn config_dir() -> Option<PathBuf> {
+    base_dir("XDG_CONFIG_HOME", ".config").map(|dir| dir.join("midie"))
+}
+
+/// `$XDG_DATA_HOME/midie`, or `~/.local/share/midie`
+pub fn data_dir() -> Option<PathBuf> {
+    base_dir("XDG_DATA_HOME", ".local/share").map(|dir| dir.join("midie"))
+}
+
+/// the directory in `var`, or `fallback` in the home directory.
+/// Relative paths in `var` are invalid and ignored, as the specification says.
+fn base_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(fallback)))
+}
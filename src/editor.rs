@@ -0,0 +1,47 @@
+//! What the editor shows, which is not part of the midi file itself.
+
+use crate::settings::Color;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_BEAT_WIDTH: f64 = 75.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EditorState {
+    /// track shown in the piano roll
+    pub current_track: u8,
+    /// scroll position of the piano roll, in pixels
+    pub scroll_x: f64,
+    pub scroll_y: f64,
+    /// grid new notes snap to, in ticks
+    pub quantize: u64,
+    /// horizontal zoom: width of a beat in pixels
+    pub beat_width: f64,
+    /// rows selected in the event list of the current track, which are its events in order
+    pub selected_events: Vec<usize>,
+}
+
+/// How a track is shown and played, kept in a project file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TrackSettings {
+    /// color of the notes
+    pub color: Option<Color>,
+    pub mute: bool,
+    pub solo: bool,
+    /// name of the midi output port the track is played on
+    pub port: Option<String>,
+}
+
+impl Default for EditorState {
+    fn default() -> Self {
+        EditorState {
+            current_track: 0,
+            scroll_x: 0.0,
+            scroll_y: 0.0,
+            quantize: crate::smf::DEFAULT_RESOLUTION as u64 / 4,
+            beat_width: DEFAULT_BEAT_WIDTH,
+            selected_events: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+
+/// Everything that can go wrong in midie.
+#[derive(Debug)]
+pub enum Error {
+    /// reading or writing a file failed
+    Io(std::io::Error),
+    /// the file is not a standard midi file, or not one the strict reader accepts
+    Parse(String),
+    /// a meta event whose data does not match its type, e.g. a tempo event without 3 bytes
+    MalformedMeta {
+        command: rimd::MetaCommand,
+        abs_tick: u64,
+        data: Vec<u8>,
+    },
+    /// a track index past the last track
+    NoSuchTrack(usize),
+    /// no time signature is in effect at this tick
+    NoTimeSignature(u64),
+    /// a time signature change would not start a bar
+    NotOnBarLine(u64),
+    /// a midi output port could not be opened or written to
+    Port(String),
+    /// the audio device used by the built-in synth failed
+    Audio(String),
+    /// an argument to an operation or command is not usable
+    InvalidArgument(String),
+    /// a JSON file written by midie (e.g. a recovery session) could not be read
+    Json(serde_json::Error),
+    /// a project file is not a zip archive with the expected entries
+    Project(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn malformed_meta(meta: &rimd::MetaEvent, abs_tick: u64) -> Self {
+        Error::MalformedMeta {
+            command: meta.command,
+            abs_tick,
+            data: meta.data.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Parse(e) => write!(f, "invalid midi file: {}", e),
+            Error::MalformedMeta { command, abs_tick, data } =>
+                write!(f, "malformed {:?} event at tick {}: {:?}", command, abs_tick, data),
+            Error::NoSuchTrack(track) => write!(f, "no such track: {}", track),
+            Error::NoTimeSignature(abs_tick) => write!(f, "no time signature at tick {}", abs_tick),
+            Error::NotOnBarLine(abs_tick) => write!(f, "tick {} is not on a bar line", abs_tick),
+            Error::Port(e) => write!(f, "midi port error: {}", e),
+            Error::Audio(e) => write!(f, "audio device error: {}", e),
+            Error::InvalidArgument(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "invalid JSON: {}", e),
+            Error::Project(e) => write!(f, "invalid project file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<rimd::SMFError> for Error {
+    fn from(e: rimd::SMFError) -> Self {
+        match e {
+            rimd::SMFError::Error(e) => Error::Io(e),
+            e => Error::Parse(e.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        match e.classify() {
+            serde_json::error::Category::Io => Error::Io(e.into()),
+            _ => Error::Json(e)
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        match e {
+            zip::result::ZipError::Io(e) => Error::Io(e),
+            e => Error::Project(e.to_string())
+        }
+    }
+}
+
+impl From<midir::InitError> for Error {
+    fn from(e: midir::InitError) -> Self {
+        Error::Port(e.to_string())
+    }
+}
+
+impl From<midir::SendError> for Error {
+    fn from(e: midir::SendError) -> Self {
+        Error::Port(e.to_string())
+    }
+}
+
+#[test]
+fn smf_io_error_is_io() {
+    let e: Error = rimd::SMFError::Error(std::io::Error::new(std::io::ErrorKind::NotFound, "gone")).into();
+    match e {
+        Error::Io(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        e => panic!("unexpected {:?}", e)
+    }
+}
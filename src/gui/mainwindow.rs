@@ -2,12 +2,53 @@ use gtk::prelude::*;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::sync::mpsc;
+use std::path::{Path, PathBuf};
 
-use crate::smf::play::{MidiPlayer, MidiProber, MidiMessage};
+use crate::smf::play::{MidiPlayer, MidiProber, MidiMessage, OutputStatus, VIRTUAL_PORT_NAME};
+use crate::editor::{EditorState, TrackSettings};
+use crate::project::{self, ProjectState};
+use crate::recent::RecentFiles;
+use crate::recovery;
+use crate::settings::{Color, Output, Settings};
+use crate::smf::MidiWorkspace;
+use super::pianoroll::PianorollContext;
+use super::tempolane::TempoLane;
+use super::ruler::Ruler;
+use super::markerlane::MarkerLane;
+use crate::smf::meter::{Bar, TimeSignature};
+use crate::smf::key::KeySignature;
+use crate::smf::marker::{Marker, MarkerKind};
+use crate::smf::tempo::{self, TempoCurve};
 
-pub fn construct_main_window() {
+/// port number used in the device list for the virtual output port
+const VIRTUAL_PORT_ID: i32 = -1;
+/// port number used in the device list for the built-in synth
+const BUILTIN_SYNTH_ID: i32 = -2;
+/// seconds between looking for midi ports which were plugged in or unplugged
+const PORT_POLL_INTERVAL: u32 = 2;
+
+/// builds the editor window and runs it until it is closed. Opens `file`, if given.
+pub fn construct_main_window(file: Option<PathBuf>) {
     gtk::init().expect("failed to initialize GTK");
 
+    let (tx, rx) = mpsc::channel::<MidiMessage>();
+    let (errors_tx, errors_rx) = mpsc::channel::<crate::Error>();
+    let (status_tx, status_rx) = mpsc::channel::<OutputStatus>();
+    let receiver = std::thread::spawn(move || {
+        crate::smf::play::MidiReceiver::start_with_status(rx, errors_tx, status_tx);
+    });
+
+    run_main_window(file, tx, errors_rx, status_rx);
+    // every sender went with the window; wait for the receiver to silence the
+    // output, or the last note offs would be lost when the process exits
+    if receiver.join().is_err() {
+        error!("the midi receiver panicked");
+    }
+}
+
+/// runs the editor window, sending what is played to `tx`.
+/// Returns after it was closed, when nothing holds on to `tx` any more.
+fn run_main_window(file: Option<PathBuf>, tx: mpsc::Sender<MidiMessage>, errors_rx: mpsc::Receiver<crate::Error>, status_rx: mpsc::Receiver<OutputStatus>) {
     let builder = gtk::Builder::new_from_file("main.glade");
     let settings_builder = gtk::Builder::new_from_file("settings.glade");
 
@@ -24,61 +65,76 @@ pub fn construct_main_window() {
     let _main_viewport = load!(gtk::Viewport, "mainViewport");
 
     let drawarea = load!(gtk::DrawingArea, "mainDrawingArea");
+    let tempo_area = load!(gtk::DrawingArea, "tempoDrawingArea");
+    let tempo_curve_combo = load!(gtk::ComboBoxText, "tempoCurveCombo");
+    let ruler_area = load!(gtk::DrawingArea, "rulerDrawingArea");
+    let marker_area = load!(gtk::DrawingArea, "markerDrawingArea");
 
     let new_toolbar_button = load!(gtk::ToolButton, "newToolbarButton");
-    let open_toolbar_button = load!(gtk::ToolButton, "openToolbarButton");
+    let open_toolbar_button = load!(gtk::MenuToolButton, "openToolbarButton");
     let write_toolbar_button = load!(gtk::ToolButton, "writeToolbarButton");
+    let save_as_toolbar_button = load!(gtk::ToolButton, "saveAsToolbarButton");
     let redraw_button = load!(gtk::ToolButton, "redrawButton");
     let settings_toolbar_button = load!(gtk::ToolButton, "settingsToolbarButton");
+    let panic_toolbar_button = load!(gtk::ToolButton, "panicToolbarButton");
+    let format0_toolbar_button = load!(gtk::ToolButton, "format0ToolbarButton");
+    let format1_toolbar_button = load!(gtk::ToolButton, "format1ToolbarButton");
+    let transpose_up_toolbar_button = load!(gtk::ToolButton, "transposeUpToolbarButton");
+    let transpose_down_toolbar_button = load!(gtk::ToolButton, "transposeDownToolbarButton");
+    let previous_marker_toolbar_button = load!(gtk::ToolButton, "previousMarkerToolbarButton");
+    let next_marker_toolbar_button = load!(gtk::ToolButton, "nextMarkerToolbarButton");
 
     let track_choose_combo = load!(gtk::ComboBox, "trackChooseCombo");
     let track_list_store = load!(gtk::ListStore, "trackListStore");
+    let resolution_combo = load!(gtk::ComboBoxText, "resolutionCombo");
 
     let midi_event_list_store = load!(gtk::ListStore, "midiEventListStore");
-    let _event_list = load!(gtk::TreeView, "mainEventList");
+    let event_list = load!(gtk::TreeView, "mainEventList");
+    let track_color_button = load!(gtk::ColorButton, "trackColorButton");
+    let mute_check = load!(gtk::CheckButton, "muteCheck");
+    let solo_check = load!(gtk::CheckButton, "soloCheck");
+    let track_port_entry = load!(gtk::Entry, "trackPortEntry");
+    let marker_list_store = load!(gtk::ListStore, "markerListStore");
+    let marker_tree_view = load!(gtk::TreeView, "markerTreeView");
+    let marker_text_renderer = load!(gtk::CellRendererText, "markerTextRenderer");
 
     // let event_type_column = load!(gtk::TreeViewColumn, "eventTypeColumn");
     // let event_start_column = load!(gtk::TreeViewColumn, "eventStartColumn");
     // let event_length_column = load!(gtk::TreeViewColumn, "eventLengthColumn");
     // let event_data_column = load!(gtk::TreeViewColumn, "eventDataColumn");
 
-    let ws: Rc<RefCell<crate::smf::MidiWorkspace>> = Rc::new(RefCell::new(crate::smf::MidiWorkspace::default()));
-    let (tx, rx) = mpsc::channel::<MidiMessage>();
-    std::thread::spawn(move || {
-        crate::smf::play::MidiReceiver::start(rx);
+    let settings = Rc::new(RefCell::new(Settings::default_path().map(|p| Settings::load(&p)).unwrap_or_default()));
+
+    let ws: Rc<RefCell<crate::smf::MidiWorkspace>> = Rc::new(RefCell::new(MidiWorkspace::empty_with_resolution(settings.borrow().resolution)));
+    let project = Rc::new(RefCell::new(ProjectState::default()));
+    // outputs which failed to open in the receiver thread, and what is played to
+    let window_c = window.clone();
+    let output_status_label = load!(gtk::Label, "outputStatusLabel");
+    output_status_label.set_text(&OutputStatus::Disconnected.to_string());
+    gtk::timeout_add(200, move || {
+        while let Ok(e) = errors_rx.try_recv() {
+            show_error(&window_c, &format!("Could not open the midi output: {}", e));
+        }
+        if let Some(status) = status_rx.try_iter().last() {
+            output_status_label.set_text(&status.to_string());
+        }
+        gtk::Continue(true)
     });
 
     let tx_c = tx.clone();
-    settings_toolbar_button.connect_clicked(move |_| {
-        let settings_window = settings_builder.get_object::<gtk::Window>("settingsWindow").expect("failed to find settingsWindow");
-        let midi_device_list_store = settings_builder.get_object::<gtk::ListStore>("midiDeviceList").expect("failed to find midiDeviceList");
-        let midi_device_combo = settings_builder.get_object::<gtk::ComboBox>("midiDeviceCombo").expect("failed to find midiDeviceCombo");
-
-        let tx_cc = tx_c.clone();
-        midi_device_combo.connect_changed(move |c| {
-            use std::convert::TryInto;
-            if let Some(iter) = c.get_active_iter() {
-                if let Some(model) = c.get_model() {
-                    let value = model.get_value(&iter, 0);
-                    let port_number = value.get_some::<i32>().unwrap();
-                    match port_number.try_into() {
-                        Ok(port_number) => {
-                            tx_cc.send(MidiMessage::ChangePort(port_number)).unwrap();
-                            debug!("new midi_player instance");
-                        },
-                        Err(e) => error!("invalid port number: {}", e)
-                    }
-                }
-            }
-        });
-
-        settings_window.connect_delete_event(|w, _| Inhibit(w.hide_on_delete()));
-
-        fetch_midi_output_device_list(&midi_device_list_store);
-        // TODO: set active selection to currently selected midi device
-        settings_window.show();
+    panic_toolbar_button.connect_clicked(move |_| {
+        if let Err(e) = tx_c.send(MidiMessage::Panic) {
+            error!("failed to send panic: {}", e);
+        }
     });
 
+    // the output chosen last time
+    if let Some(message) = settings.borrow().output.message() {
+        if let Err(e) = tx.send(message) {
+            error!("failed to open the midi output: {}", e);
+        }
+    }
+
     use super::pianoroll::{Viewport, PianorollConfig, WHITE_KEYS};
     let white_height: f64 = 30.0;
     let white_width: f64 = 60.0;
@@ -101,54 +157,96 @@ pub fn construct_main_window() {
             black_height,
             black_width,
             note_height: (white_height * WHITE_KEYS as f64) / 128.0,
-            beat_width: 75.0,
+            beat_width: settings.borrow().beat_width,
+            white_key_color: settings.borrow().white_key_color,
+            black_key_color: settings.borrow().black_key_color,
+            note_color: settings.borrow().note_color,
         },
         Rc::clone(&ws),
+        Rc::clone(&project),
         tx.clone()
     )));
+    {
+        let mut ps = ps.borrow_mut();
+        ps.set_quantize(settings.borrow().quantize(ws.borrow().resolution().max(1) as u16));
+        ps.set_velocity(settings.borrow().note_velocity);
+    }
 
-    let ws_c = Rc::clone(&ws);
-    let track_store_c = track_list_store.clone();
+    let doc = Document {
+        window: window.clone(),
+        ws: Rc::clone(&ws),
+        ps: Rc::clone(&ps),
+        project: Rc::clone(&project),
+        track_store: track_list_store.clone(),
+        event_list: event_list.clone(),
+        event_store: midi_event_list_store.clone(),
+        marker_store: marker_list_store.clone(),
+        track_combo: track_choose_combo.clone(),
+        track_controls: TrackControls {
+            color: track_color_button.clone(),
+            mute: mute_check.clone(),
+            solo: solo_check.clone(),
+            port: track_port_entry.clone(),
+        },
+        resolution_combo: resolution_combo.clone(),
+        scrolled: main_scrolled.clone(),
+        settings: Rc::clone(&settings),
+        recent: Rc::new(RefCell::new(RecentFiles::default_path().map(|p| RecentFiles::load(&p)).unwrap_or_default())),
+        recent_menu: gtk::Menu::new(),
+    };
+    doc.update_title();
+    open_toolbar_button.set_menu(&doc.recent_menu);
+    doc.update_recent_menu();
+
+    connect_settings_window(&settings_builder, &settings_toolbar_button, &doc, &drawarea, tx.clone());
+    let port_watch = watch_midi_ports(&settings_builder, &settings, tx.clone());
+
+    // midi files dropped onto the window
+    window.drag_dest_set(gtk::DestDefaults::ALL,
+        &[gtk::TargetEntry::new("text/uri-list", gtk::TargetFlags::OTHER_APP, 0)],
+        gdk::DragAction::COPY);
+    let doc_c = doc.clone();
+    window.connect_drag_data_received(move |_, _, _, _, data, _, _| {
+        let path = data.get_uris().iter()
+            .filter_map(|uri| path_from_uri(uri))
+            .find(|path| is_midi_path(path));
+        match path {
+            Some(path) => {
+                if doc_c.confirm_discard() {
+                    doc_c.open(&path);
+                }
+            },
+            None => debug!("nothing to open in the dropped data")
+        }
+    });
+
+    let doc_c = doc.clone();
     new_toolbar_button.connect_clicked(move |_| {
-        let mut ws = ws_c.borrow_mut();
-        *ws = crate::smf::MidiWorkspace::empty();
+        if !doc_c.confirm_discard() {
+            return;
+        }
+        let ws = MidiWorkspace::empty_with_resolution(doc_c.settings.borrow().resolution);
+        let state = doc_c.state_for(&ws);
+        doc_c.replace(ws, state);
         debug!("created new workspace");
-        update_track_list(&track_store_c, &ws);
     });
 
-    let ws_c = Rc::clone(&ws);
-    let window_c = window.clone();
-    let track_store_c = track_list_store.clone();
+    let doc_c = doc.clone();
     open_toolbar_button.connect_clicked(move |_| {
         use gtk::ResponseType::{Cancel, Accept};
+        if !doc_c.confirm_discard() {
+            return;
+        }
         let chooser = gtk::FileChooserDialog::with_buttons(
-            Some("Open SMF"), Some(&window_c),
+            Some("Open SMF"), Some(&doc_c.window),
             gtk::FileChooserAction::Open,
             &[("_Cancel", Cancel), ("_Open", Accept)]);
-        let filter = {
-            let t = gtk::FileFilter::new();
-            t.add_mime_type("audio/midi");
-            t.add_mime_type("audio/x-midi");
-            t
-        };
-        chooser.add_filter(&filter);
+        chooser.add_filter(&midi_file_filter());
         let response = chooser.run();
         match response {
             Accept => {
-                if let Some(smf_path) = chooser.get_filename() {
-                    debug!("smf_path: {:?}", smf_path.to_str());
-                    let new_ws = crate::smf::MidiWorkspace::from_smf_file(smf_path);
-                    match new_ws {
-                        Ok(new_ws) => {
-                            // update track list
-                            update_track_list(&track_store_c, &new_ws);
-
-                            // replace the current MidiWorkspace
-                            let mut ws = RefCell::borrow_mut(&*ws_c);
-                            *ws = new_ws;
-                        },
-                        Err(e) => warn!("error: {}", e)
-                    }
+                if let Some(path) = chooser.get_filename() {
+                    doc_c.open(&path);
                 } else {
                     warn!("could not get filename");
                 }
@@ -161,54 +259,47 @@ pub fn construct_main_window() {
         chooser.destroy();
     });
 
-    let ws_c = Rc::clone(&ws);
-    let window_c = window.clone();
-    write_toolbar_button.connect_clicked(move |_| {
-        use gtk::ResponseType::{Cancel, Accept};
-        let chooser = gtk::FileChooserDialog::with_buttons(
-            Some("Save SMF"), Some(&window_c),
-            gtk::FileChooserAction::Save,
-            &[("_Cancel", Cancel), ("_Write", Accept)]);
-        let filter = {
-            let t = gtk::FileFilter::new();
-            t.add_mime_type("audio/midi");
-            t.add_mime_type("audio/x-midi");
-            t
-        };
-        chooser.add_filter(&filter);
-        let response = chooser.run();
-        match response {
-            Accept => {
-                if let Some(write_path) = chooser.get_filename() {
-                    debug!("write_path: {:?}", write_path.to_str());
-                    let file = std::fs::OpenOptions::new().create(true).write(true).open(write_path);
-                    match file {
-                        Ok(file) => {
-                            let ws = ws_c.borrow();
-                            let mut writer = std::io::BufWriter::new(file);
-                            match ws.write_all(&mut writer) {
-                                Ok(_) => info!("write successful"),
-                                Err(e) => error!("write error: {}", e)
-                            }
-                        },
-                        Err(e) => error!("{}", e)
-                    }
-                } else {
-                    warn!("could not get filename");
-                }
-            },
-            Cancel => {
-                debug!("Canceled by user");
-            },
-            _ => unreachable!()
-        }
-        chooser.destroy();
-    });
+    for (button, save_as) in vec![(write_toolbar_button, false), (save_as_toolbar_button, true)] {
+        let doc_c = doc.clone();
+        button.connect_clicked(move |_| {
+            doc_c.save(save_as);
+        });
+    }
+
+    for (button, format) in vec![
+        (format0_toolbar_button, rimd::SMFFormat::Single),
+        (format1_toolbar_button, rimd::SMFFormat::MultiTrack)
+    ] {
+        let ws_c = Rc::clone(&ws);
+        let window_c = window.clone();
+        let track_store_c = track_list_store.clone();
+        let marker_store_c = marker_list_store.clone();
+        let combo_c = track_choose_combo.clone();
+        let da_c = drawarea.clone();
+        let doc_c = doc.clone();
+        button.connect_clicked(move |_| {
+            // the track settings follow the tracks being split or merged
+            let result = project::convert_format(&mut ws_c.borrow_mut(), &mut doc_c.project.borrow_mut(), format);
+            match result {
+                Ok(_) => {
+                    debug!("converted to {:?}", format);
+                    update_track_list(&track_store_c, &ws_c.borrow());
+                    // the markers of every track end up in the conductor track of format 0
+                    update_marker_list(&marker_store_c, &ws_c.borrow());
+                    combo_c.set_active(Some(0));
+                    // the first track is another one now, even if it was shown before
+                    doc_c.show_current_track();
+                    da_c.queue_draw();
+                    update_title(&window_c, &ws_c.borrow());
+                },
+                Err(e) => show_error(&window_c, &format!("Could not convert: {}", e))
+            }
+        });
+    }
 
     let ps_c = Rc::clone(&ps);
     let da_c = drawarea.clone();
-    let list_store_c = midi_event_list_store.clone();
-    let ws_c = Rc::clone(&ws);
+    let doc_c = doc.clone();
     track_choose_combo.connect_changed(move |cb| {
         use std::convert::TryInto;
         if let Some(iter) = cb.get_active_iter() {
@@ -216,13 +307,11 @@ pub fn construct_main_window() {
                 let gvalue = model.get_value(&iter, 0);
                 let track_number = gvalue.get_some::<i32>().expect("type mismatch");
                 if let Ok(track_number) = track_number.try_into() {
-                    let mut ps = ps_c.borrow_mut();
-                    ps.current_track = track_number;
+                    ps_c.borrow_mut().current_track = track_number;
                     // redraw piano roll canvas
                     da_c.queue_draw();
-                    // reset event list
-                    let track = ws_c.borrow().events_abs_tick(track_number as usize);
-                    super::eventlist::event_list_track(&list_store_c, &track.unwrap());
+                    // reset event list and track settings
+                    doc_c.show_current_track();
                     debug!("switched to track {}", track_number);
                 } else {
                     warn!("invalid track number");
@@ -231,9 +320,81 @@ pub fn construct_main_window() {
         }
     });
 
+    // settings of the track shown in the piano roll
+    let change_track = {
+        let (ps_c, project_c, da_c) = (Rc::clone(&ps), Rc::clone(&project), drawarea.clone());
+        move |f: &dyn Fn(&mut TrackSettings)| {
+            let track = ps_c.borrow().current_track as usize;
+            f(project_c.borrow_mut().track_mut(track));
+            da_c.queue_draw();
+        }
+    };
+    let change_track_c = change_track.clone();
+    track_color_button.connect_color_set(move |button| {
+        let rgba = button.get_rgba();
+        let color = Color::from_rgb(rgba.red, rgba.green, rgba.blue);
+        change_track_c(&|settings| settings.color = Some(color));
+    });
+    let change_track_c = change_track.clone();
+    mute_check.connect_toggled(move |check| {
+        let mute = check.get_active();
+        change_track_c(&|settings| settings.mute = mute);
+    });
+    let change_track_c = change_track.clone();
+    solo_check.connect_toggled(move |check| {
+        let solo = check.get_active();
+        change_track_c(&|settings| settings.solo = solo);
+    });
+    track_port_entry.connect_changed(move |entry| {
+        let port = entry.get_text().map(|text| text.to_string()).filter(|text| !text.is_empty());
+        change_track(&|settings| settings.port = port.clone());
+    });
+
+    let ps_c = Rc::clone(&ps);
+    let ws_c = Rc::clone(&ws);
+    let window_c = window.clone();
+    let da_c = drawarea.clone();
+    let list_store_c = midi_event_list_store.clone();
+    resolution_combo.connect_changed(move |c| {
+        let resolution = match c.get_active_id().and_then(|id| id.parse::<u16>().ok()) {
+            Some(resolution) => resolution,
+            None => return
+        };
+        let from = ws_c.borrow().resolution() as u16;
+        if from == resolution {
+            return;
+        }
+        let result = ws_c.borrow_mut().set_resolution(resolution);
+        match result {
+            Ok(report) => {
+                debug!("{}", report);
+                {
+                    let mut ps = ps_c.borrow_mut();
+                    let quantize = crate::smf::rescale::rescale_tick(ps.quantize(), from, resolution).0;
+                    ps.set_quantize(quantize.max(1));
+                    if let Some(track) = ws_c.borrow().events_abs_tick(ps.current_track as usize) {
+                        super::eventlist::event_list_track(&list_store_c, &track);
+                    }
+                }
+                da_c.queue_draw();
+                update_title(&window_c, &ws_c.borrow());
+                if !report.is_exact() {
+                    show_warning(&window_c, &report.to_string());
+                }
+            },
+            Err(e) => {
+                show_error(&window_c, &format!("Could not change the resolution: {}", e));
+                update_resolution_combo(c, &ws_c.borrow());
+            }
+        }
+    });
+
     let draw_all = {
         let ps_c = Rc::clone(&ps);
         let main_scrolled_c = main_scrolled.clone();
+        let tempo_area_c = tempo_area.clone();
+        let ruler_area_c = ruler_area.clone();
+        let marker_area_c = marker_area.clone();
         move |w: &gtk::DrawingArea, cr: &cairo::Context| {
             let h = main_scrolled_c.get_hadjustment().unwrap();
             let v = main_scrolled_c.get_vadjustment().unwrap();
@@ -244,6 +405,10 @@ pub fn construct_main_window() {
                 width: h.get_page_size(),
                 height: v.get_page_size()
             };
+            // the lanes above follow scrolling and zooming
+            tempo_area_c.queue_draw();
+            ruler_area_c.queue_draw();
+            marker_area_c.queue_draw();
             ps_c.borrow().pianoroll_draw_handler(w, cr)
         }
     };
@@ -271,12 +436,14 @@ pub fn construct_main_window() {
         let ps_c = Rc::clone(&ps);
         let list_store_c = midi_event_list_store.clone();
         let ws_c = Rc::clone(&ws);
+        let window_c = window.clone();
         move |da: &gtk::DrawingArea, ev: &gdk::EventButton| {
             let redraw = ps_c.borrow_mut().handle_click_released(ev);
             if redraw {
                 da.queue_draw();
                 let track = ws_c.borrow().events_abs_tick(ps_c.borrow().current_track as usize).unwrap();
                 super::eventlist::event_list_track(&list_store_c, &track);
+                update_title(&window_c, &ws_c.borrow());
             }
             Inhibit(true)
         }
@@ -317,13 +484,1062 @@ pub fn construct_main_window() {
 
     drawarea.set_size_request(10000, white_height as i32 * super::pianoroll::WHITE_KEYS);
 
+    let tempo_lane = Rc::new(RefCell::new(TempoLane::new(Rc::clone(&ws), Rc::clone(&ps))));
+    for (i, curve) in TempoCurve::ALL.iter().enumerate() {
+        tempo_curve_combo.append(Some(&i.to_string()), curve.name());
+    }
+    tempo_curve_combo.set_active(Some(0));
+    let tempo_lane_c = Rc::clone(&tempo_lane);
+    tempo_curve_combo.connect_changed(move |c| {
+        if let Some(curve) = c.get_active().and_then(|i| TempoCurve::ALL.get(i as usize)) {
+            tempo_lane_c.borrow_mut().curve = *curve;
+        }
+    });
+    // after the tempo, the time signature, the key or the markers were edited
+    let conductor_changed = {
+        let ps_c = Rc::clone(&ps);
+        let ws_c = Rc::clone(&ws);
+        let window_c = window.clone();
+        let da_c = drawarea.clone();
+        let list_store_c = midi_event_list_store.clone();
+        let marker_store_c = marker_list_store.clone();
+        move |lane_area: &gtk::DrawingArea| {
+            lane_area.queue_draw();
+            da_c.queue_draw();
+            update_title(&window_c, &ws_c.borrow());
+            // bars of the markers move with the time signature
+            update_marker_list(&marker_store_c, &ws_c.borrow());
+            if ps_c.borrow().current_track == 0 {
+                if let Some(track) = ws_c.borrow().events_abs_tick(0) {
+                    super::eventlist::event_list_track(&list_store_c, &track);
+                }
+            }
+        }
+    };
+    let tempo_lane_c = Rc::clone(&tempo_lane);
+    tempo_area.connect_draw(move |w, cr| tempo_lane_c.borrow().draw(w, cr));
+    let (tempo_lane_c, tempo_changed_c, window_c) = (Rc::clone(&tempo_lane), conductor_changed.clone(), window.clone());
+    tempo_area.connect_button_press_event(move |w, ev| {
+        if tempo_lane_c.borrow_mut().handle_pressed(w, ev) {
+            tempo_changed_c(w);
+        }
+        // a double click on a change enters its tempo
+        if ev.get_event_type() == gdk::EventType::DoubleButtonPress && ev.get_button() == 1 {
+            let change = tempo_lane_c.borrow().change_under(w, ev.get_position());
+            if let Some((abs_tick, usec)) = change {
+                if let Some(usec) = ask_tempo(&window_c, usec) {
+                    if tempo_lane_c.borrow_mut().set_tempo(abs_tick, usec) {
+                        tempo_changed_c(w);
+                    }
+                }
+            }
+        }
+        Inhibit(true)
+    });
+    let tempo_lane_c = Rc::clone(&tempo_lane);
+    tempo_area.connect_motion_notify_event(move |w, ev| {
+        if tempo_lane_c.borrow_mut().handle_motion(w, ev) {
+            w.queue_draw();
+        }
+        Inhibit(true)
+    });
+    let (tempo_lane_c, tempo_changed) = (Rc::clone(&tempo_lane), conductor_changed.clone());
+    tempo_area.connect_button_release_event(move |w, ev| {
+        if tempo_lane_c.borrow_mut().handle_released(w, ev) {
+            tempo_changed(w);
+        } else {
+            w.queue_draw();
+        }
+        Inhibit(true)
+    });
+
+    let ruler = Rc::new(Ruler::new(Rc::clone(&ws), Rc::clone(&ps)));
+    let ruler_c = Rc::clone(&ruler);
+    ruler_area.connect_draw(move |w, cr| ruler_c.draw(w, cr));
+    // after an edit from the ruler, of the time signature or the key
+    let ruler_edited = {
+        let window_c = window.clone();
+        let conductor_changed = conductor_changed.clone();
+        move |w: &gtk::DrawingArea, what: &str, result: crate::Result<()>| match result {
+            Ok(()) => conductor_changed(w),
+            Err(e) => show_warning(&window_c, &format!("Could not change the {}: {}", what, e))
+        }
+    };
+    let window_c = window.clone();
+    ruler_area.connect_button_press_event(move |w, ev| {
+        if ev.get_event_type() != gdk::EventType::ButtonPress {
+            return Inhibit(true);
+        }
+        let (bar, change) = match ruler.bar_at(ev.get_position().0) {
+            Some(bar) => bar,
+            None => return Inhibit(true)
+        };
+        match ev.get_button() {
+            1 => if let Some(signature) = ask_time_signature(&window_c, &bar) {
+                if change != Some(signature) {
+                    ruler_edited(w, "time signature", ruler.set_time_signature(bar.start, signature));
+                }
+            },
+            3 => {
+                let menu = gtk::Menu::new();
+                let (key, key_change) = ruler.key_at(bar.start);
+                let item = gtk::MenuItem::new_with_label(&format!("Key from bar {}...", bar.number));
+                let (ruler_c, ruler_edited_c, window_cc, w_c) = (Rc::clone(&ruler), ruler_edited.clone(), window_c.clone(), w.clone());
+                item.connect_activate(move |_| {
+                    if let Some(new_key) = ask_key(&window_cc, bar.number, key) {
+                        if key_change != Some(new_key) {
+                            ruler_edited_c(&w_c, "key", ruler_c.set_key(bar.start, new_key));
+                        }
+                    }
+                });
+                menu.append(&item);
+                if let Some(signature) = change {
+                    let item = gtk::MenuItem::new_with_label(&format!("Remove time signature {}", signature));
+                    let (ruler_c, ruler_edited_c, w_c) = (Rc::clone(&ruler), ruler_edited.clone(), w.clone());
+                    item.connect_activate(move |_| ruler_edited_c(&w_c, "time signature", ruler_c.remove_time_signature(bar.start)));
+                    menu.append(&item);
+                }
+                if let Some(key) = key_change {
+                    let item = gtk::MenuItem::new_with_label(&format!("Remove key change to {}", key));
+                    let (ruler_c, ruler_edited_c, w_c) = (Rc::clone(&ruler), ruler_edited.clone(), w.clone());
+                    item.connect_activate(move |_| ruler_edited_c(&w_c, "key", ruler_c.remove_key(bar.start)));
+                    menu.append(&item);
+                }
+                menu.set_attach_widget(Some(w));
+                menu.show_all();
+                menu.popup_at_pointer(Some(&**ev));
+            },
+            _ => {}
+        }
+        Inhibit(true)
+    });
+
+    let marker_lane = Rc::new(RefCell::new(MarkerLane::new(Rc::clone(&ws), Rc::clone(&ps))));
+    let marker_lane_c = Rc::clone(&marker_lane);
+    marker_area.connect_draw(move |w, cr| marker_lane_c.borrow().draw(w, cr));
+    // after an edit of the markers, from the lane or the list
+    let markers_edited = {
+        let window_c = window.clone();
+        let marker_area_c = marker_area.clone();
+        let conductor_changed = conductor_changed.clone();
+        move |result: crate::Result<()>| match result {
+            Ok(()) => conductor_changed(&marker_area_c),
+            Err(e) => show_warning(&window_c, &format!("Could not change the markers: {}", e))
+        }
+    };
+    let (marker_lane_c, markers_edited_c, ps_c, window_c) = (Rc::clone(&marker_lane), markers_edited.clone(), Rc::clone(&ps), window.clone());
+    marker_area.connect_button_press_event(move |w, ev| {
+        marker_lane_c.borrow_mut().handle_pressed(ev);
+        let x = ev.get_position().0;
+        if x < ps_c.borrow().config.white_width {
+            return Inhibit(true);
+        }
+        let under = marker_lane_c.borrow().marker_under(x);
+        let abs_tick = marker_lane_c.borrow().tick_at(x);
+        match (ev.get_event_type(), ev.get_button()) {
+            // a double click changes the marker it is on, or adds one
+            (gdk::EventType::DoubleButtonPress, 1) => match under {
+                Some((index, marker)) => if let Some((kind, text)) = ask_marker(&window_c, "Change marker", marker.kind, &marker.text) {
+                    if kind != marker.kind || text != marker.text {
+                        markers_edited_c(marker_lane_c.borrow().change(index, kind, &text));
+                    }
+                },
+                None => if let Some((kind, text)) = ask_marker(&window_c, "Add marker", MarkerKind::Marker, "") {
+                    markers_edited_c(marker_lane_c.borrow().add(Marker::new(abs_tick, kind, text)));
+                }
+            },
+            (gdk::EventType::ButtonPress, 3) => {
+                let menu = gtk::Menu::new();
+                if let Some((index, marker)) = under {
+                    let name = format!("{} \"{}\"", marker.kind.name().to_lowercase(), marker.text);
+                    let item = gtk::MenuItem::new_with_label(&format!("Change {}...", name));
+                    let (marker_lane_cc, markers_edited_cc, window_cc, marker_c) = (Rc::clone(&marker_lane_c), markers_edited_c.clone(), window_c.clone(), marker.clone());
+                    item.connect_activate(move |_| {
+                        if let Some((kind, text)) = ask_marker(&window_cc, "Change marker", marker_c.kind, &marker_c.text) {
+                            if kind != marker_c.kind || text != marker_c.text {
+                                markers_edited_cc(marker_lane_cc.borrow().change(index, kind, &text));
+                            }
+                        }
+                    });
+                    menu.append(&item);
+                    let item = gtk::MenuItem::new_with_label(&format!("Delete {}", name));
+                    let (marker_lane_cc, markers_edited_cc) = (Rc::clone(&marker_lane_c), markers_edited_c.clone());
+                    item.connect_activate(move |_| markers_edited_cc(marker_lane_cc.borrow().remove(index)));
+                    menu.append(&item);
+                }
+                for (label, kind) in vec![
+                    ("Add marker here...", Some(MarkerKind::Marker)),
+                    ("Add cue point here...", Some(MarkerKind::CuePoint)),
+                    ("Add rehearsal letter here", None),
+                ] {
+                    let item = gtk::MenuItem::new_with_label(label);
+                    let (marker_lane_cc, markers_edited_cc, window_cc) = (Rc::clone(&marker_lane_c), markers_edited_c.clone(), window_c.clone());
+                    item.connect_activate(move |_| match kind {
+                        Some(kind) => if let Some((kind, text)) = ask_marker(&window_cc, "Add marker", kind, "") {
+                            markers_edited_cc(marker_lane_cc.borrow().add(Marker::new(abs_tick, kind, text)));
+                        },
+                        None => markers_edited_cc(marker_lane_cc.borrow().add_rehearsal_letter(abs_tick))
+                    });
+                    menu.append(&item);
+                }
+                menu.set_attach_widget(Some(w));
+                menu.show_all();
+                menu.popup_at_pointer(Some(&**ev));
+            },
+            _ => {}
+        }
+        Inhibit(true)
+    });
+    let marker_lane_c = Rc::clone(&marker_lane);
+    marker_area.connect_motion_notify_event(move |w, ev| {
+        if marker_lane_c.borrow_mut().handle_motion(ev) {
+            w.queue_draw();
+        }
+        Inhibit(true)
+    });
+    let (marker_lane_c, markers_edited_c) = (Rc::clone(&marker_lane), markers_edited.clone());
+    marker_area.connect_button_release_event(move |w, _| {
+        let result = marker_lane_c.borrow_mut().handle_released();
+        match result {
+            Ok(false) => w.queue_draw(),
+            result => markers_edited_c(result.map(|_| ()))
+        }
+        Inhibit(true)
+    });
+
+    // jumping to markers scrolls them to the left edge of the piano roll
+    for (button, forward) in vec![(previous_marker_toolbar_button, false), (next_marker_toolbar_button, true)] {
+        let (marker_lane_c, main_scrolled_c) = (Rc::clone(&marker_lane), main_scrolled.clone());
+        button.connect_clicked(move |_| {
+            let marker = marker_lane_c.borrow().neighbour(forward);
+            match (marker, main_scrolled_c.get_hadjustment()) {
+                (Some(marker), Some(h)) => h.set_value(marker_lane_c.borrow().scroll_value(marker.abs_tick)),
+                _ => debug!("no marker to go to")
+            }
+        });
+    }
+    let (marker_lane_c, main_scrolled_c) = (Rc::clone(&marker_lane), main_scrolled.clone());
+    marker_tree_view.connect_row_activated(move |tv, path, _| {
+        let index = tv.get_model()
+            .and_then(|model| model.get_iter(path).map(|iter| model.get_value(&iter, 0)))
+            .and_then(|value| value.get_some::<i32>().ok());
+        let marker = index.and_then(|index| marker_lane_c.borrow().marker_info()?.markers.get(index as usize).cloned());
+        if let (Some(marker), Some(h)) = (marker, main_scrolled_c.get_hadjustment()) {
+            h.set_value(marker_lane_c.borrow().scroll_value(marker.abs_tick));
+        }
+    });
+    let (marker_lane_c, store_c) = (Rc::clone(&marker_lane), marker_list_store.clone());
+    marker_text_renderer.connect_edited(move |_, path, text| {
+        let iter = match store_c.get_iter(&path) {
+            Some(iter) => iter,
+            None => return
+        };
+        let index = store_c.get_value(&iter, 0).get_some::<i32>().unwrap();
+        let old = store_c.get_value(&iter, 3).get::<String>().ok().flatten().unwrap_or_default();
+        if old != text {
+            markers_edited(marker_lane_c.borrow().rename(index as usize, text));
+        }
+    });
+
+    let snap_check = load!(gtk::CheckButton, "snapToScaleCheck");
+    let (ps_c, da_c) = (Rc::clone(&ps), drawarea.clone());
+    snap_check.connect_toggled(move |c| {
+        ps_c.borrow_mut().set_snap_to_scale(c.get_active());
+        // the rows of the scale are shaded while snapping
+        da_c.queue_draw();
+    });
+    for (button, direction) in vec![(transpose_up_toolbar_button, 1), (transpose_down_toolbar_button, -1)] {
+        let (ps_c, ws_c, window_c, da_c, list_store_c) = (Rc::clone(&ps), Rc::clone(&ws), window.clone(), drawarea.clone(), midi_event_list_store.clone());
+        button.connect_clicked(move |_| {
+            if ps_c.borrow_mut().transpose(direction) {
+                da_c.queue_draw();
+                update_title(&window_c, &ws_c.borrow());
+                if let Some(track) = ws_c.borrow().events_abs_tick(ps_c.borrow().current_track as usize) {
+                    super::eventlist::event_list_track(&list_store_c, &track);
+                }
+            }
+        });
+    }
+
     let drawarea_c = drawarea.clone();
     redraw_button.connect_clicked(move |_| {
         drawarea_c.queue_draw();
     });
 
-    window.connect_destroy(|_| gtk::main_quit());
+    // the startup workspace is shown like one New creates
+    let startup = ws.borrow().clone();
+    let state = doc.state_for(&startup);
+    doc.replace(startup, state);
+
+    // sessions a crashed editor left behind, then autosave this one
+    let session: Rc<RefCell<Option<recovery::Session>>> = Rc::new(RefCell::new(None));
+    if let Some(recovery_dir) = recovery::recovery_dir() {
+        let restored = offer_recovery(&window, &recovery_dir);
+        if let Some((restored, state)) = &restored {
+            doc.replace(restored.clone(), state.clone());
+        }
+        match recovery::Session::create(&recovery_dir) {
+            Ok(s) => {
+                // the restored edits are only in this session now
+                if let Some((restored, state)) = &restored {
+                    if let Err(e) = s.autosave(restored, state) {
+                        warn!("autosave failed: {}", e);
+                    }
+                }
+                *session.borrow_mut() = Some(s);
+            },
+            Err(e) => warn!("autosave is disabled: {}", e)
+        }
+    }
+
+    let session_c = Rc::clone(&session);
+    let doc_c = doc.clone();
+    let autosave = gtk::timeout_add_seconds(recovery::AUTOSAVE_INTERVAL, move || {
+        if let Some(session) = session_c.borrow().as_ref() {
+            let state = doc_c.project_state();
+            if let Err(e) = session.autosave(&doc_c.ws.borrow(), &state) {
+                warn!("autosave failed: {}", e);
+            }
+        }
+        gtk::Continue(true)
+    });
+
+    if let Some(file) = file {
+        if doc.confirm_discard() {
+            doc.open(&file);
+        }
+    }
+
+    let doc_c = doc.clone();
+    window.connect_delete_event(move |_, _| {
+        Inhibit(!doc_c.confirm_discard())
+    });
+
+    let tx_c = tx.clone();
+    let session_c = Rc::clone(&session);
+    window.connect_destroy(move |_| {
+        // silence the device before the receiver goes away
+        for message in vec![MidiMessage::Panic, MidiMessage::Close] {
+            if let Err(e) = tx_c.send(message) {
+                error!("failed to silence the midi output: {}", e);
+            }
+        }
+        // a clean exit leaves nothing to recover
+        if let Some(session) = session_c.borrow_mut().take() {
+            if let Err(e) = session.close() {
+                warn!("failed to remove the autosave: {}", e);
+            }
+        }
+        gtk::main_quit()
+    });
     gtk::main();
+
+    // the closures of the main window went with it; let go of the senders held elsewhere
+    glib::source_remove(autosave);
+    glib::source_remove(port_watch);
+    if let Some(settings_window) = settings_builder.get_object::<gtk::Window>("settingsWindow") {
+        settings_window.destroy();
+    }
+    doc.recent_menu.destroy();
+}
+
+fn show_error(parent: &gtk::ApplicationWindow, message: &str) {
+    error!("{}", message);
+    show_message(parent, gtk::MessageType::Error, message);
+}
+
+fn show_warning(parent: &gtk::ApplicationWindow, message: &str) {
+    warn!("{}", message);
+    show_message(parent, gtk::MessageType::Warning, message);
+}
+
+fn show_message(parent: &gtk::ApplicationWindow, message_type: gtk::MessageType, message: &str) {
+    let dialog = gtk::MessageDialog::new(Some(parent),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        message_type,
+        gtk::ButtonsType::Close,
+        message);
+    dialog.run();
+    dialog.destroy();
+}
+
+/// asks for the tempo of a change, starting from `usec` microseconds per quarter note
+fn ask_tempo(parent: &gtk::ApplicationWindow, usec: u32) -> Option<u32> {
+    let dialog = gtk::Dialog::with_buttons(Some("Tempo"), Some(parent),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[("_Cancel", gtk::ResponseType::Cancel), ("_OK", gtk::ResponseType::Ok)]);
+    dialog.set_default_response(gtk::ResponseType::Ok);
+    let spin = gtk::SpinButton::with_range(tempo::bpm(tempo::MAX_TEMPO).ceil(), 999.0, 0.01);
+    spin.set_digits(2);
+    spin.set_value(tempo::bpm(usec));
+    spin.set_activates_default(true);
+    let content = dialog.get_content_area();
+    content.set_spacing(6);
+    content.pack_start(&gtk::Label::new(Some("Beats per minute")), false, false, 0);
+    content.pack_start(&spin, false, false, 0);
+    dialog.show_all();
+    let response = dialog.run();
+    let bpm = spin.get_value();
+    dialog.destroy();
+    if response == gtk::ResponseType::Ok {
+        Some(tempo::usec_per_quarter(bpm))
+    } else {
+        None
+    }
+}
+
+/// asks for the time signature from `bar` on, starting from the one in effect there
+fn ask_time_signature(parent: &gtk::ApplicationWindow, bar: &Bar) -> Option<TimeSignature> {
+    let dialog = gtk::Dialog::with_buttons(Some(&format!("Time signature from bar {}", bar.number)), Some(parent),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[("_Cancel", gtk::ResponseType::Cancel), ("_OK", gtk::ResponseType::Ok)]);
+    dialog.set_default_response(gtk::ResponseType::Ok);
+    let numerator_spin = gtk::SpinButton::with_range(1.0, 255.0, 1.0);
+    numerator_spin.set_value(bar.signature.numerator as f64);
+    numerator_spin.set_activates_default(true);
+    let denominator_combo = gtk::ComboBoxText::new();
+    for i in 0..8 {
+        denominator_combo.append(Some(&(1 << i).to_string()), &(1 << i).to_string());
+    }
+    denominator_combo.set_active_id(Some(&bar.signature.denominator.to_string()));
+    let clocks_spin = gtk::SpinButton::with_range(1.0, 255.0, 1.0);
+    clocks_spin.set_value(bar.signature.clocks_per_click as f64);
+    let notated_32nds_spin = gtk::SpinButton::with_range(1.0, 255.0, 1.0);
+    notated_32nds_spin.set_value(bar.signature.notated_32nds as f64);
+    // a click on every beat of the new denominator
+    let clocks_spin_c = clocks_spin.clone();
+    denominator_combo.connect_changed(move |c| {
+        if let Some(denominator) = c.get_active_id().and_then(|id| id.parse().ok()) {
+            clocks_spin_c.set_value(TimeSignature::new(1, denominator).clocks_per_click as f64);
+        }
+    });
+
+    let grid = gtk::Grid::new();
+    grid.set_row_spacing(4);
+    grid.set_column_spacing(8);
+    let rows: [(&str, &gtk::Widget); 4] = [
+        ("Beats per bar", numerator_spin.upcast_ref()),
+        ("Beat note value", denominator_combo.upcast_ref()),
+        ("MIDI clocks per metronome click", clocks_spin.upcast_ref()),
+        ("32nd notes per quarter note", notated_32nds_spin.upcast_ref()),
+    ];
+    for (i, (label, widget)) in rows.iter().enumerate() {
+        let label = gtk::Label::new(Some(label));
+        label.set_halign(gtk::Align::Start);
+        grid.attach(&label, 0, i as i32, 1, 1);
+        grid.attach(*widget, 1, i as i32, 1, 1);
+    }
+    dialog.get_content_area().pack_start(&grid, false, false, 0);
+    dialog.show_all();
+    let response = dialog.run();
+    let signature = TimeSignature {
+        numerator: numerator_spin.get_value() as u8,
+        denominator: denominator_combo.get_active_id().and_then(|id| id.parse().ok()).unwrap_or(4),
+        clocks_per_click: clocks_spin.get_value() as u8,
+        notated_32nds: notated_32nds_spin.get_value() as u8,
+    };
+    dialog.destroy();
+    if response == gtk::ResponseType::Ok {
+        Some(signature)
+    } else {
+        None
+    }
+}
+
+/// asks for the key from `bar_number` on, starting from `key`
+fn ask_key(parent: &gtk::ApplicationWindow, bar_number: u32, key: KeySignature) -> Option<KeySignature> {
+    let dialog = gtk::Dialog::with_buttons(Some(&format!("Key from bar {}", bar_number)), Some(parent),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[("_Cancel", gtk::ResponseType::Cancel), ("_OK", gtk::ResponseType::Ok)]);
+    dialog.set_default_response(gtk::ResponseType::Ok);
+    let keys: Vec<KeySignature> = KeySignature::all().collect();
+    let combo = gtk::ComboBoxText::new();
+    for (i, k) in keys.iter().enumerate() {
+        let accidentals = match k.sharps {
+            0 => String::new(),
+            1 => String::from(" (1 sharp)"),
+            -1 => String::from(" (1 flat)"),
+            n if n > 0 => format!(" ({} sharps)", n),
+            n => format!(" ({} flats)", -n),
+        };
+        combo.append(Some(&i.to_string()), &format!("{}{}", k, accidentals));
+    }
+    combo.set_active(keys.iter().position(|k| *k == key).map(|i| i as u32));
+    dialog.get_content_area().pack_start(&combo, false, false, 0);
+    dialog.show_all();
+    let response = dialog.run();
+    let chosen = combo.get_active().and_then(|i| keys.get(i as usize).copied());
+    dialog.destroy();
+    if response == gtk::ResponseType::Ok {
+        chosen
+    } else {
+        None
+    }
+}
+
+/// asks for the kind and the text of a marker, starting from `kind` and `text`
+fn ask_marker(parent: &gtk::ApplicationWindow, title: &str, kind: MarkerKind, text: &str) -> Option<(MarkerKind, String)> {
+    let dialog = gtk::Dialog::with_buttons(Some(title), Some(parent),
+        gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+        &[("_Cancel", gtk::ResponseType::Cancel), ("_OK", gtk::ResponseType::Ok)]);
+    dialog.set_default_response(gtk::ResponseType::Ok);
+    let combo = gtk::ComboBoxText::new();
+    for (i, k) in MarkerKind::ALL.iter().enumerate() {
+        combo.append(Some(&i.to_string()), k.name());
+    }
+    combo.set_active(MarkerKind::ALL.iter().position(|k| *k == kind).map(|i| i as u32));
+    let entry = gtk::Entry::new();
+    entry.set_text(text);
+    entry.set_activates_default(true);
+    let content = dialog.get_content_area();
+    content.set_spacing(6);
+    content.pack_start(&combo, false, false, 0);
+    content.pack_start(&entry, false, false, 0);
+    dialog.show_all();
+    let response = dialog.run();
+    let chosen = combo.get_active().and_then(|i| MarkerKind::ALL.get(i as usize).copied());
+    let text = entry.get_text().map(|t| t.to_string()).unwrap_or_default();
+    dialog.destroy();
+    match (response, chosen) {
+        (gtk::ResponseType::Ok, Some(kind)) => Some((kind, text)),
+        _ => None
+    }
+}
+
+/// "name.mid - midie", with a * while there are unsaved edits
+fn update_title(window: &gtk::ApplicationWindow, ws: &crate::smf::MidiWorkspace) {
+    let modified = if ws.is_modified() { "*" } else { "" };
+    window.set_title(&format!("{}{} - midie", modified, display_name(ws)));
+}
+
+fn display_name(ws: &crate::smf::MidiWorkspace) -> String {
+    ws.path()
+        .and_then(|p| p.file_name())
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("Untitled"))
+}
+
+/// midi files, and midie projects
+fn midi_file_filter() -> gtk::FileFilter {
+    let filter = gtk::FileFilter::new();
+    filter.set_name(Some("MIDI files and midie projects"));
+    filter.add_mime_type("audio/midi");
+    filter.add_mime_type("audio/x-midi");
+    filter.add_pattern(&format!("*.{}", project::PROJECT_EXTENSION));
+    filter
+}
+
+/// the local path of a file:// URI, as found in dropped data
+fn path_from_uri(uri: &str) -> Option<PathBuf> {
+    let rest = uri.trim().strip_prefix("file://")?;
+    // skip the host, which is empty or localhost
+    let path = &rest[rest.find('/')?..];
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' {
+            bytes.get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(b) => {
+                decoded.push(b);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&decoded).into_owned()))
+}
+
+/// whether the file looks like something the editor opens
+fn is_midi_path(path: &Path) -> bool {
+    project::is_project_path(path) || path.extension()
+        .and_then(|ext| ext.to_str())
+        .map_or(false, |ext| ["mid", "midi", "smf", "kar"].contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// a name ending in .midie saves a project, anything else a standard midi file
+fn choose_save_path(parent: &gtk::ApplicationWindow) -> Option<PathBuf> {
+    use gtk::ResponseType::{Cancel, Accept};
+    let chooser = gtk::FileChooserDialog::with_buttons(
+        Some("Save SMF"), Some(parent),
+        gtk::FileChooserAction::Save,
+        &[("_Cancel", Cancel), ("_Save", Accept)]);
+    chooser.add_filter(&midi_file_filter());
+    chooser.set_do_overwrite_confirmation(true);
+    let path = match chooser.run() {
+        Accept => chooser.get_filename(),
+        _ => {
+            debug!("Canceled by user");
+            None
+        }
+    };
+    chooser.destroy();
+    path
+}
+
+/// The open workspace, what is kept with it in a project, and the widgets showing it.
+#[derive(Clone)]
+struct Document {
+    window: gtk::ApplicationWindow,
+    ws: Rc<RefCell<MidiWorkspace>>,
+    ps: Rc<RefCell<PianorollContext>>,
+    project: Rc<RefCell<ProjectState>>,
+    track_store: gtk::ListStore,
+    /// events of the track shown in the piano roll
+    event_list: gtk::TreeView,
+    event_store: gtk::ListStore,
+    /// markers of the conductor track
+    marker_store: gtk::ListStore,
+    track_combo: gtk::ComboBox,
+    track_controls: TrackControls,
+    resolution_combo: gtk::ComboBoxText,
+    scrolled: gtk::ScrolledWindow,
+    settings: Rc<RefCell<Settings>>,
+    recent: Rc<RefCell<RecentFiles>>,
+    /// drop down of the open button
+    recent_menu: gtk::Menu,
+}
+
+/// The settings of the track shown in the piano roll.
+#[derive(Clone)]
+struct TrackControls {
+    color: gtk::ColorButton,
+    mute: gtk::CheckButton,
+    solo: gtk::CheckButton,
+    port: gtk::Entry,
+}
+
+impl Document {
+    fn update_title(&self) {
+        update_title(&self.window, &self.ws.borrow());
+    }
+
+    /// what the editor shows, with the rows selected in the event list
+    fn editor_state(&self) -> EditorState {
+        let mut editor = self.ps.borrow().editor_state();
+        editor.selected_events = self.event_list.get_selection().get_selected_rows().0.iter()
+            .filter_map(|path| path.get_indices().first().map(|row| *row as usize))
+            .collect();
+        editor
+    }
+
+    /// what to keep with the workspace in a project or recovery session
+    fn project_state(&self) -> ProjectState {
+        let mut state = self.project.borrow().clone();
+        state.editor = self.editor_state();
+        state
+    }
+
+    /// fills the event list and the track controls for the track shown in the piano roll
+    fn show_current_track(&self) {
+        let track = self.ps.borrow().current_track as usize;
+        match self.ws.borrow().events_abs_tick(track) {
+            Some(events) => super::eventlist::event_list_track(&self.event_store, &events),
+            None => self.event_store.clear()
+        }
+        // not borrowed while the controls call back with the same settings
+        let settings = self.project.borrow().track(track);
+        let (r, g, b) = settings.color.unwrap_or(self.ps.borrow().config.note_color).rgb();
+        self.track_controls.color.set_rgba(&gdk::RGBA { red: r, green: g, blue: b, alpha: 1.0 });
+        self.track_controls.mute.set_active(settings.mute);
+        self.track_controls.solo.set_active(settings.solo);
+        self.track_controls.port.set_text(settings.port.as_deref().unwrap_or(""));
+    }
+
+    /// project state for a workspace which does not come with one
+    fn state_for(&self, ws: &MidiWorkspace) -> ProjectState {
+        let mut state = ProjectState::default();
+        state.editor.quantize = self.settings.borrow().quantize(ws.resolution().max(1) as u16);
+        state.editor.beat_width = self.ps.borrow().config.beat_width;
+        state
+    }
+
+    /// shows `ws`, and restores the editor as `state` describes it.
+    fn replace(&self, ws: MidiWorkspace, state: ProjectState) {
+        let editor = state.editor.clone();
+        let track_count = ws.track_count();
+        *self.ws.borrow_mut() = ws;
+        *self.project.borrow_mut() = state;
+        update_track_list(&self.track_store, &self.ws.borrow());
+        update_marker_list(&self.marker_store, &self.ws.borrow());
+        update_resolution_combo(&self.resolution_combo, &self.ws.borrow());
+        self.update_title();
+        self.ps.borrow_mut().restore_editor_state(&editor);
+        if track_count > 0 {
+            // switches the piano roll and event list over to the track
+            self.track_combo.set_active(Some((editor.current_track as usize).min(track_count - 1) as u32));
+        }
+        // the track may have been shown before, with the old workspace
+        self.show_current_track();
+        let selection = self.event_list.get_selection();
+        selection.unselect_all();
+        for row in &editor.selected_events {
+            selection.select_path(&gtk::TreePath::new_from_indicesv(&[*row as i32]));
+        }
+        if let Some(h) = self.scrolled.get_hadjustment() {
+            h.set_value(editor.scroll_x);
+        }
+        if let Some(v) = self.scrolled.get_vadjustment() {
+            v.set_value(editor.scroll_y);
+        }
+    }
+
+    /// opens a midi file or project, telling the user what went wrong.
+    /// Returns whether it was opened.
+    fn open(&self, path: &Path) -> bool {
+        debug!("open: {:?}", path.to_str());
+        let opened = if project::is_project_path(path) {
+            project::open(path).map(|(ws, state)| {
+                if state.is_newer() {
+                    show_warning(&self.window, &format!("{} was saved by a newer version of midie. \
+                        What this version does not know about is kept, but not used.", path.display()));
+                }
+                (ws, state)
+            })
+        } else {
+            MidiWorkspace::from_smf_file_lenient(path).map(|(ws, warnings)| {
+                if !warnings.is_empty() {
+                    let warnings: Vec<String> = warnings.iter().map(|w| w.to_string()).collect();
+                    show_warning(&self.window, &format!("{} was damaged and has been repaired:\n{}",
+                        path.display(), warnings.join("\n")));
+                }
+                let state = self.state_for(&ws);
+                (ws, state)
+            })
+        };
+        match opened {
+            Ok((ws, state)) => {
+                self.replace(ws, state);
+                self.remember(path);
+                true
+            },
+            Err(e) => {
+                show_error(&self.window, &format!("Could not open {}: {}", path.display(), e));
+                if !path.exists() {
+                    self.recent.borrow_mut().remove(path);
+                    self.save_recent();
+                }
+                false
+            }
+        }
+    }
+
+    /// puts `path` at the top of the recent files
+    fn remember(&self, path: &Path) {
+        self.recent.borrow_mut().add(path);
+        self.save_recent();
+    }
+
+    fn save_recent(&self) {
+        if let Some(recent_path) = RecentFiles::default_path() {
+            if let Err(e) = self.recent.borrow().save(&recent_path) {
+                warn!("failed to save the recent files: {}", e);
+            }
+        }
+        self.update_recent_menu();
+    }
+
+    fn update_recent_menu(&self) {
+        for item in self.recent_menu.get_children() {
+            self.recent_menu.remove(&item);
+        }
+        for path in self.recent.borrow().files() {
+            let item = gtk::MenuItem::new_with_label(&path.to_string_lossy());
+            let doc = self.clone();
+            let path = path.clone();
+            item.connect_activate(move |_| {
+                if doc.confirm_discard() {
+                    doc.open(&path);
+                }
+            });
+            self.recent_menu.append(&item);
+        }
+        if self.recent.borrow().files().is_empty() {
+            let item = gtk::MenuItem::new_with_label("No recent files");
+            item.set_sensitive(false);
+            self.recent_menu.append(&item);
+        }
+        self.recent_menu.show_all();
+    }
+
+    /// saves to the file the workspace came from, or asks where to save it.
+    /// Returns whether it was saved.
+    fn save(&self, save_as: bool) -> bool {
+        let path = match self.ws.borrow().path() {
+            Some(path) if !save_as => Some(path.to_path_buf()),
+            _ => None
+        };
+        let path = match path.or_else(|| choose_save_path(&self.window)) {
+            Some(path) => path,
+            None => return false
+        };
+        debug!("save path: {:?}", path.to_str());
+        let backup = self.settings.borrow().backup;
+        let result = if project::is_project_path(&path) {
+            let state = self.project_state();
+            let result = project::save(&path, &mut self.ws.borrow_mut(), &state, backup);
+            *self.project.borrow_mut() = state;
+            result
+        } else {
+            self.ws.borrow_mut().save(&path, backup)
+        };
+        match result {
+            Ok(_) => {
+                info!("saved {}", path.display());
+                self.update_title();
+                self.remember(&path);
+                true
+            },
+            Err(e) => {
+                show_error(&self.window, &format!("Could not save {}: {}", path.display(), e));
+                false
+            }
+        }
+    }
+
+    /// asks what to do with unsaved edits before they are thrown away.
+    /// Returns false if the user wants to keep editing.
+    fn confirm_discard(&self) -> bool {
+        if !self.ws.borrow().is_modified() {
+            return true;
+        }
+        let message = format!("Save changes to {} before closing it?", display_name(&self.ws.borrow()));
+        let dialog = gtk::MessageDialog::new(Some(&self.window),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::None,
+            &message);
+        dialog.add_buttons(&[
+            ("Close _without Saving", gtk::ResponseType::Reject),
+            ("_Cancel", gtk::ResponseType::Cancel),
+            ("_Save", gtk::ResponseType::Accept),
+        ]);
+        dialog.set_default_response(gtk::ResponseType::Accept);
+        let response = dialog.run();
+        dialog.destroy();
+        match response {
+            gtk::ResponseType::Reject => true,
+            gtk::ResponseType::Accept => self.save(false),
+            _ => false
+        }
+    }
+}
+
+/// shows the settings and keeps them up to date with what the user changes there.
+fn connect_settings_window(builder: &gtk::Builder, button: &gtk::ToolButton, doc: &Document, drawarea: &gtk::DrawingArea, tx: mpsc::Sender<MidiMessage>) {
+    macro_rules! load {
+        ($t: ty, $id: expr) => {{
+            builder.get_object::<$t>($id).expect(&format!("failed to find {}", $id))
+        }}
+    }
+    let settings_window = load!(gtk::Window, "settingsWindow");
+    let midi_device_list_store = load!(gtk::ListStore, "midiDeviceList");
+    let midi_device_combo = load!(gtk::ComboBox, "midiDeviceCombo");
+    let backup_check = load!(gtk::CheckButton, "backupCheck");
+    let resolution_spin = load!(gtk::SpinButton, "resolutionSpin");
+    let quantize_spin = load!(gtk::SpinButton, "quantizeSpin");
+    let beat_width_spin = load!(gtk::SpinButton, "beatWidthSpin");
+    let velocity_spin = load!(gtk::SpinButton, "noteVelocitySpin");
+    let color_buttons = [
+        load!(gtk::ColorButton, "whiteKeyColorButton"),
+        load!(gtk::ColorButton, "blackKeyColorButton"),
+        load!(gtk::ColorButton, "noteColorButton"),
+    ];
+
+    {
+        let settings = doc.settings.borrow();
+        backup_check.set_active(settings.backup);
+        resolution_spin.set_value(settings.resolution as f64);
+        quantize_spin.set_value(settings.quantize_per_beat as f64);
+        beat_width_spin.set_value(settings.beat_width);
+        velocity_spin.set_value(settings.note_velocity as f64);
+        let colors = [settings.white_key_color, settings.black_key_color, settings.note_color];
+        for (button, color) in color_buttons.iter().zip(colors.iter()) {
+            let (r, g, b) = color.rgb();
+            button.set_rgba(&gdk::RGBA { red: r, green: g, blue: b, alpha: 1.0 });
+        }
+    }
+
+    // changes `settings` with `f`, saves them and redraws the piano roll
+    let change = {
+        let settings = Rc::clone(&doc.settings);
+        let drawarea = drawarea.clone();
+        move |f: &dyn Fn(&mut Settings)| {
+            f(&mut settings.borrow_mut());
+            if let Some(path) = Settings::default_path() {
+                if let Err(e) = settings.borrow().save(&path) {
+                    warn!("failed to save the settings: {}", e);
+                }
+            }
+            drawarea.queue_draw();
+        }
+    };
+
+    let (change_c, settings) = (change.clone(), Rc::clone(&doc.settings));
+    midi_device_combo.connect_changed(move |c| {
+        let (model, iter) = match (c.get_model(), c.get_active_iter()) {
+            (Some(model), Some(iter)) => (model, iter),
+            _ => return
+        };
+        let port_number = model.get_value(&iter, 0).get_some::<i32>().unwrap();
+        let output = match port_number {
+            VIRTUAL_PORT_ID => Output::Virtual,
+            BUILTIN_SYNTH_ID => Output::BuiltinSynth,
+            _ => Output::Port(model.get_value(&iter, 1).get::<String>().ok().flatten().unwrap_or_default())
+        };
+        // selecting what is already playing, as when the window is shown
+        if output == settings.borrow().output {
+            return;
+        }
+        debug!("switching the midi output to {:?}", output);
+        if let Some(message) = output.message() {
+            if let Err(e) = tx.send(message) {
+                error!("failed to switch the midi output: {}", e);
+            }
+        }
+        change_c(&|settings| settings.output = output.clone());
+    });
+
+    let change_c = change.clone();
+    backup_check.connect_toggled(move |check| {
+        let backup = check.get_active();
+        change_c(&|settings| settings.backup = backup);
+    });
+    let change_c = change.clone();
+    resolution_spin.connect_value_changed(move |spin| {
+        let resolution = spin.get_value_as_int() as u16;
+        change_c(&|settings| settings.resolution = resolution);
+    });
+    let change_c = change.clone();
+    quantize_spin.connect_value_changed(move |spin| {
+        let quantize_per_beat = spin.get_value_as_int() as u16;
+        change_c(&|settings| settings.quantize_per_beat = quantize_per_beat);
+    });
+    let (change_c, ps_c) = (change.clone(), Rc::clone(&doc.ps));
+    beat_width_spin.connect_value_changed(move |spin| {
+        let beat_width = spin.get_value();
+        ps_c.borrow_mut().config.beat_width = beat_width;
+        change_c(&|settings| settings.beat_width = beat_width);
+    });
+    let (change_c, ps_c) = (change.clone(), Rc::clone(&doc.ps));
+    velocity_spin.connect_value_changed(move |spin| {
+        let velocity = spin.get_value_as_int() as u8;
+        ps_c.borrow_mut().set_velocity(velocity);
+        change_c(&|settings| settings.note_velocity = velocity);
+    });
+    for (i, button) in color_buttons.iter().enumerate() {
+        let (change_c, ps_c) = (change.clone(), Rc::clone(&doc.ps));
+        button.connect_color_set(move |button| {
+            let rgba = button.get_rgba();
+            let color = Color::from_rgb(rgba.red, rgba.green, rgba.blue);
+            {
+                let config = &mut ps_c.borrow_mut().config;
+                *[&mut config.white_key_color, &mut config.black_key_color, &mut config.note_color][i] = color;
+            }
+            change_c(&|settings| *[&mut settings.white_key_color, &mut settings.black_key_color, &mut settings.note_color][i] = color);
+        });
+    }
+
+    settings_window.connect_delete_event(|w, _| Inhibit(w.hide_on_delete()));
+    let settings = Rc::clone(&doc.settings);
+    button.connect_clicked(move |_| {
+        let port_names = MidiProber::new("midie").map(|mb| mb.port_names()).unwrap_or_default();
+        update_midi_output_device_list(&midi_device_list_store, &port_names);
+        select_output(&midi_device_combo, &settings.borrow().output);
+        settings_window.show();
+    });
+}
+
+/// looks for midi ports being plugged in and unplugged, so that the receiver can
+/// reconnect and the device list stays current.
+/// Returns the source to remove when the window is closed.
+fn watch_midi_ports(builder: &gtk::Builder, settings: &Rc<RefCell<Settings>>, tx: mpsc::Sender<MidiMessage>) -> glib::SourceId {
+    let settings_window = builder.get_object::<gtk::Window>("settingsWindow").expect("failed to find settingsWindow");
+    let midi_device_list_store = builder.get_object::<gtk::ListStore>("midiDeviceList").expect("failed to find midiDeviceList");
+    let midi_device_combo = builder.get_object::<gtk::ComboBox>("midiDeviceCombo").expect("failed to find midiDeviceCombo");
+    let settings = Rc::clone(settings);
+    let mut last_port_names: Option<Vec<String>> = None;
+    gtk::timeout_add_seconds(PORT_POLL_INTERVAL, move || {
+        let port_names = match MidiProber::new("midie") {
+            Ok(mb) => mb.port_names(),
+            Err(e) => {
+                warn!("failed to list the midi ports: {}", e);
+                return gtk::Continue(true);
+            }
+        };
+        if last_port_names.as_ref() == Some(&port_names) {
+            return gtk::Continue(true);
+        }
+        debug!("midi ports changed: {:?}", port_names);
+        if tx.send(MidiMessage::PortsChanged(port_names.clone())).is_err() {
+            return gtk::Continue(false);
+        }
+        if settings_window.is_visible() {
+            update_midi_output_device_list(&midi_device_list_store, &port_names);
+            select_output(&midi_device_combo, &settings.borrow().output);
+        }
+        last_port_names = Some(port_names);
+        gtk::Continue(true)
+    })
+}
+
+/// offers to restore what crashed editors autosaved, newest first.
+/// Sessions the user neither restores nor discards are offered again next time.
+fn offer_recovery(parent: &gtk::ApplicationWindow, recovery_dir: &std::path::Path) -> Option<(crate::smf::MidiWorkspace, ProjectState)> {
+    for recovered in recovery::crashed_sessions(recovery_dir) {
+        let message = format!("midie did not exit cleanly.\nRestore the unsaved edits to {}, autosaved {} minutes ago?",
+            recovered.info.name(), recovered.info.age().as_secs() / 60);
+        let dialog = gtk::MessageDialog::new(Some(parent),
+            gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT,
+            gtk::MessageType::Question,
+            gtk::ButtonsType::None,
+            &message);
+        dialog.add_buttons(&[
+            ("_Discard", gtk::ResponseType::Reject),
+            ("_Not Now", gtk::ResponseType::Cancel),
+            ("_Restore", gtk::ResponseType::Accept),
+        ]);
+        dialog.set_default_response(gtk::ResponseType::Accept);
+        let response = dialog.run();
+        dialog.destroy();
+        match response {
+            gtk::ResponseType::Accept => match recovered.restore() {
+                Ok(restored) => {
+                    if let Err(e) = recovered.discard() {
+                        warn!("failed to remove the recovered session: {}", e);
+                    }
+                    return Some(restored);
+                },
+                Err(e) => show_error(parent, &format!("Could not restore {}: {}", recovered.info.name(), e))
+            },
+            gtk::ResponseType::Reject => {
+                if let Err(e) = recovered.discard() {
+                    warn!("failed to remove the recovered session: {}", e);
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+fn update_resolution_combo(combo: &gtk::ComboBoxText, ws: &crate::smf::MidiWorkspace) {
+    // SMPTE based files are converted through their division instead
+    combo.set_sensitive(!ws.division().is_smpte());
+    let id = ws.resolution().to_string();
+    if !combo.set_active_id(Some(&id)) {
+        combo.append(Some(&id), &id);
+        combo.set_active_id(Some(&id));
+    }
 }
 
 fn update_track_list(ls: &gtk::ListStore, ws: &crate::smf::MidiWorkspace) {
@@ -337,19 +1553,86 @@ fn update_track_list(ls: &gtk::ListStore, ws: &crate::smf::MidiWorkspace) {
     }
 }
 
-fn fetch_midi_output_device_list(ls: &gtk::ListStore) {
+/// lists the markers of the conductor track, at bar.beat or at their tick if the file has no bars
+fn update_marker_list(ls: &gtk::ListStore, ws: &crate::smf::MidiWorkspace) {
     ls.clear();
-    match MidiProber::new("midie") {
-        Ok(mb) => {
-            for (i, port) in mb.list_ports().iter().enumerate() {
-                let port_name = mb.port_name(port).unwrap_or_else(|_| String::from("port name unknown"));
-                let iter = ls.append();
-                ls.set(&iter,
-                    &[0, 1],
-                    &[&(i as i32), &port_name]
-                )
+    if ws.track_count() == 0 {
+        return;
+    }
+    let markers = match ws.create_marker_info(0) {
+        Ok(markers) => markers,
+        Err(e) => {
+            warn!("cannot list the markers: {}", e);
+            return;
+        }
+    };
+    let ts = if ws.division().is_smpte() { None } else { ws.create_time_signature_info(0).ok() };
+    let resolution = ws.resolution().max(1) as u16;
+    for (i, marker) in markers.markers.iter().enumerate() {
+        let position = match &ts {
+            Some(ts) => {
+                let bar = ts.bar_at(marker.abs_tick, resolution);
+                let beat = ((marker.abs_tick - bar.start) as f64 / bar.signature.beat_ticks(resolution)) as u64 + 1;
+                format!("{}.{}", bar.number, beat)
+            },
+            None => marker.abs_tick.to_string()
+        };
+        let iter = ls.append();
+        ls.set(&iter,
+            &[0, 1, 2, 3],
+            &[&(i as i32), &position, &marker.kind.name(), &marker.text]
+        );
+    }
+}
+
+/// selects `output` in the device list, if it is there
+fn select_output(combo: &gtk::ComboBox, output: &Output) {
+    let model = match combo.get_model() {
+        Some(model) => model,
+        None => return
+    };
+    let mut rows = Vec::new();
+    if let Some(iter) = model.get_iter_first() {
+        loop {
+            let id = model.get_value(&iter, 0).get_some::<i32>().unwrap();
+            let name = model.get_value(&iter, 1).get::<String>().ok().flatten().unwrap_or_default();
+            rows.push((id, name));
+            if !model.iter_next(&iter) {
+                break;
             }
-        },
-        Err(e) => error!("{}", e)
+        }
+    }
+    let active = match output {
+        Output::None => None,
+        Output::Virtual => rows.iter().position(|(id, _)| *id == VIRTUAL_PORT_ID),
+        Output::BuiltinSynth => rows.iter().position(|(id, _)| *id == BUILTIN_SYNTH_ID),
+        Output::Port(name) => {
+            let port_names: Vec<String> = rows.iter().filter(|(id, _)| *id >= 0).map(|(_, name)| name.clone()).collect();
+            crate::smf::play::find_port(&port_names, name)
+        }
+    };
+    combo.set_active(active.map(|i| i as u32));
+}
+
+fn update_midi_output_device_list(ls: &gtk::ListStore, port_names: &[String]) {
+    ls.clear();
+    for (i, port_name) in port_names.iter().enumerate() {
+        let iter = ls.append();
+        ls.set(&iter,
+            &[0, 1],
+            &[&(i as i32), port_name]
+        )
+    }
+    let iter = ls.append();
+    ls.set(&iter,
+        &[0, 1],
+        &[&BUILTIN_SYNTH_ID, &"Built-in synthesizer"]
+    );
+    if cfg!(unix) {
+        let iter = ls.append();
+        ls.set(&iter,
+            &[0, 1],
+            &[&VIRTUAL_PORT_ID, &format!("Virtual port ({})", VIRTUAL_PORT_NAME)]
+        )
     }
 }
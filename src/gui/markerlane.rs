@@ -0,0 +1,230 @@
+//! The lane above the piano roll which shows and edits the markers, cue points and
+//! text events of the conductor track.
+
+use gtk::prelude::*;
+use cairo::Context;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::error::Result;
+use crate::smf::{MidiWorkspace, MarkerInfo};
+use crate::smf::marker::{Marker, MarkerKind};
+use super::pianoroll::PianorollContext;
+
+/// how far left of its line a marker can be grabbed, in pixels
+const GRAB_DISTANCE: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy)]
+struct Drag {
+    index: usize,
+    to: u64,
+}
+
+pub struct MarkerLane {
+    ws: Rc<RefCell<MidiWorkspace>>,
+    /// what the piano roll shows, so that ticks line up with it
+    ps: Rc<RefCell<PianorollContext>>,
+    drag: Option<Drag>,
+    /// (index, left, right) of every marker drawn, to find the one under the pointer
+    hit_boxes: RefCell<Vec<(usize, f64, f64)>>,
+}
+
+impl MarkerLane {
+    pub fn new(ws: Rc<RefCell<MidiWorkspace>>, ps: Rc<RefCell<PianorollContext>>) -> Self {
+        MarkerLane { ws, ps, drag: None, hit_boxes: RefCell::new(Vec::new()) }
+    }
+
+    /// markers of the conductor track, none if there are no tracks
+    pub fn marker_info(&self) -> Option<MarkerInfo> {
+        let ws = self.ws.borrow();
+        if ws.track_count() == 0 {
+            return None;
+        }
+        ws.create_marker_info(0).map_err(|e| warn!("cannot edit the markers: {}", e)).ok()
+    }
+
+    fn edit<F: FnOnce(&mut MarkerInfo)>(&self, f: F) -> Result<()> {
+        let mut markers = self.ws.borrow().create_marker_info(0)?;
+        f(&mut markers);
+        self.ws.borrow_mut().set_marker_info(&markers)
+    }
+
+    /// the tick under `x`, on the quantize grid
+    pub fn tick_at(&self, x: f64) -> u64 {
+        let ps = self.ps.borrow();
+        crate::smf::util::quantize_tick(ps.lane_tick(x), ps.quantize())
+    }
+
+    /// the index of the marker drawn under `x`, and the marker
+    pub fn marker_under(&self, x: f64) -> Option<(usize, Marker)> {
+        let markers = self.marker_info()?;
+        // the last one drawn is on top
+        self.hit_boxes.borrow().iter().rev()
+            .find(|(_, left, right)| *left <= x && x <= *right)
+            .and_then(|(index, _, _)| markers.markers.get(*index).map(|m| (*index, m.clone())))
+    }
+
+    pub fn add(&self, marker: Marker) -> Result<()> {
+        self.edit(|markers| {
+            markers.add(marker);
+        })
+    }
+
+    /// adds a rehearsal letter at `abs_tick`, lettering the ones after it again
+    pub fn add_rehearsal_letter(&self, abs_tick: u64) -> Result<()> {
+        self.edit(|markers| {
+            markers.add_rehearsal_letter(abs_tick);
+        })
+    }
+
+    /// changes the kind and the text of the marker at `index`
+    pub fn change(&self, index: usize, kind: MarkerKind, text: &str) -> Result<()> {
+        self.edit(|markers| {
+            if let Some(marker) = markers.markers.get_mut(index) {
+                marker.kind = kind;
+            }
+            markers.rename(index, text);
+        })
+    }
+
+    pub fn rename(&self, index: usize, text: &str) -> Result<()> {
+        self.edit(|markers| markers.rename(index, text))
+    }
+
+    /// removes the marker at `index`. The rehearsal letters after it move up to close the gap.
+    pub fn remove(&self, index: usize) -> Result<()> {
+        self.edit(|markers| {
+            if markers.remove(index).map_or(false, |m| m.is_rehearsal_letter()) {
+                markers.reletter();
+            }
+        })
+    }
+
+    /// the marker to scroll to from what is shown at the left edge, forward or back
+    pub fn neighbour(&self, forward: bool) -> Option<Marker> {
+        let markers = self.marker_info()?;
+        let ps = self.ps.borrow();
+        let left = ps.config.white_width;
+        if forward {
+            // a pixel in, so that the marker scrolled to last is passed
+            markers.next_after(ps.lane_tick(left + 1.0)).cloned()
+        } else {
+            markers.previous_before(ps.lane_tick(left)).cloned()
+        }
+    }
+
+    /// the horizontal scroll which puts `abs_tick` at the left edge of the piano roll
+    pub fn scroll_value(&self, abs_tick: u64) -> f64 {
+        let ps = self.ps.borrow();
+        let resolution = self.ws.borrow().resolution().max(1) as f64;
+        abs_tick as f64 * ps.config.beat_width / resolution
+    }
+
+    fn color(kind: MarkerKind) -> (f64, f64, f64) {
+        match kind {
+            MarkerKind::Marker => (0.85, 0.35, 0.1),
+            MarkerKind::CuePoint => (0.5, 0.2, 0.7),
+            MarkerKind::Text => (0.4, 0.4, 0.4),
+        }
+    }
+
+    pub fn draw(&self, w: &gtk::DrawingArea, cr: &Context) -> Inhibit {
+        let width = w.get_allocated_width() as f64;
+        let height = w.get_allocated_height() as f64;
+        let white_width = self.ps.borrow().config.white_width;
+        self.hit_boxes.borrow_mut().clear();
+
+        cr.set_source_rgba(0.97, 0.95, 0.9, 1.0);
+        cr.paint();
+        cr.set_font_size(10.0);
+        cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+        cr.move_to(4.0, height - 6.0);
+        cr.show_text("Markers");
+
+        let markers = match self.marker_info() {
+            Some(markers) => markers,
+            None => return Inhibit(true)
+        };
+        cr.rectangle(white_width, 0.0, width - white_width, height);
+        cr.clip();
+        cr.set_line_width(1.0);
+        for (index, marker) in markers.markers.iter().enumerate() {
+            let abs_tick = match self.drag {
+                Some(drag) if drag.index == index => drag.to,
+                _ => marker.abs_tick
+            };
+            let x = self.ps.borrow().lane_x(abs_tick);
+            if x > width {
+                break;
+            }
+            let label = if marker.text.is_empty() { marker.kind.name() } else { marker.text.as_str() };
+            let right = x + 6.0 + cr.text_extents(label).x_advance;
+            if right < white_width {
+                // scrolled out of sight
+                continue;
+            }
+            let (r, g, b) = Self::color(marker.kind);
+            cr.set_source_rgba(r, g, b, 1.0);
+            cr.move_to(x, 0.0);
+            cr.line_to(x, height);
+            cr.stroke();
+            // a flag at the top of the line
+            cr.move_to(x, 0.0);
+            cr.line_to(x + 6.0, 3.0);
+            cr.line_to(x, 6.0);
+            cr.close_path();
+            cr.fill();
+            cr.move_to(x + 3.0, height - 6.0);
+            cr.show_text(label);
+            self.hit_boxes.borrow_mut().push((index, x - GRAB_DISTANCE, right));
+        }
+        Inhibit(true)
+    }
+
+    /// starts dragging the marker under the pointer
+    pub fn handle_pressed(&mut self, event: &gdk::EventButton) {
+        self.drag = None;
+        if event.get_event_type() != gdk::EventType::ButtonPress || event.get_button() != 1 {
+            return;
+        }
+        let x = event.get_position().0;
+        if x < self.ps.borrow().config.white_width {
+            return;
+        }
+        if let Some((index, marker)) = self.marker_under(x) {
+            self.drag = Some(Drag { index, to: marker.abs_tick });
+        }
+    }
+
+    /// Returns whether the lane needs to be redrawn.
+    pub fn handle_motion(&mut self, event: &gdk::EventMotion) -> bool {
+        let to = self.tick_at(event.get_position().0);
+        match self.drag.as_mut() {
+            Some(drag) if drag.to != to => {
+                drag.to = to;
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Returns whether a marker was moved.
+    pub fn handle_released(&mut self) -> Result<bool> {
+        let drag = match self.drag.take() {
+            Some(drag) => drag,
+            None => return Ok(false)
+        };
+        let mut markers = self.ws.borrow().create_marker_info(0)?;
+        match markers.markers.get(drag.index) {
+            Some(marker) if marker.abs_tick != drag.to => {
+                let reletter = marker.is_rehearsal_letter();
+                markers.move_to(drag.index, drag.to);
+                if reletter {
+                    markers.reletter();
+                }
+                self.ws.borrow_mut().set_marker_info(&markers)?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+}
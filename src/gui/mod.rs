@@ -1,3 +1,6 @@
 pub mod mainwindow;
 pub mod pianoroll;
-pub mod eventlist;
\ No newline at end of file
+pub mod eventlist;
+pub mod tempolane;
+pub mod ruler;
+pub mod markerlane;
@@ -2,6 +2,8 @@ use gtk::prelude::*;
 use cairo::Context;
 use crate::smf::MidiWorkspace;
 use crate::smf::play::MidiMessage;
+use crate::project::ProjectState;
+use crate::settings::Color;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::sync::mpsc::Sender;
@@ -22,6 +24,8 @@ pub struct PianorollContext {
     pub viewport: Viewport,
     pub config: PianorollConfig,
     pub ws: Rc<RefCell<MidiWorkspace>>,
+    /// colors and mute/solo of the tracks
+    project: Rc<RefCell<ProjectState>>,
     note_height_cache: RefCell<Vec<f64>>,
     editing_state: EditingContext,
     pub current_track: u8,
@@ -32,6 +36,10 @@ pub struct PianorollContext {
 struct EditingContext {
     click_state: ClickState,
     quantize_unit: u64,
+    /// velocity of new notes
+    velocity: u8,
+    /// new notes go into the scale of the key, and transposing moves notes along it
+    snap_to_scale: bool,
 }
 
 impl Default for EditingContext {
@@ -39,6 +47,8 @@ impl Default for EditingContext {
         EditingContext {
             click_state: ClickState::default(),
             quantize_unit: 480/4,
+            velocity: 100,
+            snap_to_scale: false,
         }
     }
 }
@@ -59,6 +69,14 @@ impl EditingContext {
     pub fn set_quantize(&mut self, quantize: u64) {
         self.quantize_unit = quantize;
     }
+
+    pub fn velocity(&self) -> u8 {
+        self.velocity
+    }
+
+    pub fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = velocity.clamp(1, 127);
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -99,9 +117,82 @@ struct NoteDrawBounds {
 }
 
 impl PianorollContext {
-    pub fn new(viewport: Viewport, config: PianorollConfig, ws: Rc<RefCell<MidiWorkspace>>, midi_sender: Sender<MidiMessage>) -> Self {
+    /// quantize grid in ticks
+    pub fn quantize(&self) -> u64 {
+        self.editing_state.quantize()
+    }
+
+    pub fn set_quantize(&mut self, quantize: u64) {
+        self.editing_state.set_quantize(quantize);
+    }
+
+    /// velocity of notes drawn with the mouse
+    pub fn set_velocity(&mut self, velocity: u8) {
+        self.editing_state.set_velocity(velocity);
+    }
+
+    pub fn set_snap_to_scale(&mut self, snap_to_scale: bool) {
+        self.editing_state.snap_to_scale = snap_to_scale;
+    }
+
+    /// moves the notes of the current track by a semitone, or by a note of the scale while
+    /// snapping to it. Returns whether the notes were moved.
+    pub fn transpose(&mut self, direction: i32) -> bool {
+        let track = Some(self.current_track as usize);
+        let mut ws = self.ws.borrow_mut();
+        let result = if self.editing_state.snap_to_scale {
+            ws.transpose_in_key(track, direction)
+        } else {
+            ws.transpose(track, direction)
+        };
+        result.map_err(|e| warn!("failed to transpose: {}", e)).is_ok()
+    }
+
+    /// what to keep in a project or recovery session; the selection is up to the event list
+    pub fn editor_state(&self) -> crate::editor::EditorState {
+        crate::editor::EditorState {
+            current_track: self.current_track,
+            scroll_x: self.viewport.left_upper_x,
+            scroll_y: self.viewport.left_upper_y,
+            quantize: self.quantize(),
+            beat_width: self.config.beat_width,
+            selected_events: Vec::new(),
+        }
+    }
+
+    /// restores track, quantize and zoom. Scrolling is up to the scrolled window.
+    pub fn restore_editor_state(&mut self, state: &crate::editor::EditorState) {
+        self.current_track = state.current_track;
+        self.set_quantize(state.quantize.max(1));
+        if state.beat_width > 0.0 {
+            self.config.beat_width = state.beat_width;
+        }
+    }
+
+    /// color of the notes of the current track, and their opacity: faint while the track is not heard
+    fn note_color(&self) -> (Color, f64) {
+        let project = self.project.borrow();
+        let track = self.current_track as usize;
+        let color = project.track(track).color.unwrap_or(self.config.note_color);
+        (color, if project.is_audible(track) { 1.0 } else { 0.35 })
+    }
+
+    /// x of `abs_tick` in the lanes above the piano roll, which follow its scrolling
+    pub fn lane_x(&self, abs_tick: u64) -> f64 {
+        let resolution = self.ws.borrow().resolution().max(1) as f64;
+        self.config.white_width + abs_tick as f64 * self.config.beat_width / resolution - self.viewport.left_upper_x
+    }
+
+    /// the tick under `x` of a lane above the piano roll, not quantized
+    pub fn lane_tick(&self, x: f64) -> u64 {
+        let resolution = self.ws.borrow().resolution().max(1) as f64;
+        let x = (x - self.config.white_width + self.viewport.left_upper_x).max(0.0);
+        (x * resolution / self.config.beat_width) as u64
+    }
+
+    pub fn new(viewport: Viewport, config: PianorollConfig, ws: Rc<RefCell<MidiWorkspace>>, project: Rc<RefCell<ProjectState>>, midi_sender: Sender<MidiMessage>) -> Self {
         PianorollContext {
-            viewport, config, ws,
+            viewport, config, ws, project,
             note_height_cache: RefCell::new(vec![0.0; 128]),
             editing_state: EditingContext::new(),
             current_track: 0,
@@ -127,6 +218,14 @@ pub struct PianorollConfig {
     pub black_width: f64,
     pub note_height: f64,
     pub beat_width: f64,
+    pub white_key_color: Color,
+    pub black_key_color: Color,
+    pub note_color: Color,
+}
+
+fn set_source_color(cr: &Context, color: Color, alpha: f64) {
+    let (r, g, b) = color.rgb();
+    cr.set_source_rgba(r, g, b, alpha);
 }
 
 trait NoteDrawable {
@@ -198,6 +297,7 @@ impl PianorollContext {
 
         cr.set_matrix(init_transform);
         cr.translate(self.config.white_width, 0.0);
+        self.draw_scale(cr);
         if let Some(track) = self.ws.borrow().events_abs_tick(self.current_track as usize) {
             self.draw_notes(cr, &track.events(), &self.full_note_draw_bounds());
             self.draw_pending_note(cr);
@@ -241,7 +341,7 @@ impl PianorollContext {
             cr.stroke();
 
             cr.rectangle(0.0, height, white_width, white_height);
-            cr.set_source_rgba(1.0, 1.0, 1.0, 1.0);
+            set_source_color(cr, ctx.config.white_key_color, 1.0);
             cr.fill();
 
             if is_c {
@@ -276,7 +376,7 @@ impl PianorollContext {
             }
         }
 
-        cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+        set_source_color(cr, ctx.config.black_key_color, 1.0);
         cr.rectangle(0.0, 0.0, black_width, black_height / 2.0);
         cr.fill();
 
@@ -298,6 +398,50 @@ impl PianorollContext {
         }
     }
 
+    /// shades the rows of the notes in the scale of the key signatures of the conductor track,
+    /// if it has any or new notes snap to the scale
+    fn draw_scale(&self, cr: &Context) {
+        let keys = match self.ws.borrow().create_key_signature_info(0) {
+            Ok(keys) => keys,
+            Err(_) => return
+        };
+        if keys.changes.is_empty() && !self.editing_state.snap_to_scale {
+            return;
+        }
+        let bounds = self.full_note_draw_bounds();
+        let mut starts: Vec<u64> = keys.changes.iter().map(|(abs, _)| *abs).collect();
+        if starts.first() != Some(&0) {
+            starts.insert(0, 0);
+        }
+        cr.set_source_rgba(0.2, 0.5, 1.0, 0.08);
+        for (i, &start) in starts.iter().enumerate() {
+            let left = self.calculate_note_h_cord(start);
+            let right = starts.get(i + 1).map(|&end| self.calculate_note_h_cord(end)).unwrap_or(bounds.right);
+            if right < bounds.left || left > bounds.right {
+                continue;
+            }
+            let key = keys.key_at(start);
+            for note in (12..127).filter(|&note| key.contains(note)) {
+                let y = self.calculate_note_v_cord(note);
+                if y + self.config.note_height >= bounds.upper && y <= bounds.lower {
+                    cr.rectangle(left, y, right - left, self.config.note_height);
+                }
+            }
+            cr.fill();
+        }
+    }
+
+    /// `note` moved into the scale of the key at `abs_tick` while snapping to it
+    fn snapped_note(&self, abs_tick: u64, note: u8) -> u8 {
+        if !self.editing_state.snap_to_scale {
+            return note;
+        }
+        match self.ws.borrow().create_key_signature_info(0) {
+            Ok(keys) => keys.key_at(abs_tick).snap(note, 0),
+            Err(_) => note
+        }
+    }
+
     fn draw_a_note<N: NoteDrawable>(&self, cr: &Context, bounds: &NoteDrawBounds, note: &N) -> NoteDrawnPosition {
         let end_cord = self.calculate_note_h_cord(note.end_tick());
         if end_cord < bounds.left {
@@ -328,7 +472,8 @@ impl PianorollContext {
         let (notes, _) = Self::build_drawing_graph(track);
 
         let mut _note_drawn = 0;
-        cr.set_source_rgba(1.0, 0.0, 0.0, 1.0);
+        let (color, alpha) = self.note_color();
+        set_source_color(cr, color, alpha);
         for note in &notes {
             match self.draw_a_note(cr, bounds, note) {
                 NoteDrawnPosition::Left | NoteDrawnPosition::Up | NoteDrawnPosition::Down | NoteDrawnPosition::Invalid => continue,
@@ -349,7 +494,7 @@ impl PianorollContext {
                 match clicked_pos.end {
                     Some(end_pos) => {
                         let start_pos = clicked_pos.start;
-                        cr.set_source_rgba(1.0, 0.0, 0.0, 0.5); // transparent red color
+                        set_source_color(cr, self.note_color().0, 0.5); // transparent note color
                         let start_info = self.parse_click_position(start_pos);
                         let end_info = self.parse_click_position(end_pos);
                         if start_info.is_some() && end_info.is_some() {
@@ -358,7 +503,7 @@ impl PianorollContext {
                             let start_quantized = self.quantize_time(start_tick);
                             let end_quantized = self.quantize_time(end_tick);
                             self.draw_a_note(cr, &self.full_note_draw_bounds(), &MinimalNote{
-                                note: start_note,
+                                note: self.snapped_note(start_quantized, start_note),
                                 start_tick: start_quantized,
                                 end_tick: end_quantized
                             });
@@ -384,58 +529,75 @@ impl PianorollContext {
         beat_width * (abs_tick as f64 / beat_tick as f64) as f64
     }
 
+    /// bar lines, and lighter lines for the beats of the time signatures of the conductor track.
+    /// The bars are numbered on the ruler above.
     fn draw_timeline<W: WidgetExt>(&self, w: &W, cr: &Context) {
-        let ctx = self;
-        let height: f64 = WHITE_KEYS as f64 * ctx.config.white_height;
-        let width = ctx.viewport.max_width;
-        let beat_width = ctx.config.beat_width;
-        let ws = RefCell::borrow(&ctx.ws);
-        let ts_info = ws.create_time_signature_info(0); // TODO: always track 0?
-        if ts_info.is_none() {
+        let height: f64 = WHITE_KEYS as f64 * self.config.white_height;
+        let width = self.viewport.max_width;
+        let ws = RefCell::borrow(&self.ws);
+        if ws.division().is_smpte() {
+            self.draw_timecode_timeline(w, cr);
             return;
         }
-        let ts_info = ts_info.unwrap();
-        let tick_per_beat = ws.resolution();
-
-        let mut _i = 0;
-        let mut measure = 0;
-        let mut abs_tick = 0;
-        let mut last_x = 0.0;
-
-        let font_resolution = pangocairo::context_get_resolution(&w.get_pango_context().unwrap());
-        cr.select_font_face("Monospace", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
-        cr.set_font_size(20.0 * font_resolution / 72.0);
-        'top: loop {
-            let (curr_nn, curr_dd) = ts_info.time_signature(abs_tick).unwrap();
-            let interval = beat_width * (4.0 / curr_dd as f64);
-            let interval_tick = (tick_per_beat as f64 * (4.0 / curr_dd as f64)) as u64;
-
-            for beat in 0..curr_nn {
-                let x = last_x + interval;
-                if x > width {
-                    break 'top;
-                }
-
-                if beat == curr_nn - 1 {
-                    cr.set_source_rgba(0.4, 0.4, 0.4, 1.0);
-                } else {
-                    cr.set_source_rgba(0.7, 0.7, 0.7, 0.7);
-                }
+        let ts_info = match ws.create_time_signature_info(0) {
+            Ok(ts_info) => ts_info,
+            Err(e) => {
+                debug!("timeline not drawn: {}", e);
+                return;
+            }
+        };
+        let resolution = ws.resolution().max(1) as u16;
+        let x_of = |abs_tick: f64| self.config.beat_width * abs_tick / resolution as f64;
+        let end = (width / self.config.beat_width * resolution as f64) as u64;
+
+        for bar in ts_info.bars(resolution, end) {
+            let beat_ticks = bar.signature.beat_ticks(resolution);
+            cr.set_source_rgba(0.7, 0.7, 0.7, 0.7);
+            let mut beat = 1.0;
+            while beat * beat_ticks < bar.length as f64 {
+                let x = x_of(bar.start as f64 + beat * beat_ticks);
                 cr.move_to(x, 0.0);
                 cr.line_to(x, height);
                 cr.stroke();
+                beat += 1.0;
+            }
+            cr.set_source_rgba(0.4, 0.4, 0.4, 1.0);
+            let x = x_of(bar.start as f64);
+            cr.move_to(x, 0.0);
+            cr.line_to(x, height);
+            cr.stroke();
+        }
+    }
 
-                if beat == 0 {
-                    cr.move_to(last_x, ctx.viewport.left_upper_y + ctx.config.white_height);
-                    cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
-                    measure += 1;
-                    cr.show_text(&format!("{}", measure));
-                }
+    /// SMPTE based files have no measures; a line is drawn every second
+    /// (resolution() ticks) and labelled with its timecode.
+    fn draw_timecode_timeline<W: WidgetExt>(&self, w: &W, cr: &Context) {
+        let height: f64 = WHITE_KEYS as f64 * self.config.white_height;
+        let width = self.viewport.max_width;
+        let beat_width = self.config.beat_width;
+        let ws = RefCell::borrow(&self.ws);
+        let tick_per_second = ws.resolution() as u64;
 
-                last_x = x;
-                _i += 1;
-                abs_tick += interval_tick;
+        let font_resolution = pangocairo::context_get_resolution(&w.get_pango_context().unwrap());
+        cr.select_font_face("Monospace", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+        cr.set_font_size(12.0 * font_resolution / 72.0);
+        let mut second = 0;
+        loop {
+            let x = beat_width * second as f64;
+            if x > width {
+                break;
             }
+            cr.set_source_rgba(0.4, 0.4, 0.4, 1.0);
+            cr.move_to(x, 0.0);
+            cr.line_to(x, height);
+            cr.stroke();
+
+            if let Some(timecode) = ws.timecode(second * tick_per_second) {
+                cr.move_to(x, self.viewport.left_upper_y + self.config.white_height);
+                cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+                cr.show_text(&timecode);
+            }
+            second += 1;
         }
     }
 
@@ -490,16 +652,16 @@ impl PianorollContext {
             1 => {
                 self.editing_state.click_state = ClickState::Clicked(NoteEditState::only_start(pos));
                 // preview sound
-                if let Some(clicked_note) = self.parse_click_position(pos) {
-                    let msg = rimd::MidiMessage::note_on(clicked_note.1, 100, self.current_track);
-                    self.midi_sender.send(MidiMessage::Midi(msg.data)).unwrap();
-                    let sender_c = self.midi_sender.clone();
-                    let track = self.current_track;
-                    std::thread::spawn(move || {
-                        std::thread::sleep(std::time::Duration::from_secs(1));
-                        let msg = rimd::MidiMessage::note_off(clicked_note.1, 0, track);
-                        let _ = sender_c.send(MidiMessage::Midi(msg.data));
-                    });
+                if let Some((tick, note)) = self.parse_click_position(pos) {
+                    let note = self.snapped_note(self.quantize_time(tick), note);
+                    let msg = rimd::MidiMessage::note_on(note, 100, self.current_track);
+                    let off_at = std::time::Instant::now() + std::time::Duration::from_secs(1);
+                    let off = rimd::MidiMessage::note_off(note, 0, self.current_track);
+                    for message in vec![MidiMessage::Midi(msg.data), MidiMessage::MidiAt(off_at, off.data)] {
+                        if let Err(e) = self.midi_sender.send(message) {
+                            warn!("failed to preview the note: {}", e);
+                        }
+                    }
                 }
             },
             3 => self.editing_state.click_state = ClickState::SubClicked(pos),
@@ -534,6 +696,7 @@ impl PianorollContext {
                     let (mut end_tick, _) = release_pos_parsed.unwrap();
                     start_tick = self.quantize_time(start_tick);
                     end_tick = self.quantize_time(end_tick);
+                    let note = self.snapped_note(start_tick, note);
                     if start_tick >= end_tick {
                         debug!("note_on: invalid range: failed {:?} < {:?}", start_tick, end_tick);
                         false
@@ -542,7 +705,7 @@ impl PianorollContext {
                         let mut ws = ws.borrow_mut();
                         if let Some(mut track) = ws.events_abs_tick(self.current_track as usize) {
                             track.append_notes(vec![
-                                (start_tick, note, 100, 0),
+                                (start_tick, note, self.editing_state.velocity(), 0),
                                 (end_tick, note, 0, 0)
                             ]);
                             track.clean();
@@ -585,7 +748,10 @@ impl PianorollContext {
     fn delete_note_tick_note(&mut self, tick: u64, note: u8) -> bool {
         let ws = Rc::clone(&self.ws);
         let mut ws = ws.borrow_mut();
-        let mut track = ws.events_abs_tick(self.current_track as usize).unwrap();
+        let mut track = match ws.events_abs_tick(self.current_track as usize) {
+            Some(track) => track,
+            None => return false
+        };
         let (note_info, _) = Self::build_drawing_graph(&track.events());
         for info in &note_info {
             if info.note == note && info.start_tick <= tick && tick <= info.end_tick {
@@ -602,12 +768,7 @@ impl PianorollContext {
     }
 
     fn quantize_time(&self, abs_tick: u64) -> u64 {
-        let diff = abs_tick % self.editing_state.quantize_unit;
-        if diff > (self.editing_state.quantize_unit / 2) {
-            abs_tick + self.editing_state.quantize_unit - diff
-        } else {
-            abs_tick - diff
-        }
+        crate::smf::util::quantize_tick(abs_tick, self.editing_state.quantize_unit)
     }
 
     /// returns (abs_tick, note)
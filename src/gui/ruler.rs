@@ -0,0 +1,149 @@
+//! The ruler above the piano roll, which numbers the bars and edits the time signatures
+//! and key signatures of the conductor track.
+
+use gtk::prelude::*;
+use cairo::Context;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::error::Result;
+use crate::smf::{MidiWorkspace, KeySignatureInfo, TimeSignatureInfo};
+use crate::smf::meter::{Bar, TimeSignature};
+use crate::smf::key::KeySignature;
+use super::pianoroll::PianorollContext;
+
+pub struct Ruler {
+    ws: Rc<RefCell<MidiWorkspace>>,
+    /// what the piano roll shows, so that bars line up with it
+    ps: Rc<RefCell<PianorollContext>>,
+}
+
+impl Ruler {
+    pub fn new(ws: Rc<RefCell<MidiWorkspace>>, ps: Rc<RefCell<PianorollContext>>) -> Self {
+        Ruler { ws, ps }
+    }
+
+    /// time signatures of the conductor track, none if it cannot be read or the file has no bars
+    fn time_signature_info(&self) -> Option<TimeSignatureInfo> {
+        let ws = self.ws.borrow();
+        if ws.track_count() == 0 || ws.division().is_smpte() {
+            return None;
+        }
+        ws.create_time_signature_info(0).map_err(|e| warn!("cannot edit the time signature: {}", e)).ok()
+    }
+
+    fn key_signature_info(&self) -> Option<KeySignatureInfo> {
+        let ws = self.ws.borrow();
+        if ws.track_count() == 0 {
+            return None;
+        }
+        ws.create_key_signature_info(0).map_err(|e| warn!("cannot edit the key: {}", e)).ok()
+    }
+
+    fn resolution(&self) -> u16 {
+        self.ws.borrow().resolution().max(1) as u16
+    }
+
+    /// the bar under `x`, and the time signature change it starts with, if there is one
+    pub fn bar_at(&self, x: f64) -> Option<(Bar, Option<TimeSignature>)> {
+        if x < self.ps.borrow().config.white_width {
+            return None;
+        }
+        let ts = self.time_signature_info()?;
+        let bar = ts.bar_at(self.ps.borrow().lane_tick(x), self.resolution());
+        Some((bar, ts.change_at(bar.start)))
+    }
+
+    /// sets the time signature from the bar starting at `abs_tick` on
+    pub fn set_time_signature(&self, abs_tick: u64, signature: TimeSignature) -> Result<()> {
+        let mut ts = self.ws.borrow().create_time_signature_info(0)?;
+        ts.set_at_bar(abs_tick, signature, self.resolution())?;
+        self.ws.borrow_mut().set_time_signature_info(&ts)
+    }
+
+    /// removes the time signature change at `abs_tick`
+    pub fn remove_time_signature(&self, abs_tick: u64) -> Result<()> {
+        let mut ts = self.ws.borrow().create_time_signature_info(0)?;
+        ts.remove_at_bar(abs_tick, self.resolution())?;
+        self.ws.borrow_mut().set_time_signature_info(&ts)
+    }
+
+    /// the key at `abs_tick`, and the key change there, if there is one
+    pub fn key_at(&self, abs_tick: u64) -> (KeySignature, Option<KeySignature>) {
+        match self.key_signature_info() {
+            Some(keys) => (keys.key_at(abs_tick), keys.change_at(abs_tick)),
+            None => (KeySignature::default(), None)
+        }
+    }
+
+    /// sets the key from `abs_tick` on
+    pub fn set_key(&self, abs_tick: u64, key: KeySignature) -> Result<()> {
+        let mut keys = self.ws.borrow().create_key_signature_info(0)?;
+        keys.set(abs_tick, key);
+        self.ws.borrow_mut().set_key_signature_info(&keys)
+    }
+
+    /// removes the key change at `abs_tick`
+    pub fn remove_key(&self, abs_tick: u64) -> Result<()> {
+        let mut keys = self.ws.borrow().create_key_signature_info(0)?;
+        keys.remove(abs_tick);
+        self.ws.borrow_mut().set_key_signature_info(&keys)
+    }
+
+    pub fn draw(&self, w: &gtk::DrawingArea, cr: &Context) -> Inhibit {
+        let width = w.get_allocated_width() as f64;
+        let height = w.get_allocated_height() as f64;
+        let white_width = self.ps.borrow().config.white_width;
+
+        cr.set_source_rgba(0.9, 0.9, 0.9, 1.0);
+        cr.paint();
+        cr.set_font_size(10.0);
+        cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+        cr.move_to(4.0, height - 4.0);
+        cr.show_text("Bar");
+
+        let ts = match self.time_signature_info() {
+            Some(ts) => ts,
+            None => return Inhibit(true)
+        };
+        let keys = self.key_signature_info().unwrap_or_else(|| KeySignatureInfo::new(Vec::new(), false));
+        // the key at the left edge
+        cr.move_to(4.0, 10.0);
+        cr.show_text(&format!("Key: {}", keys.key_at(self.ps.borrow().lane_tick(white_width)).short_name()));
+
+        cr.rectangle(white_width, 0.0, width - white_width, height);
+        cr.clip();
+        let end = self.ps.borrow().lane_tick(width);
+        cr.set_line_width(1.0);
+        for bar in ts.bars(self.resolution(), end) {
+            let x = self.ps.borrow().lane_x(bar.start);
+            if self.ps.borrow().lane_x(bar.start + bar.length) < white_width {
+                // scrolled out of sight
+                continue;
+            }
+            cr.set_source_rgba(0.4, 0.4, 0.4, 1.0);
+            cr.move_to(x, height / 2.0);
+            cr.line_to(x, height);
+            cr.stroke();
+            cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+            cr.move_to(x + 3.0, height - 4.0);
+            cr.show_text(&bar.number.to_string());
+            let mut label_x = x + 3.0;
+            if let Some(signature) = ts.change_at(bar.start) {
+                let label = signature.to_string();
+                cr.set_source_rgba(0.1, 0.3, 0.9, 1.0);
+                cr.move_to(label_x, 10.0);
+                cr.show_text(&label);
+                label_x += cr.text_extents(&label).x_advance + 6.0;
+            }
+            for (abs, key) in keys.changes.iter().filter(|(abs, _)| *abs >= bar.start && *abs < bar.start + bar.length) {
+                let label = key.short_name();
+                label_x = label_x.max(self.ps.borrow().lane_x(*abs) + 3.0);
+                cr.set_source_rgba(0.1, 0.5, 0.2, 1.0);
+                cr.move_to(label_x, 10.0);
+                cr.show_text(&label);
+                label_x += cr.text_extents(&label).x_advance + 6.0;
+            }
+        }
+        Inhibit(true)
+    }
+}
@@ -0,0 +1,258 @@
+//! The lane above the piano roll which shows and edits the tempo of the conductor track.
+
+use gtk::prelude::*;
+use cairo::Context;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::smf::{MidiWorkspace, TempoInfo, DEFAULT_TEMPO};
+use crate::smf::tempo::{self, TempoCurve};
+use super::pianoroll::PianorollContext;
+
+/// tempo at the bottom and the top of the lane
+const MIN_BPM: f64 = 20.0;
+const MAX_BPM: f64 = 300.0;
+/// tempos the guide lines are drawn at
+const GUIDE_BPMS: [f64; 4] = [60.0, 120.0, 180.0, 240.0];
+/// how close to a tempo change a click has to be to grab it, in pixels
+const GRAB_DISTANCE: f64 = 6.0;
+
+#[derive(Debug, Clone, Copy)]
+enum Drag {
+    /// moving the change `from` to `to`
+    Move { from: (u64, u32), to: (u64, u32) },
+    /// drawing a gradual change from the change `from`, ending at `to`
+    Ramp { from: (u64, u32), to: (u64, u32) },
+}
+
+pub struct TempoLane {
+    ws: Rc<RefCell<MidiWorkspace>>,
+    /// what the piano roll shows, so that ticks line up with it
+    ps: Rc<RefCell<PianorollContext>>,
+    drag: Option<Drag>,
+    /// shape of gradual changes
+    pub curve: TempoCurve,
+}
+
+impl TempoLane {
+    pub fn new(ws: Rc<RefCell<MidiWorkspace>>, ps: Rc<RefCell<PianorollContext>>) -> Self {
+        TempoLane { ws, ps, drag: None, curve: TempoCurve::Linear }
+    }
+
+    /// tempo changes of the conductor track, none if it cannot be read
+    fn tempo_info(&self) -> Option<TempoInfo> {
+        let ws = self.ws.borrow();
+        if ws.track_count() == 0 {
+            return None;
+        }
+        ws.create_tempo_info(0).map_err(|e| warn!("cannot edit the tempo: {}", e)).ok()
+    }
+
+    /// the tempo changes as they will be once the drag is done
+    fn dragged(&self, mut tempo: TempoInfo) -> TempoInfo {
+        match self.drag {
+            Some(Drag::Move { from, to }) => {
+                tempo.delete(from);
+                tempo.set(to.0, to.1);
+            },
+            Some(Drag::Ramp { from, to }) if from.0 != to.0 => {
+                let step = self.ps.borrow().quantize();
+                tempo.insert_ramp(from.0, to.0, tempo::bpm(to.1), self.curve, step);
+            },
+            _ => {}
+        }
+        tempo
+    }
+
+    fn write(&self, tempo: &TempoInfo) -> bool {
+        match self.ws.borrow_mut().set_tempo_info(tempo) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("failed to change the tempo: {}", e);
+                false
+            }
+        }
+    }
+
+    fn x_of(&self, abs_tick: u64) -> f64 {
+        self.ps.borrow().lane_x(abs_tick)
+    }
+
+    /// the tick under `x`, on the quantize grid
+    fn tick_at(&self, x: f64) -> u64 {
+        let ps = self.ps.borrow();
+        crate::smf::util::quantize_tick(ps.lane_tick(x), ps.quantize())
+    }
+
+    fn y_of(usec: u32, height: f64) -> f64 {
+        let bpm = tempo::bpm(usec).max(MIN_BPM).min(MAX_BPM);
+        height - (bpm - MIN_BPM) / (MAX_BPM - MIN_BPM) * height
+    }
+
+    /// the tempo at `y`, in whole beats per minute; finer tempos are entered by double clicking a change
+    fn tempo_at(y: f64, height: f64) -> u32 {
+        let bpm = MIN_BPM + (height - y) / height * (MAX_BPM - MIN_BPM);
+        tempo::usec_per_quarter(bpm.round().max(MIN_BPM).min(MAX_BPM))
+    }
+
+    /// the change drawn at `pos`
+    fn change_at(&self, tempo: &TempoInfo, pos: (f64, f64), height: f64) -> Option<(u64, u32)> {
+        tempo.changes.iter()
+            .find(|&&(abs, usec)| {
+                let (dx, dy) = (self.x_of(abs) - pos.0, Self::y_of(usec, height) - pos.1);
+                (dx * dx + dy * dy).sqrt() <= GRAB_DISTANCE
+            })
+            .copied()
+    }
+
+    /// the change under `pos` of the lane, to be edited
+    pub fn change_under(&self, w: &gtk::DrawingArea, pos: (f64, f64)) -> Option<(u64, u32)> {
+        let tempo = self.tempo_info()?;
+        self.change_at(&tempo, pos, w.get_allocated_height() as f64)
+    }
+
+    /// Sets the tempo at `abs_tick` to `usec` microseconds per quarter note. Returns whether the tempo was changed.
+    pub fn set_tempo(&mut self, abs_tick: u64, usec: u32) -> bool {
+        match self.tempo_info() {
+            Some(mut tempo) if tempo.change_at(abs_tick) != Some((abs_tick, usec)) => {
+                tempo.set(abs_tick, usec);
+                self.write(&tempo)
+            },
+            _ => false
+        }
+    }
+
+    pub fn draw(&self, w: &gtk::DrawingArea, cr: &Context) -> Inhibit {
+        let width = w.get_allocated_width() as f64;
+        let height = w.get_allocated_height() as f64;
+        let white_width = self.ps.borrow().config.white_width;
+
+        cr.set_source_rgba(0.95, 0.95, 0.95, 1.0);
+        cr.paint();
+
+        cr.set_font_size(10.0);
+        cr.set_line_width(1.0);
+        for &bpm in &GUIDE_BPMS {
+            let y = Self::y_of(tempo::usec_per_quarter(bpm), height);
+            cr.set_source_rgba(0.8, 0.8, 0.8, 1.0);
+            cr.move_to(white_width, y);
+            cr.line_to(width, y);
+            cr.stroke();
+            cr.set_source_rgba(0.4, 0.4, 0.4, 1.0);
+            cr.move_to(white_width - 24.0, y + 4.0);
+            cr.show_text(&bpm.to_string());
+        }
+        cr.set_source_rgba(0.0, 0.0, 0.0, 1.0);
+        cr.move_to(4.0, 14.0);
+        cr.show_text("Tempo");
+
+        let tempo = match self.tempo_info() {
+            Some(tempo) => tempo,
+            None => return Inhibit(true)
+        };
+        cr.rectangle(white_width, 0.0, width - white_width, height);
+        cr.clip();
+        if self.drag.is_some() {
+            self.draw_tempo(cr, &tempo, width, height, (0.6, 0.6, 0.6, 1.0));
+            self.draw_tempo(cr, &self.dragged(tempo), width, height, (0.1, 0.3, 0.9, 0.8));
+        } else {
+            self.draw_tempo(cr, &tempo, width, height, (0.1, 0.3, 0.9, 1.0));
+        }
+        Inhibit(true)
+    }
+
+    /// draws the tempo as a staircase, with a handle at every change
+    fn draw_tempo(&self, cr: &Context, tempo: &TempoInfo, width: f64, height: f64, (r, g, b, a): (f64, f64, f64, f64)) {
+        cr.set_source_rgba(r, g, b, a);
+        cr.set_line_width(2.0);
+        let mut usec = DEFAULT_TEMPO;
+        cr.move_to(self.x_of(0), Self::y_of(usec, height));
+        for &(abs, new_usec) in &tempo.changes {
+            let x = self.x_of(abs);
+            cr.line_to(x, Self::y_of(usec, height));
+            cr.line_to(x, Self::y_of(new_usec, height));
+            usec = new_usec;
+        }
+        cr.line_to(width, Self::y_of(usec, height));
+        cr.stroke();
+
+        let mut last_label_x = f64::MIN;
+        for &(abs, usec) in &tempo.changes {
+            let (x, y) = (self.x_of(abs), Self::y_of(usec, height));
+            cr.arc(x, y, 3.0, 0.0, 2.0 * std::f64::consts::PI);
+            cr.fill();
+            // labels of a dense ramp would overlap
+            if x - last_label_x > 30.0 {
+                cr.move_to(x + 4.0, (y - 4.0).max(10.0));
+                cr.show_text(&tempo::format_bpm(usec));
+                last_label_x = x;
+            }
+        }
+    }
+
+    /// Returns whether the tempo was changed.
+    pub fn handle_pressed(&mut self, w: &gtk::DrawingArea, event: &gdk::EventButton) -> bool {
+        let pos = event.get_position();
+        let height = w.get_allocated_height() as f64;
+        if pos.0 < self.ps.borrow().config.white_width {
+            return false;
+        }
+        let mut tempo = match self.tempo_info() {
+            Some(tempo) => tempo,
+            None => return false
+        };
+        let grabbed = self.change_at(&tempo, pos, height);
+        if event.get_event_type() != gdk::EventType::ButtonPress {
+            // the second press of a double click, which edits the change instead
+            self.drag = None;
+            return false;
+        }
+        match (event.get_button(), grabbed) {
+            (1, Some(change)) => {
+                self.drag = Some(if event.get_state().contains(gdk::ModifierType::SHIFT_MASK) {
+                    Drag::Ramp { from: change, to: change }
+                } else {
+                    Drag::Move { from: change, to: change }
+                });
+                false
+            },
+            (1, None) => {
+                let abs_tick = self.tick_at(pos.0);
+                let usec = Self::tempo_at(pos.1, height);
+                tempo.set(abs_tick, usec);
+                debug!("tempo {} bpm at tick {}", tempo::format_bpm(usec), abs_tick);
+                self.write(&tempo)
+            },
+            (3, Some(change)) => {
+                tempo.delete(change);
+                debug!("removed tempo {} bpm at tick {}", tempo::format_bpm(change.1), change.0);
+                self.write(&tempo)
+            },
+            _ => false
+        }
+    }
+
+    /// Returns whether the lane needs to be redrawn.
+    pub fn handle_motion(&mut self, w: &gtk::DrawingArea, event: &gdk::EventMotion) -> bool {
+        let pos = event.get_position();
+        let height = w.get_allocated_height() as f64;
+        let to = (self.tick_at(pos.0), Self::tempo_at(pos.1, height));
+        match self.drag.as_mut() {
+            // the tempo at the start of the song stays there
+            Some(Drag::Move { from, to: moved }) => *moved = (if from.0 == 0 { 0 } else { to.0 }, to.1),
+            Some(Drag::Ramp { to: end, .. }) => *end = to,
+            None => return false
+        }
+        true
+    }
+
+    /// Returns whether the tempo was changed.
+    pub fn handle_released(&mut self, _w: &gtk::DrawingArea, _event: &gdk::EventButton) -> bool {
+        let changed = match (self.drag, self.tempo_info()) {
+            (Some(Drag::Move { from, to }), Some(tempo)) if from != to => self.write(&self.dragged(tempo)),
+            (Some(Drag::Ramp { from, to }), Some(tempo)) if from.0 != to.0 => self.write(&self.dragged(tempo)),
+            _ => false
+        };
+        self.drag = None;
+        changed
+    }
+}
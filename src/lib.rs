@@ -1,10 +1,26 @@
-extern crate gtk;
+#[cfg(feature = "gui")] extern crate gtk;
 extern crate rimd;
-extern crate cairo;
-extern crate pangocairo;
-extern crate gdk;
+#[cfg(feature = "gui")] extern crate cairo;
+#[cfg(feature = "gui")] extern crate pangocairo;
+#[cfg(feature = "gui")] extern crate gdk;
+#[cfg(feature = "gui")] extern crate glib;
 extern crate midir;
+extern crate cpal;
+extern crate serde;
+extern crate serde_json;
+extern crate zip;
 #[macro_use] extern crate log;
 
+#[cfg(feature = "gui")]
 pub mod gui;
 pub mod smf;
+pub mod cli;
+pub mod error;
+pub mod editor;
+pub mod recovery;
+pub mod project;
+pub mod dirs;
+pub mod recent;
+pub mod settings;
+
+pub use error::{Error, Result};
@@ -3,5 +3,37 @@ extern crate midie;
 fn main() {
     env_logger::init();
 
-    midie::gui::mainwindow::construct_main_window();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.get(0).map(|s| s.as_str()) {
+        None => open(None),
+        // what follows is a file, even if it is called like a command
+        Some("--") => open(args.get(1).map(|s| s.as_str())),
+        Some(command) if midie::cli::is_command(command) => {
+            if let Err(e) = midie::cli::run(&args) {
+                eprintln!("midie: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(option) if option.starts_with('-') => {
+            eprintln!("midie: unknown option: {}\n{}", option, midie::cli::USAGE);
+            std::process::exit(1);
+        },
+        Some(file) => open(Some(file))
+    }
+}
+
+#[cfg(feature = "gui")]
+fn open(file: Option<&str>) {
+    midie::gui::mainwindow::construct_main_window(file.map(std::path::PathBuf::from))
+}
+
+#[cfg(not(feature = "gui"))]
+fn open(file: Option<&str>) {
+    match file {
+        None => println!("{}", midie::cli::USAGE),
+        Some(file) => {
+            eprintln!("midie: cannot open {}: built without the editor\n{}", file, midie::cli::USAGE);
+            std::process::exit(1);
+        }
+    }
 }
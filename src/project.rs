@@ -0,0 +1,248 @@
+//! The midie project format, which keeps what a standard midi file cannot.
+//!
+//! A project (`song.midie`) is a zip archive of two entries:
+//! `sequence.mid`, the workspace as a standard midi file, and `project.json`,
+//! the editor state and track settings.
+//!
+//! `project.json` carries the version of midie's format which wrote it. Fields are only
+//! ever added: missing fields take their defaults, and fields this version does not know
+//! are kept and written back, so that older versions can edit newer projects.
+
+use crate::editor::{EditorState, TrackSettings};
+use crate::error::{Error, Result};
+use crate::smf::{reader, save, AbsTrack, MidiWorkspace};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::Path;
+
+pub const PROJECT_EXTENSION: &str = "midie";
+/// the version of the project format written by this version of midie
+pub const PROJECT_VERSION: u32 = 1;
+
+const SEQUENCE_ENTRY: &str = "sequence.mid";
+const STATE_ENTRY: &str = "project.json";
+
+/// Everything in a project besides the midi data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProjectState {
+    pub version: u32,
+    pub editor: EditorState,
+    /// settings of each track, in track order
+    pub tracks: Vec<TrackSettings>,
+    /// fields written by a newer version
+    #[serde(flatten)]
+    pub unknown: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Default for ProjectState {
+    fn default() -> Self {
+        ProjectState {
+            version: PROJECT_VERSION,
+            editor: EditorState::default(),
+            tracks: Vec::new(),
+            unknown: serde_json::Map::new(),
+        }
+    }
+}
+
+impl ProjectState {
+    /// whether it was written by a newer version of midie, whose additions will not be used
+    pub fn is_newer(&self) -> bool {
+        self.version > PROJECT_VERSION
+    }
+
+    /// settings of the track, default if there are none
+    pub fn track(&self, track: usize) -> TrackSettings {
+        self.tracks.get(track).cloned().unwrap_or_default()
+    }
+
+    /// settings of the track to change, made for it if there are none yet
+    pub fn track_mut(&mut self, track: usize) -> &mut TrackSettings {
+        if self.tracks.len() <= track {
+            self.tracks.resize(track + 1, TrackSettings::default());
+        }
+        &mut self.tracks[track]
+    }
+
+    /// whether the track is heard: it is not muted, and soloed if any track is
+    pub fn is_audible(&self, track: usize) -> bool {
+        let settings = self.track(track);
+        !settings.mute && (settings.solo || !self.tracks.iter().any(|t| t.solo))
+    }
+
+    /// the port the audible tracks are played on. Only one output is played at a time,
+    /// so tracks asking for different ports get the first one.
+    pub fn output_port(&self, track_count: usize) -> Option<&str> {
+        let mut ports = (0..track_count)
+            .filter(|i| self.is_audible(*i))
+            .filter_map(|i| self.tracks.get(i).and_then(|t| t.port.as_deref()));
+        let port = ports.next()?;
+        if ports.any(|p| p != port) {
+            warn!("tracks are routed to different ports, playing all of them on {}", port);
+        }
+        Some(port)
+    }
+}
+
+/// merges tracks like `MidiWorkspace::merge_tracks`. The merged track keeps the settings of the first one.
+pub fn merge_tracks(ws: &mut MidiWorkspace, state: &mut ProjectState, tracks: &[usize]) -> Result<usize> {
+    state.tracks.resize(ws.track_count(), TrackSettings::default());
+    let first = ws.merge_tracks(tracks)?;
+    let mut tracks = tracks.to_vec();
+    tracks.sort();
+    tracks.dedup();
+    for track in tracks.iter().skip(1).rev() {
+        state.tracks.remove(*track);
+    }
+    Ok(first)
+}
+
+/// splits a track like `MidiWorkspace::split_track_by_channel`. Every track split off
+/// gets the settings of the track.
+pub fn split_track_by_channel(ws: &mut MidiWorkspace, state: &mut ProjectState, track: usize) -> Result<usize> {
+    state.tracks.resize(ws.track_count(), TrackSettings::default());
+    let count = ws.split_track_by_channel(track)?;
+    let settings = state.tracks.remove(track);
+    for i in 0..count {
+        state.tracks.insert(track + i, settings.clone());
+    }
+    Ok(count)
+}
+
+/// converts like `MidiWorkspace::convert_format`, which merges or splits tracks.
+pub fn convert_format(ws: &mut MidiWorkspace, state: &mut ProjectState, format: rimd::SMFFormat) -> Result<()> {
+    use rimd::SMFFormat::{Single, MultiTrack};
+    match (ws.format(), format) {
+        (Single, MultiTrack) if ws.track_count() > 0 => split_track_by_channel(ws, state, 0).map(|_| ()),
+        (MultiTrack, Single) if ws.track_count() > 0 => {
+            let tracks: Vec<usize> = (0..ws.track_count()).collect();
+            merge_tracks(ws, state, &tracks)?;
+            // with one track left, this only changes the format
+            ws.convert_format(format)
+        },
+        _ => ws.convert_format(format)
+    }
+}
+
+/// a copy of the workspace in which only the audible tracks (see `ProjectState::is_audible`)
+/// have midi messages. Meta events, such as the tempo, are kept.
+pub fn audible(ws: &MidiWorkspace, state: &ProjectState) -> Result<MidiWorkspace> {
+    let mut audible = ws.clone();
+    for i in (0..ws.track_count()).filter(|i| !state.is_audible(*i)) {
+        let events = ws.events_abs_tick(i).ok_or(Error::NoSuchTrack(i))?.events().iter()
+            .filter(|ate| !matches!(ate.track_event.event, rimd::Event::Midi(_)))
+            .cloned()
+            .collect();
+        let mut track = AbsTrack::new(events);
+        track.clean();
+        audible.replace_events(i, track.into())?;
+    }
+    Ok(audible)
+}
+
+/// whether `path` names a project rather than a midi file
+pub fn is_project_path(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case(PROJECT_EXTENSION))
+}
+
+pub fn open(path: impl AsRef<Path>) -> Result<(MidiWorkspace, ProjectState)> {
+    let path = path.as_ref();
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut bytes = Vec::new();
+    archive.by_name(SEQUENCE_ENTRY)
+        .map_err(|_| Error::Project(format!("{} is missing", SEQUENCE_ENTRY)))?
+        .read_to_end(&mut bytes)?;
+    let mut ws = MidiWorkspace::from_smf(reader::read_strict(&bytes)?)?;
+    ws.set_origin(Some(path.to_path_buf()), false);
+
+    let state = match archive.by_name(STATE_ENTRY) {
+        Ok(entry) => serde_json::from_reader(entry)?,
+        Err(_) => {
+            warn!("{}: {} is missing, using the defaults", path.display(), STATE_ENTRY);
+            ProjectState::default()
+        }
+    };
+    Ok((ws, state))
+}
+
+/// saves the workspace and `state` as a project at `path` (see `save::write_atomic`),
+/// which becomes the file the workspace belongs to.
+pub fn save(path: impl AsRef<Path>, ws: &mut MidiWorkspace, state: &ProjectState, backup: bool) -> Result<()> {
+    let path = path.as_ref();
+    let mut state = state.clone();
+    state.version = PROJECT_VERSION;
+    // tracks may have been merged or split since the settings were made
+    state.tracks.resize(ws.track_count(), TrackSettings::default());
+
+    save::write_atomic(path, backup, |writer| {
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        let mut archive = zip::ZipWriter::new(writer);
+        archive.start_file(SEQUENCE_ENTRY, options)?;
+        ws.write_all(&mut archive)?;
+        archive.start_file(STATE_ENTRY, options)?;
+        serde_json::to_writer_pretty(&mut archive, &state)?;
+        archive.finish()?.flush()?;
+        Ok(())
+    })?;
+    ws.set_origin(Some(path.to_path_buf()), false);
+    Ok(())
+}
+
+#[test]
+fn project_keeps_state_and_unknown_fields() {
+    let path = std::env::temp_dir().join(format!("midie-project-{}.midie", std::process::id()));
+    let mut ws = MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    let mut state = ProjectState::default();
+    state.editor.current_track = 1;
+    state.editor.beat_width = 120.0;
+    state.tracks = vec![TrackSettings::default(), TrackSettings { color: Some(crate::settings::Color(0xff, 0x80, 0x00)), mute: true, ..Default::default() }];
+    state.unknown.insert(String::from("from_the_future"), serde_json::json!({ "answer": 42 }));
+
+    save(&path, &mut ws, &state, false).unwrap();
+    assert!(!ws.is_modified());
+    let (opened, opened_state) = open(&path).unwrap();
+    assert_eq!(opened_state, state);
+    assert_eq!(opened.path(), Some(path.as_path()));
+    assert_eq!(opened.track_count(), ws.track_count());
+    std::fs::remove_file(&path).unwrap();
+
+    // an older project without the newer fields
+    let old: ProjectState = serde_json::from_str(r#"{ "version": 1, "editor": { "current_track": 2 } }"#).unwrap();
+    assert_eq!(old.editor.current_track, 2);
+    assert_eq!(old.editor.beat_width, crate::editor::DEFAULT_BEAT_WIDTH);
+    let newer: ProjectState = serde_json::from_str(r#"{ "version": 7 }"#).unwrap();
+    assert!(newer.is_newer());
+}
+
+#[test]
+fn track_settings_follow_the_tracks() {
+    let mut ws = MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    let mut state = ProjectState::default();
+    let count = ws.track_count();
+    state.track_mut(1).mute = true;
+    state.track_mut(1).port = Some(String::from("Synth"));
+    assert!(!state.is_audible(1));
+    assert!(state.is_audible(0));
+
+    let split = split_track_by_channel(&mut ws, &mut state, 1).unwrap();
+    assert_eq!(state.tracks.len(), ws.track_count());
+    assert!((1..1 + split).all(|i| state.track(i).mute));
+    merge_tracks(&mut ws, &mut state, &(1..1 + split).collect::<Vec<usize>>()).unwrap();
+    assert_eq!(ws.track_count(), count);
+    assert_eq!(state.tracks.len(), count);
+    assert!(state.track(1).mute);
+
+    // muted tracks are silent, and a soloed track silences the rest
+    let has_midi = |ws: &MidiWorkspace, track: usize| ws.events(track).unwrap().iter().any(|e| matches!(e.event, rimd::Event::Midi(_)));
+    assert!(has_midi(&ws, 1));
+    assert!(!has_midi(&audible(&ws, &state).unwrap(), 1));
+    assert_eq!(state.output_port(count), None);
+    state.track_mut(1).solo = true;
+    state.track_mut(1).mute = false;
+    let soloed = audible(&ws, &state).unwrap();
+    assert!(has_midi(&soloed, 1));
+    assert!((0..count).filter(|i| *i != 1).all(|i| !has_midi(&soloed, i)));
+    assert_eq!(state.output_port(count), Some("Synth"));
+}
@@ -0,0 +1,78 @@
+//! The list of recently opened files, kept in the config directory.
+
+use crate::error::Result;
+use crate::smf::save;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// how many files the list holds
+pub const MAX_RECENT_FILES: usize = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecentFiles {
+    /// most recent first
+    files: Vec<PathBuf>,
+}
+
+impl RecentFiles {
+    /// `recent.json` in the config directory
+    pub fn default_path() -> Option<PathBuf> {
+        crate::dirs::config_dir().map(|dir| dir.join("recent.json"))
+    }
+
+    /// an empty list if there is none yet, or it cannot be read
+    pub fn load(path: &Path) -> Self {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Self::default()
+        };
+        serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!("ignored the recent files in {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        save::write_atomic(path, false, |writer| Ok(serde_json::to_writer_pretty(writer, self)?))
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// moves `path` to the top of the list, dropping the oldest file if it is full.
+    pub fn add(&mut self, path: &Path) {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.files.retain(|p| *p != path);
+        self.files.insert(0, path);
+        self.files.truncate(MAX_RECENT_FILES);
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        self.files.retain(|p| p != path);
+    }
+}
+
+#[test]
+fn recent_files_are_ordered_and_kept() {
+    let dir = std::env::temp_dir().join(format!("midie-recent-{}", std::process::id()));
+    let mut recent = RecentFiles::default();
+    for i in 0..MAX_RECENT_FILES + 2 {
+        recent.add(Path::new(&format!("/nowhere/{}.mid", i)));
+    }
+    recent.add(Path::new("/nowhere/5.mid"));
+    assert_eq!(recent.files().len(), MAX_RECENT_FILES);
+    assert_eq!(recent.files()[0], Path::new("/nowhere/5.mid"));
+    assert_eq!(recent.files()[1], Path::new("/nowhere/11.mid"));
+    assert_eq!(recent.files().iter().filter(|p| p.ends_with("5.mid")).count(), 1);
+
+    let path = dir.join("recent.json");
+    recent.save(&path).unwrap();
+    assert_eq!(RecentFiles::load(&path), recent);
+    assert_eq!(RecentFiles::load(&dir.join("missing.json")), RecentFiles::default());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,244 @@
+//! Autosaving open workspaces, so that edits survive a crash.
+//!
+//! Every running editor owns a session directory under the recovery directory,
+//! named after its process id, and holds an exclusive lock on the `lock` file in it.
+//! While there are unsaved edits the directory holds the workspace (`workspace.mid`) and what
+//! a project keeps besides it (`session.json`). A clean exit removes the directory, so one
+//! whose lock nobody holds any more was left behind by a crash.
+
+use crate::error::Result;
+use crate::project::ProjectState;
+use crate::smf::{save, MidiWorkspace};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// seconds between autosaves
+pub const AUTOSAVE_INTERVAL: u32 = 30;
+
+const SESSION_VERSION: u32 = 1;
+const WORKSPACE_FILE: &str = "workspace.mid";
+const SESSION_FILE: &str = "session.json";
+const LOCK_FILE: &str = "lock";
+
+/// `$XDG_DATA_HOME/midie/recovery`, or `~/.local/share/midie/recovery`
+pub fn recovery_dir() -> Option<PathBuf> {
+    crate::dirs::data_dir().map(|dir| dir.join("recovery"))
+}
+
+/// Stored next to the autosaved workspace.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub version: u32,
+    pub pid: u32,
+    /// seconds since the unix epoch
+    pub saved_at: u64,
+    /// the file the workspace was opened from or saved to
+    pub path: Option<PathBuf>,
+    /// the editor state and track settings
+    pub project: ProjectState,
+}
+
+impl SessionInfo {
+    /// "song.mid", or "Untitled" for a workspace which was never saved
+    pub fn name(&self) -> String {
+        self.path.as_ref()
+            .and_then(|p| p.file_name())
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| String::from("Untitled"))
+    }
+
+    pub fn age(&self) -> std::time::Duration {
+        let saved_at = UNIX_EPOCH + std::time::Duration::from_secs(self.saved_at);
+        SystemTime::now().duration_since(saved_at).unwrap_or_default()
+    }
+}
+
+/// The session of this process. It counts as running until it is closed or dropped.
+#[derive(Debug)]
+pub struct Session {
+    dir: PathBuf,
+    _lock: fs::File,
+}
+
+impl Session {
+    pub fn create(recovery_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(recovery_dir)?;
+        // never one a crashed editor with the same process id left behind
+        let mut n = 0;
+        let dir = loop {
+            let dir = recovery_dir.join(format!("{}-{}", std::process::id(), n));
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e.into())
+            }
+        };
+        let lock = fs::File::create(dir.join(LOCK_FILE))?;
+        lock.try_lock().map_err(std::io::Error::from)?;
+        Ok(Session { dir, _lock: lock })
+    }
+
+    /// saves the workspace if it has unsaved edits; otherwise there is nothing to recover
+    /// and what was autosaved before is removed.
+    pub fn autosave(&self, ws: &MidiWorkspace, state: &ProjectState) -> Result<()> {
+        if !ws.is_modified() {
+            return remove_if_exists(&self.dir.join(SESSION_FILE));
+        }
+        save::write_atomic(&self.dir.join(WORKSPACE_FILE), false, |writer| ws.write_all(writer))?;
+        let info = SessionInfo {
+            version: SESSION_VERSION,
+            pid: std::process::id(),
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            path: ws.path().map(Path::to_path_buf),
+            project: state.clone(),
+        };
+        // written last: a session without it is not offered for recovery
+        save::write_atomic(&self.dir.join(SESSION_FILE), false, |writer| Ok(serde_json::to_writer_pretty(writer, &info)?))
+    }
+
+    /// removes the session on a clean exit.
+    pub fn close(self) -> Result<()> {
+        // nothing to offer for recovery even if it is unlocked before it is gone
+        remove_if_exists(&self.dir.join(SESSION_FILE))?;
+        let dir = self.dir;
+        drop(self._lock);
+        Ok(fs::remove_dir_all(&dir)?)
+    }
+}
+
+/// A session left behind by an editor which did not exit cleanly.
+/// Its lock is held until it is dropped, so no other editor offers it meanwhile.
+#[derive(Debug)]
+pub struct Recovered {
+    dir: PathBuf,
+    _lock: fs::File,
+    pub info: SessionInfo,
+}
+
+impl Recovered {
+    /// the autosaved workspace, marked as modified and belonging to its original file.
+    pub fn restore(&self) -> Result<(MidiWorkspace, ProjectState)> {
+        let mut ws = MidiWorkspace::from_smf_file(self.dir.join(WORKSPACE_FILE))?;
+        ws.set_origin(self.info.path.clone(), true);
+        Ok((ws, self.info.project.clone()))
+    }
+
+    pub fn discard(self) -> Result<()> {
+        Ok(fs::remove_dir_all(&self.dir)?)
+    }
+}
+
+/// sessions nobody holds the lock of, newest first.
+/// Leftover sessions without anything to recover are removed.
+pub fn crashed_sessions(recovery_dir: &Path) -> Vec<Recovered> {
+    let entries = match fs::read_dir(recovery_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut sessions = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let dir = entry.path();
+        // a session which is being created has no lock file yet
+        let lock = match fs::File::open(dir.join(LOCK_FILE)) {
+            Ok(lock) => lock,
+            Err(_) => continue
+        };
+        match lock.try_lock() {
+            Ok(()) => {},
+            Err(fs::TryLockError::WouldBlock) => continue,
+            Err(fs::TryLockError::Error(e)) => {
+                warn!("skipped recovery session {}: {}", dir.display(), e);
+                continue;
+            }
+        }
+        match read_info(&dir) {
+            Ok(Some(info)) => sessions.push(Recovered { dir, _lock: lock, info }),
+            Ok(None) => {
+                if let Err(e) = fs::remove_dir_all(&dir) {
+                    warn!("failed to remove {}: {}", dir.display(), e);
+                }
+            },
+            Err(e) => warn!("skipped recovery session {}: {}", dir.display(), e)
+        }
+    }
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.info.saved_at));
+    sessions
+}
+
+fn read_info(dir: &Path) -> Result<Option<SessionInfo>> {
+    let path = dir.join(SESSION_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let info: SessionInfo = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(Some(info))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(())
+    }
+}
+
+#[test]
+fn crashed_session_is_restored() {
+    let root = std::env::temp_dir().join(format!("midie-recovery-{}", std::process::id()));
+    let mut ws = MidiWorkspace::empty();
+    let mut track = ws.events_abs_tick(1).unwrap();
+    track.append_notes(vec![(0, 60, 100, 0), (480, 60, 0, 0)]);
+    track.clean();
+    ws.replace_events(1, track.into()).unwrap();
+    let mut state = ProjectState {
+        editor: crate::editor::EditorState { current_track: 1, scroll_x: 120.0, selected_events: vec![2], ..Default::default() },
+        ..Default::default()
+    };
+    state.track_mut(1).mute = true;
+
+    let session = Session::create(&root).unwrap();
+    session.autosave(&ws, &state).unwrap();
+    // our own session is still running
+    assert!(crashed_sessions(&root).is_empty());
+
+    // as if the process had crashed
+    let crashed = session.dir.clone();
+    drop(session);
+    let mut sessions = crashed_sessions(&root);
+    assert_eq!(sessions.len(), 1);
+    let (restored, restored_state) = sessions[0].restore().unwrap();
+    assert!(restored.is_modified());
+    let bytes = |ws: &MidiWorkspace| {
+        let mut bytes = Vec::new();
+        ws.write_all(&mut bytes).unwrap();
+        bytes
+    };
+    assert_eq!(bytes(&restored), bytes(&ws));
+    assert_eq!(restored_state, state);
+
+    sessions.remove(0).discard().unwrap();
+    assert!(!crashed.exists());
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn running_sessions_are_not_offered() {
+    let root = std::env::temp_dir().join(format!("midie-recovery-running-{}", std::process::id()));
+    let mut ws = MidiWorkspace::empty();
+    ws.transpose(None, 1).unwrap();
+    assert!(ws.is_modified());
+
+    let first = Session::create(&root).unwrap();
+    first.autosave(&ws, &ProjectState::default()).unwrap();
+    // another editor starting while the first one is still open
+    let second = Session::create(&root).unwrap();
+    assert_ne!(first.dir, second.dir);
+    assert!(crashed_sessions(&root).is_empty());
+    assert!(first.dir.join(SESSION_FILE).exists());
+
+    second.close().unwrap();
+    first.close().unwrap();
+    assert!(crashed_sessions(&root).is_empty());
+    fs::remove_dir_all(&root).unwrap();
+}
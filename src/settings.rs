@@ -0,0 +1,173 @@
+//! User settings, kept in the config directory and loaded at startup.
+
+use crate::error::Result;
+use crate::smf::play::{MidiMessage, VIRTUAL_PORT_NAME};
+use crate::smf::save;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// resolutions new workspaces can have, as the settings dialog offers them
+pub const RESOLUTION_RANGE: RangeInclusive<u16> = 24..=960;
+
+/// Where the editor plays to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "name", rename_all = "snake_case")]
+pub enum Output {
+    /// nothing is played until an output is chosen
+    #[default]
+    None,
+    /// a midi output port, by name; port numbers change as devices come and go
+    Port(String),
+    /// a port of our own which other applications can connect to
+    Virtual,
+    BuiltinSynth,
+}
+
+impl Output {
+    /// the message which opens this output. A port which is not connected is played
+    /// through the built-in synth until it is.
+    pub fn message(&self) -> Option<MidiMessage> {
+        match self {
+            Output::None => None,
+            Output::Port(name) => Some(MidiMessage::ConnectPort(name.clone())),
+            Output::Virtual => Some(MidiMessage::VirtualPort(String::from(VIRTUAL_PORT_NAME))),
+            Output::BuiltinSynth => Some(MidiMessage::BuiltinSynth),
+        }
+    }
+}
+
+/// A color, written as "#rrggbb".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub u8, pub u8, pub u8);
+
+impl Color {
+    /// components from 0.0 to 1.0, as cairo takes them
+    pub fn rgb(self) -> (f64, f64, f64) {
+        (self.0 as f64 / 255.0, self.1 as f64 / 255.0, self.2 as f64 / 255.0)
+    }
+
+    pub fn from_rgb(r: f64, g: f64, b: f64) -> Self {
+        let c = |v: f64| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Color(c(r), c(g), c(b))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, String> {
+        let hex = s.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.is_ascii())
+            .ok_or_else(|| format!("not a color: {}", s))?;
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("not a color: {}", s));
+        Ok(Color(component(0)?, component(2)?, component(4)?))
+    }
+}
+
+impl From<Color> for String {
+    fn from(c: Color) -> String {
+        format!("#{:02x}{:02x}{:02x}", c.0, c.1, c.2)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub output: Output,
+    /// grid new notes snap to, in notes per quarter note
+    pub quantize_per_beat: u16,
+    /// horizontal zoom: width of a beat in pixels
+    pub beat_width: f64,
+    pub white_key_color: Color,
+    pub black_key_color: Color,
+    pub note_color: Color,
+    /// ticks per quarter note of new workspaces
+    pub resolution: u16,
+    /// velocity of notes drawn in the piano roll
+    pub note_velocity: u8,
+    /// keep the previous version of a file as `.bak` when saving over it
+    pub backup: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            output: Output::default(),
+            quantize_per_beat: 4,
+            beat_width: crate::editor::DEFAULT_BEAT_WIDTH,
+            white_key_color: Color(0xff, 0xff, 0xff),
+            black_key_color: Color(0x00, 0x00, 0x00),
+            note_color: Color(0xff, 0x00, 0x00),
+            resolution: crate::smf::DEFAULT_RESOLUTION,
+            note_velocity: 100,
+            backup: true,
+        }
+    }
+}
+
+impl Settings {
+    /// `settings.json` in the config directory
+    pub fn default_path() -> Option<PathBuf> {
+        crate::dirs::config_dir().map(|dir| dir.join("settings.json"))
+    }
+
+    /// the defaults if there are no settings yet, or they cannot be read.
+    /// A resolution out of `RESOLUTION_RANGE` is clamped into it.
+    pub fn load(path: &Path) -> Self {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Self::default()
+        };
+        let mut settings: Self = serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!("ignored the settings in {}: {}", path.display(), e);
+            Self::default()
+        });
+        if !RESOLUTION_RANGE.contains(&settings.resolution) {
+            let resolution = settings.resolution.clamp(*RESOLUTION_RANGE.start(), *RESOLUTION_RANGE.end());
+            warn!("resolution {} in {} is out of range, using {}", settings.resolution, path.display(), resolution);
+            settings.resolution = resolution;
+        }
+        settings
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        save::write_atomic(path, false, |writer| Ok(serde_json::to_writer_pretty(writer, self)?))
+    }
+
+    /// the quantize grid in ticks, for a workspace of `resolution` ticks per quarter note
+    pub fn quantize(&self, resolution: u16) -> u64 {
+        (resolution as u64 / self.quantize_per_beat.max(1) as u64).max(1)
+    }
+}
+
+#[test]
+fn settings_are_kept() {
+    let path = std::env::temp_dir().join(format!("midie-settings-{}", std::process::id())).join("settings.json");
+    let mut settings = Settings::load(&path);
+    assert_eq!(settings, Settings::default());
+    settings.output = Output::Port(String::from("FLUID Synth (1234):Synth input port (1234:0) 128:0"));
+    settings.note_color = Color(0x12, 0xab, 0xff);
+    settings.resolution = 96;
+    settings.save(&path).unwrap();
+    assert_eq!(Settings::load(&path), settings);
+    let json = std::fs::read_to_string(&path).unwrap();
+    assert!(json.contains("\"#12abff\""), "{}", json);
+
+    // hand-edited resolutions which are no division of a standard midi file
+    for (resolution, loaded) in [(0, 24), (40000, 960)] {
+        std::fs::write(&path, format!(r#"{{ "resolution": {} }}"#, resolution)).unwrap();
+        assert_eq!(Settings::load(&path).resolution, loaded);
+    }
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+    // settings from an older version, and ones which cannot be read
+    let old: Settings = serde_json::from_str(r#"{ "note_velocity": 64 }"#).unwrap();
+    assert_eq!(old.note_velocity, 64);
+    assert_eq!(old.quantize(480), 120);
+    assert!(serde_json::from_str::<Settings>(r#"{ "note_color": "red" }"#).is_err());
+}
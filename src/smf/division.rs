@@ -0,0 +1,175 @@
+//! The time division of a midi file: ticks per quarter note, or SMPTE frames.
+
+use super::TempoInfo;
+use crate::error::{Error, Result};
+
+/// SMPTE frame rates a midi file can be based on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpteFps {
+    Fps24,
+    Fps25,
+    /// 30 fps drop frame, which runs at 29.97 frames per second
+    Fps29_97,
+    Fps30,
+}
+
+impl SmpteFps {
+    /// `fps` is the (positive) frame rate stored in the header: 24, 25, 29 or 30.
+    pub fn from_raw(fps: u8) -> Option<Self> {
+        match fps {
+            24 => Some(SmpteFps::Fps24),
+            25 => Some(SmpteFps::Fps25),
+            29 => Some(SmpteFps::Fps29_97),
+            30 => Some(SmpteFps::Fps30),
+            _ => None
+        }
+    }
+
+    pub fn to_raw(self) -> u8 {
+        match self {
+            SmpteFps::Fps24 => 24,
+            SmpteFps::Fps25 => 25,
+            SmpteFps::Fps29_97 => 29,
+            SmpteFps::Fps30 => 30,
+        }
+    }
+
+    /// frames per second the timecode counts, 30 for 29.97
+    pub fn nominal(self) -> u8 {
+        match self {
+            SmpteFps::Fps29_97 => 30,
+            fps => fps.to_raw()
+        }
+    }
+
+    pub fn frames_per_second(self) -> f64 {
+        match self {
+            SmpteFps::Fps29_97 => 30000.0 / 1001.0,
+            fps => fps.to_raw() as f64
+        }
+    }
+
+    /// hh:mm:ss:ff of the frame, counted from 0.
+    /// 29.97 uses drop frame numbering, written hh:mm:ss;ff.
+    pub fn timecode(self, frame: u64) -> String {
+        let nominal = self.nominal() as u64;
+        let mut frame = frame;
+        if self == SmpteFps::Fps29_97 {
+            // frame numbers 0 and 1 are skipped every minute, except every tenth minute
+            const FRAMES_PER_10_MINUTES: u64 = 17982;
+            const FRAMES_PER_MINUTE: u64 = 1798;
+            let tens = frame / FRAMES_PER_10_MINUTES;
+            let rest = frame % FRAMES_PER_10_MINUTES;
+            frame += 18 * tens;
+            if rest >= 2 {
+                frame += 2 * ((rest - 2) / FRAMES_PER_MINUTE);
+            }
+        }
+        let separator = if self == SmpteFps::Fps29_97 { ';' } else { ':' };
+        format!("{:02}:{:02}:{:02}{}{:02}",
+            frame / (nominal * 3600),
+            frame / (nominal * 60) % 60,
+            frame / nominal % 60,
+            separator,
+            frame % nominal)
+    }
+}
+
+/// How ticks of a midi file relate to time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Division {
+    /// ticks per quarter note; real time depends on the tempo
+    TicksPerQuarter(u16),
+    /// frame rate and ticks per frame; tempo changes do not affect timing
+    Smpte(SmpteFps, u8),
+}
+
+impl Division {
+    /// decodes the division field of the MThd header.
+    pub fn from_raw(raw: i16) -> Result<Self> {
+        if raw >= 0 {
+            return Ok(Division::TicksPerQuarter(raw as u16));
+        }
+        let fps = (raw >> 8) as i8;
+        let ticks_per_frame = (raw & 0xFF) as u8;
+        SmpteFps::from_raw(fps.unsigned_abs())
+            .map(|fps| Division::Smpte(fps, ticks_per_frame))
+            .ok_or_else(|| Error::Parse(format!("unsupported SMPTE frame rate {}", -(fps as i16))))
+    }
+
+    pub fn to_raw(self) -> i16 {
+        match self {
+            Division::TicksPerQuarter(ppq) => ppq as i16,
+            Division::Smpte(fps, ticks_per_frame) => ((-(fps.to_raw() as i16)) << 8) | ticks_per_frame as i16,
+        }
+    }
+
+    pub fn is_smpte(self) -> bool {
+        matches!(self, Division::Smpte(_, _))
+    }
+
+    /// ticks which make up a beat on screen. SMPTE files have no beats,
+    /// so a second (of nominal frames) is used instead.
+    pub fn ticks_per_beat(self) -> u16 {
+        match self {
+            Division::TicksPerQuarter(ppq) => ppq.max(1),
+            Division::Smpte(fps, ticks_per_frame) => (fps.nominal() as u16 * ticks_per_frame as u16).max(1),
+        }
+    }
+
+    /// seconds elapsed from tick 0 to `abs_tick`. `tempo` only matters for ticks per quarter.
+    pub fn seconds_at(self, abs_tick: u64, tempo: &TempoInfo) -> f64 {
+        match self {
+            Division::TicksPerQuarter(ppq) => tempo.seconds_at(abs_tick, ppq),
+            Division::Smpte(fps, ticks_per_frame) =>
+                abs_tick as f64 / (fps.frames_per_second() * ticks_per_frame.max(1) as f64),
+        }
+    }
+
+    /// inverse of `seconds_at`, not rounded.
+    pub fn ticks_at(self, seconds: f64, tempo: &TempoInfo) -> f64 {
+        match self {
+            Division::TicksPerQuarter(ppq) => tempo.ticks_at(seconds, ppq),
+            Division::Smpte(fps, ticks_per_frame) =>
+                seconds * fps.frames_per_second() * ticks_per_frame.max(1) as f64,
+        }
+    }
+
+    /// timecode of the tick for SMPTE divisions.
+    pub fn timecode(self, abs_tick: u64) -> Option<String> {
+        match self {
+            Division::TicksPerQuarter(_) => None,
+            Division::Smpte(fps, ticks_per_frame) => Some(fps.timecode(abs_tick / ticks_per_frame.max(1) as u64)),
+        }
+    }
+}
+
+impl std::fmt::Display for Division {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Division::TicksPerQuarter(ppq) => write!(f, "{} ticks per quarter note", ppq),
+            Division::Smpte(SmpteFps::Fps29_97, ticks_per_frame) => write!(f, "29.97 fps, {} ticks per frame", ticks_per_frame),
+            Division::Smpte(fps, ticks_per_frame) => write!(f, "{} fps, {} ticks per frame", fps.to_raw(), ticks_per_frame),
+        }
+    }
+}
+
+#[test]
+fn division_raw_round_trip() {
+    for raw in &[480, 96, -(25 << 8) | 40, -(29 << 8) | 80, -(24 << 8) | 4, -(30 << 8) | 100] {
+        assert_eq!(Division::from_raw(*raw).unwrap().to_raw(), *raw);
+    }
+    assert_eq!(Division::from_raw(-(25 << 8) | 40).unwrap(), Division::Smpte(SmpteFps::Fps25, 40));
+    assert!(Division::from_raw(-(23 << 8) | 40).is_err());
+}
+
+#[test]
+fn drop_frame_timecode() {
+    let fps = SmpteFps::Fps29_97;
+    assert_eq!(fps.timecode(1799), "00:00:59;29");
+    // 00:01:00;00 and ;01 do not exist
+    assert_eq!(fps.timecode(1800), "00:01:00;02");
+    // but every tenth minute they do
+    assert_eq!(fps.timecode(17982), "00:10:00;00");
+    assert_eq!(SmpteFps::Fps25.timecode(25 * 3661 + 3), "01:01:01:03");
+}
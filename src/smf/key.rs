@@ -0,0 +1,191 @@
+//! Key signatures, and the scales notes are snapped to when editing in a key.
+
+use std::fmt;
+use super::KeySignatureInfo;
+
+/// pitch classes of a major scale, from its tonic
+const MAJOR_SCALE: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const SHARP_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+const FLAT_NAMES: [&str; 12] = ["C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B"];
+
+/// The data of a key signature meta event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KeySignature {
+    /// sharps if positive, flats if negative, from -7 to 7
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+impl KeySignature {
+    /// every key, major and minor, from seven flats to seven sharps
+    pub fn all() -> impl Iterator<Item = KeySignature> {
+        (-7..=7).flat_map(|sharps| vec![KeySignature::new(sharps, false), KeySignature::new(sharps, true)])
+    }
+
+    pub fn new(sharps: i8, minor: bool) -> Self {
+        KeySignature { sharps, minor }
+    }
+
+    /// the key signature of the data of a meta event, which has to be two bytes
+    pub fn from_meta_data(data: &[u8]) -> Option<Self> {
+        match *data {
+            [sf, mi] if (-7..=7).contains(&(sf as i8)) && mi <= 1 => Some(KeySignature::new(sf as i8, mi == 1)),
+            _ => None
+        }
+    }
+
+    pub fn meta_event(&self) -> rimd::MetaEvent {
+        rimd::MetaEvent::key_signature(self.sharps as u8, self.minor as u8)
+    }
+
+    /// pitch class of the major key with the same sharps or flats
+    fn major_tonic(&self) -> u8 {
+        (self.sharps as i32 * 7).rem_euclid(12) as u8
+    }
+
+    /// pitch class of the tonic, 0 for C
+    pub fn tonic(&self) -> u8 {
+        if self.minor {
+            (self.major_tonic() + 9) % 12
+        } else {
+            self.major_tonic()
+        }
+    }
+
+    /// name of the tonic, spelled with sharps or flats like the key signature
+    pub fn tonic_name(&self) -> &'static str {
+        match (self.sharps, self.minor) {
+            (-7, false) => "Cb",
+            (sharps, _) if sharps < 0 => FLAT_NAMES[self.tonic() as usize],
+            _ => SHARP_NAMES[self.tonic() as usize],
+        }
+    }
+
+    /// e.g. "G" or "Em"
+    pub fn short_name(&self) -> String {
+        format!("{}{}", self.tonic_name(), if self.minor { "m" } else { "" })
+    }
+
+    /// whether `note` is in the scale of the key. Minor keys use the natural minor scale.
+    pub fn contains(&self, note: u8) -> bool {
+        MAJOR_SCALE.contains(&((note as i32 - self.major_tonic() as i32).rem_euclid(12) as u8))
+    }
+
+    /// the nearest note of the scale. A note between two of them goes up if `direction` is positive, down otherwise.
+    pub fn snap(&self, note: u8, direction: i32) -> u8 {
+        if self.contains(note) {
+            note
+        } else if (direction > 0 && note < 127) || note == 0 {
+            note + 1
+        } else {
+            note - 1
+        }
+    }
+
+    /// moves `note` by `steps` notes of the scale, staying in the midi note range.
+    /// A note outside of the scale moves to the next one of it in the first step.
+    pub fn transpose(&self, note: u8, steps: i32) -> u8 {
+        let mut note = note as i32;
+        for _ in 0..steps.abs() {
+            let mut next = note + steps.signum();
+            while (0..=127).contains(&next) && !self.contains(next as u8) {
+                next += steps.signum();
+            }
+            if !(0..=127).contains(&next) {
+                break;
+            }
+            note = next;
+        }
+        note as u8
+    }
+}
+
+impl fmt::Display for KeySignature {
+    /// e.g. "G major" or "E minor"
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.tonic_name(), if self.minor { "minor" } else { "major" })
+    }
+}
+
+impl KeySignatureInfo {
+    /// the key at `abs_tick`, C major before the first key signature
+    pub fn key_at(&self, abs_tick: u64) -> KeySignature {
+        self.changes.iter()
+            .filter(|(abs, _)| *abs <= abs_tick)
+            .max_by_key(|(abs, _)| *abs)
+            .map(|(_, key)| *key)
+            .unwrap_or_default()
+    }
+
+    /// the change at `abs_tick`, if there is one
+    pub fn change_at(&self, abs_tick: u64) -> Option<KeySignature> {
+        self.changes.iter().find(|(abs, _)| *abs == abs_tick).map(|(_, key)| *key)
+    }
+
+    /// sets the key from `abs_tick` on, replacing a change which is already there.
+    pub fn set(&mut self, abs_tick: u64, key: KeySignature) {
+        self.changes.retain(|(abs, _)| *abs != abs_tick);
+        self.append((abs_tick, key));
+    }
+
+    pub fn remove(&mut self, abs_tick: u64) {
+        self.changes.retain(|(abs, _)| *abs != abs_tick);
+    }
+}
+
+#[test]
+fn keys_have_names_and_scales() {
+    let names: Vec<String> = [(0, false), (0, true), (1, false), (-3, true), (6, true), (-6, false), (-7, false), (7, false)].iter()
+        .map(|&(sharps, minor)| KeySignature::new(sharps, minor).to_string())
+        .collect();
+    assert_eq!(names, vec!["C major", "A minor", "G major", "C minor", "D# minor", "Gb major", "Cb major", "C# major"]);
+    assert_eq!(KeySignature::all().count(), 30);
+
+    let d_major = KeySignature::new(2, false);
+    let scale: Vec<u8> = (60..72).filter(|&n| d_major.contains(n)).collect();
+    assert_eq!(scale, vec![61, 62, 64, 66, 67, 69, 71]);
+    assert_eq!(d_major.snap(65, 0), 64);
+    assert_eq!(d_major.snap(65, 1), 66);
+    assert_eq!(d_major.snap(62, 1), 62);
+
+    let c_major = KeySignature::default();
+    assert_eq!(c_major.transpose(60, 2), 64);
+    assert_eq!(c_major.transpose(64, 1), 65);
+    assert_eq!(c_major.transpose(60, -1), 59);
+    // a note outside of the scale moves into it first
+    assert_eq!(c_major.transpose(61, 1), 62);
+    assert_eq!(c_major.transpose(61, -1), 60);
+    assert_eq!(c_major.transpose(127, 3), 127);
+
+    assert_eq!(KeySignature::from_meta_data(&[0xfd, 1]), Some(KeySignature::new(-3, true)));
+    assert_eq!(KeySignature::from_meta_data(&[8, 0]), None);
+    assert_eq!(KeySignature::new(-3, true).meta_event().data, vec![0xfd, 1]);
+}
+
+#[test]
+fn notes_are_transposed_in_the_key_they_are_in() {
+    let mut ws = super::MidiWorkspace::empty();
+    let mut keys = ws.create_key_signature_info(0).unwrap();
+    assert!(keys.changes.is_empty());
+    keys.set(960, KeySignature::new(-1, true));
+    ws.set_key_signature_info(&keys).unwrap();
+    assert_eq!(ws.create_key_signature_info(0).unwrap().changes, vec![(960, KeySignature::new(-1, true))]);
+
+    let mut track = ws.events_abs_tick(1).unwrap();
+    // E in C major, A held from C major into D minor, and B flat in D minor
+    track.append_notes(vec![(0, 64, 100, 0), (480, 64, 0, 0), (480, 69, 100, 0), (1440, 69, 0, 0), (1440, 70, 100, 0), (1920, 70, 0, 0)]);
+    track.clean();
+    ws.replace_events(1, track.into()).unwrap();
+    ws.transpose_in_key(Some(1), 1).unwrap();
+
+    let mut notes: Vec<(u64, u8, bool)> = ws.events_abs_tick(1).unwrap().events().iter()
+        .filter_map(|ate| match &ate.track_event.event {
+            rimd::Event::Midi(msg) => super::util::note_on(msg).map(|(_, note, _)| (ate.abs_time, note, true))
+                .or_else(|| super::util::note_off(msg).map(|(_, note, _)| (ate.abs_time, note, false))),
+            _ => None
+        })
+        .collect();
+    notes.sort();
+    // the held note ends on the note it started on
+    assert_eq!(notes, vec![(0, 65, true), (480, 65, false), (480, 71, true), (1440, 71, false), (1440, 72, true), (1920, 72, false)]);
+}
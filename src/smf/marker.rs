@@ -0,0 +1,185 @@
+//! Markers, cue points and text events of the conductor track, and rehearsal letters kept as markers.
+
+use super::MarkerInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerKind {
+    /// a section of the song, e.g. "Verse" or a rehearsal letter
+    Marker,
+    /// something happening at that point, e.g. a sound effect
+    CuePoint,
+    Text,
+}
+
+impl MarkerKind {
+    pub const ALL: [MarkerKind; 3] = [MarkerKind::Marker, MarkerKind::CuePoint, MarkerKind::Text];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MarkerKind::Marker => "Marker",
+            MarkerKind::CuePoint => "Cue point",
+            MarkerKind::Text => "Text",
+        }
+    }
+
+    pub fn command(self) -> rimd::MetaCommand {
+        match self {
+            MarkerKind::Marker => rimd::MetaCommand::MarkerText,
+            MarkerKind::CuePoint => rimd::MetaCommand::CuePoint,
+            MarkerKind::Text => rimd::MetaCommand::TextEvent,
+        }
+    }
+
+    pub fn from_command(command: rimd::MetaCommand) -> Option<Self> {
+        MarkerKind::ALL.iter().copied().find(|kind| kind.command() == command)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    pub abs_tick: u64,
+    pub kind: MarkerKind,
+    pub text: String,
+}
+
+impl Marker {
+    pub fn new<S: Into<String>>(abs_tick: u64, kind: MarkerKind, text: S) -> Self {
+        Marker { abs_tick, kind, text: text.into() }
+    }
+
+    /// the marker of a meta event, if it is one
+    pub fn from_meta(abs_tick: u64, meta: &rimd::MetaEvent) -> Option<Self> {
+        MarkerKind::from_command(meta.command).map(|kind| Marker::new(abs_tick, kind, String::from_utf8_lossy(&meta.data)))
+    }
+
+    pub fn meta_event(&self) -> rimd::MetaEvent {
+        match self.kind {
+            MarkerKind::Marker => rimd::MetaEvent::marker_text(self.text.clone()),
+            MarkerKind::CuePoint => rimd::MetaEvent::cue_point(self.text.clone()),
+            MarkerKind::Text => rimd::MetaEvent::text_event(self.text.clone()),
+        }
+    }
+
+    /// whether it is a marker named like a rehearsal letter, e.g. "C" or "AA"
+    pub fn is_rehearsal_letter(&self) -> bool {
+        self.kind == MarkerKind::Marker && (0..REHEARSAL_LETTERS * 2).any(|i| rehearsal_letter(i) == self.text)
+    }
+}
+
+/// letters used for rehearsal letters. I and O are left out, as they look like 1 and 0.
+const LETTERS: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ";
+const REHEARSAL_LETTERS: usize = 24;
+
+/// the rehearsal letter of the `index`th section: A to Z, then AA, BB and so on
+pub fn rehearsal_letter(index: usize) -> String {
+    let letter = LETTERS.chars().nth(index % REHEARSAL_LETTERS).expect("index is in range");
+    letter.to_string().repeat(index / REHEARSAL_LETTERS + 1)
+}
+
+impl MarkerInfo {
+    /// adds `marker` after the ones at the same tick. Returns its index.
+    pub fn add(&mut self, marker: Marker) -> usize {
+        let index = self.markers.iter().take_while(|m| m.abs_tick <= marker.abs_tick).count();
+        self.markers.insert(index, marker);
+        index
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Marker> {
+        if index < self.markers.len() {
+            Some(self.markers.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn rename(&mut self, index: usize, text: &str) {
+        if let Some(marker) = self.markers.get_mut(index) {
+            marker.text = String::from(text);
+        }
+    }
+
+    /// moves the marker at `index` to `abs_tick`. Returns its new index.
+    pub fn move_to(&mut self, index: usize, abs_tick: u64) -> Option<usize> {
+        let mut marker = self.remove(index)?;
+        marker.abs_tick = abs_tick;
+        Some(self.add(marker))
+    }
+
+    /// the first marker after `abs_tick`
+    pub fn next_after(&self, abs_tick: u64) -> Option<&Marker> {
+        self.markers.iter().find(|m| m.abs_tick > abs_tick)
+    }
+
+    /// the last marker before `abs_tick`
+    pub fn previous_before(&self, abs_tick: u64) -> Option<&Marker> {
+        self.markers.iter().rev().find(|m| m.abs_tick < abs_tick)
+    }
+
+    /// Adds a rehearsal letter at `abs_tick`, and letters the ones after it again so that
+    /// they stay in order. Returns its index.
+    pub fn add_rehearsal_letter(&mut self, abs_tick: u64) -> usize {
+        let index = self.add(Marker::new(abs_tick, MarkerKind::Marker, rehearsal_letter(0)));
+        self.reletter();
+        index
+    }
+
+    /// names the rehearsal letters A, B, C and so on, in the order they come in
+    pub fn reletter(&mut self) {
+        for (i, marker) in self.markers.iter_mut().filter(|m| m.is_rehearsal_letter()).enumerate() {
+            marker.text = rehearsal_letter(i);
+        }
+    }
+}
+
+#[test]
+fn rehearsal_letters_stay_in_order() {
+    assert_eq!((0..3).map(rehearsal_letter).collect::<Vec<_>>(), vec!["A", "B", "C"]);
+    assert_eq!(rehearsal_letter(8), "J");
+    assert_eq!(rehearsal_letter(23), "Z");
+    assert_eq!(rehearsal_letter(24), "AA");
+
+    let mut markers = MarkerInfo::new(vec![Marker::new(0, MarkerKind::Marker, "Intro")], false);
+    markers.add_rehearsal_letter(3840);
+    markers.add_rehearsal_letter(7680);
+    let index = markers.add_rehearsal_letter(1920);
+    assert_eq!(index, 1);
+    let texts: Vec<&str> = markers.markers.iter().map(|m| m.text.as_str()).collect();
+    assert_eq!(texts, vec!["Intro", "A", "B", "C"]);
+
+    markers.add(Marker::new(1920, MarkerKind::CuePoint, "B"));
+    // moved after the marker which is already there
+    assert_eq!(markers.move_to(3, 0), Some(1));
+    markers.reletter();
+    let texts: Vec<&str> = markers.markers.iter().map(|m| m.text.as_str()).collect();
+    assert_eq!(texts, vec!["Intro", "A", "B", "B", "C"]);
+    assert_eq!(markers.markers[3].kind, MarkerKind::CuePoint);
+    assert_eq!(markers.next_after(0).map(|m| m.abs_tick), Some(1920));
+    assert_eq!(markers.previous_before(1920).map(|m| m.text.as_str()), Some("A"));
+    assert_eq!(markers.previous_before(0), None);
+}
+
+#[test]
+fn markers_are_read_from_and_written_to_the_conductor_track() {
+    let mut ws = super::MidiWorkspace::from_smf_file("test_corpus/meta_events.mid").unwrap();
+    let mut markers = ws.create_marker_info(0).unwrap();
+    assert_eq!(markers.markers, vec![
+        Marker::new(0, MarkerKind::Text, "text"),
+        Marker::new(480, MarkerKind::Marker, "Verse"),
+        Marker::new(960, MarkerKind::CuePoint, "cue"),
+        Marker::new(960, MarkerKind::Text, ""),
+    ]);
+
+    markers.rename(1, "Chorus");
+    markers.remove(3);
+    markers.add(Marker::new(240, MarkerKind::CuePoint, "Thunder"));
+    ws.set_marker_info(&markers).unwrap();
+    assert!(ws.is_modified());
+    assert_eq!(ws.create_marker_info(0).unwrap().markers, markers.markers);
+    let track = ws.events_abs_tick(0).unwrap();
+    assert!(track.events().last().unwrap().is_end_of_track());
+    // the sequencer specific event is left alone
+    let kept = track.events().iter()
+        .filter(|ate| matches!(&ate.track_event.event, rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::SequencerSpecificEvent))
+        .count();
+    assert_eq!(kept, 1);
+}
@@ -0,0 +1,244 @@
+//! Time signatures, and the bars they divide a song into.
+//!
+//! Every time signature change starts a new bar. Changes made in the editor have to land
+//! on a bar line, so that the bars before them keep their length.
+
+use std::fmt;
+use crate::error::{Error, Result};
+use super::TimeSignatureInfo;
+
+/// midi clocks per quarter note
+const CLOCKS_PER_QUARTER: u32 = 24;
+
+/// The data of a time signature meta event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub numerator: u8,
+    /// a power of two, the note value of a beat
+    pub denominator: u8,
+    /// midi clocks between metronome clicks
+    pub clocks_per_click: u8,
+    /// notated 32nd notes in a midi quarter note, nearly always 8
+    pub notated_32nds: u8,
+}
+
+impl TimeSignature {
+    /// `numerator`/`denominator` with a click on every beat
+    pub fn new(numerator: u8, denominator: u8) -> Self {
+        TimeSignature {
+            numerator,
+            denominator,
+            clocks_per_click: (CLOCKS_PER_QUARTER * 4 / denominator.max(1) as u32).clamp(1, 255) as u8,
+            notated_32nds: 8,
+        }
+    }
+
+    /// the time signature of the data of a meta event, which has to be four bytes
+    pub fn from_meta_data(data: &[u8]) -> Option<Self> {
+        match *data {
+            // denominators above 2^7 do not fit in a u8
+            [nn, dd, cc, bb] if nn > 0 && dd < 8 => Some(TimeSignature {
+                numerator: nn,
+                denominator: 2_u8.pow(dd as u32),
+                clocks_per_click: cc,
+                notated_32nds: bb,
+            }),
+            _ => None
+        }
+    }
+
+    pub fn meta_event(&self) -> rimd::MetaEvent {
+        rimd::MetaEvent::time_signature(self.numerator, self.denominator.trailing_zeros() as u8,
+            self.clocks_per_click, self.notated_32nds)
+    }
+
+    /// whether it can be written to a file
+    pub fn is_valid(&self) -> bool {
+        self.numerator > 0 && self.denominator.is_power_of_two() && self.clocks_per_click > 0 && self.notated_32nds > 0
+    }
+
+    /// (numerator, denominator)
+    pub fn fraction(&self) -> (u8, u8) {
+        (self.numerator, self.denominator)
+    }
+
+    pub fn beat_ticks(&self, resolution: u16) -> f64 {
+        resolution as f64 * 4.0 / self.denominator.max(1) as f64
+    }
+
+    pub fn bar_ticks(&self, resolution: u16) -> u64 {
+        resolution as u64 * 4 * self.numerator as u64 / self.denominator.max(1) as u64
+    }
+}
+
+impl Default for TimeSignature {
+    /// 4/4, which is in effect until the first time signature, as the SMF spec says
+    fn default() -> Self {
+        TimeSignature::new(4, 4)
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bar {
+    /// counted from 1
+    pub number: u32,
+    pub start: u64,
+    /// shorter than a full bar if a time signature change cuts it short
+    pub length: u64,
+    pub signature: TimeSignature,
+}
+
+impl TimeSignatureInfo {
+    /// the time signature in effect at `abs_tick`
+    pub fn signature_at(&self, abs_tick: u64) -> TimeSignature {
+        self.changes.iter()
+            .filter(|(abs, _)| *abs <= abs_tick)
+            .max_by_key(|(abs, _)| *abs)
+            .map(|(_, ts)| *ts)
+            .unwrap_or_default()
+    }
+
+    /// the change at `abs_tick`, if there is one
+    pub fn change_at(&self, abs_tick: u64) -> Option<TimeSignature> {
+        self.changes.iter().find(|(abs, _)| *abs == abs_tick).map(|(_, ts)| *ts)
+    }
+
+    /// the bars from the start of the song to the one `end` is in
+    pub fn bars(&self, resolution: u16, end: u64) -> Vec<Bar> {
+        let mut bars = Vec::new();
+        let mut start = 0;
+        let mut number = 1;
+        while start <= end {
+            let signature = self.signature_at(start);
+            let next_change = self.changes.iter().map(|(abs, _)| *abs).find(|abs| *abs > start);
+            let full = signature.bar_ticks(resolution).max(1);
+            let length = next_change.map(|abs| full.min(abs - start)).unwrap_or(full);
+            bars.push(Bar { number, start, length, signature });
+            start += length;
+            number += 1;
+        }
+        bars
+    }
+
+    /// the bar `abs_tick` is in
+    pub fn bar_at(&self, abs_tick: u64, resolution: u16) -> Bar {
+        *self.bars(resolution, abs_tick).last().expect("there is always a first bar")
+    }
+
+    /// whether a bar of the changes before `abs_tick` starts there
+    pub fn is_bar_line(&self, abs_tick: u64, resolution: u16) -> bool {
+        let before = TimeSignatureInfo::new(self.changes.iter().filter(|(abs, _)| *abs < abs_tick).copied().collect(), false);
+        before.bar_at(abs_tick, resolution).start == abs_tick
+    }
+
+    /// Sets the time signature from the bar line at `abs_tick` on, replacing a change which is already there.
+    pub fn set_at_bar(&mut self, abs_tick: u64, signature: TimeSignature, resolution: u16) -> Result<()> {
+        if !signature.is_valid() {
+            return Err(Error::InvalidArgument(format!("invalid time signature {:?}", signature)));
+        }
+        if !self.is_bar_line(abs_tick, resolution) {
+            return Err(Error::NotOnBarLine(abs_tick));
+        }
+        self.edit(abs_tick, resolution, |ts| {
+            ts.changes.retain(|(abs, _)| *abs != abs_tick);
+            ts.append((abs_tick, signature));
+        })
+    }
+
+    /// removes the change at `abs_tick`, the time signature before it goes on instead
+    pub fn remove_at_bar(&mut self, abs_tick: u64, resolution: u16) -> Result<()> {
+        self.edit(abs_tick, resolution, |ts| ts.changes.retain(|(abs, _)| *abs != abs_tick))
+    }
+
+    /// edits the change at `abs_tick`, unless that would move the next change off its bar line
+    fn edit<F: FnOnce(&mut Self)>(&mut self, abs_tick: u64, resolution: u16, f: F) -> Result<()> {
+        let mut edited = self.clone();
+        f(&mut edited);
+        let next = self.changes.iter().map(|(abs, _)| *abs).find(|abs| *abs > abs_tick);
+        if let Some(next) = next {
+            if self.is_bar_line(next, resolution) && !edited.is_bar_line(next, resolution) {
+                return Err(Error::NotOnBarLine(next));
+            }
+        }
+        *self = edited;
+        Ok(())
+    }
+}
+
+#[test]
+fn bars_are_numbered_across_changes() {
+    let ts = TimeSignatureInfo::new(vec![
+        (0, TimeSignature::new(4, 4)),
+        (3840, TimeSignature::new(3, 4)),
+        (6720, TimeSignature::new(6, 8)),
+    ], false);
+    let starts: Vec<(u32, u64)> = ts.bars(480, 8000).iter().map(|bar| (bar.number, bar.start)).collect();
+    assert_eq!(starts, vec![(1, 0), (2, 1920), (3, 3840), (4, 5280), (5, 6720)]);
+    assert_eq!(ts.bar_at(7000, 480).signature, TimeSignature::new(6, 8));
+    assert_eq!(ts.bar_at(9000, 480).number, 6);
+
+    // a change off the bar line of a file cuts the bar before it short
+    let ts = TimeSignatureInfo::new(vec![(0, TimeSignature::new(4, 4)), (2400, TimeSignature::new(3, 4))], false);
+    let bars = ts.bars(480, 3840);
+    assert_eq!(bars[1].length, 480);
+    assert_eq!((bars[2].number, bars[2].start, bars[3].start), (3, 2400, 3840));
+}
+
+#[test]
+fn changes_have_to_be_on_bar_lines() {
+    let mut ts = TimeSignatureInfo::new(vec![(0, TimeSignature::new(4, 4)), (3840, TimeSignature::new(3, 4))], false);
+    match ts.set_at_bar(1000, TimeSignature::new(3, 4), 480) {
+        Err(Error::NotOnBarLine(1000)) => {},
+        other => panic!("unexpected {:?}", other)
+    }
+    // 5/4 from bar 2 would put the change of bar 3 in the middle of a bar
+    match ts.set_at_bar(1920, TimeSignature::new(5, 4), 480) {
+        Err(Error::NotOnBarLine(3840)) => {},
+        other => panic!("unexpected {:?}", other)
+    }
+    ts.set_at_bar(1920, TimeSignature::new(2, 4), 480).unwrap();
+    assert_eq!(ts.bar_at(3840, 480).number, 4);
+    assert!(ts.set_at_bar(3840, TimeSignature::new(4, 3), 480).is_err());
+
+    let mut seven_eight = TimeSignature::new(7, 8);
+    seven_eight.clocks_per_click = 36;
+    ts.set_at_bar(3840, seven_eight, 480).unwrap();
+    assert_eq!(ts.change_at(3840), Some(seven_eight));
+    assert_eq!(ts.changes.len(), 3);
+
+    ts.remove_at_bar(1920, 480).unwrap();
+    assert_eq!(ts.bar_at(3840, 480).number, 3);
+    // without the change at bar 1, 4/4 goes on
+    ts.remove_at_bar(0, 480).unwrap();
+    assert_eq!(ts.signature_at(0), TimeSignature::default());
+}
+
+#[test]
+fn time_signatures_are_written_to_the_conductor_track() {
+    let mut ws = super::MidiWorkspace::empty();
+    let mut ts = ws.create_time_signature_info(0).unwrap();
+    assert_eq!(ts.signature_at(0), TimeSignature { numerator: 4, denominator: 4, clocks_per_click: 24, notated_32nds: 8 });
+    let mut seven_eight = TimeSignature::new(7, 8);
+    seven_eight.clocks_per_click = 36;
+    ts.set_at_bar(1920, seven_eight, 480).unwrap();
+    ws.set_time_signature_info(&ts).unwrap();
+    assert!(ws.is_modified());
+
+    let read = ws.create_time_signature_info(0).unwrap();
+    assert_eq!(read.changes, ts.changes);
+    let data = ws.events_abs_tick(0).unwrap().events().iter()
+        .filter(|ate| ate.abs_time == 1920)
+        .find_map(|ate| match &ate.track_event.event {
+            rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::TimeSignature => Some(meta.data.clone()),
+            _ => None
+        });
+    assert_eq!(data, Some(vec![7, 3, 36, 8]));
+    // the tempo is left alone
+    assert_eq!(ws.create_tempo_info(0).unwrap().changes, vec![(0, super::DEFAULT_TEMPO)]);
+}
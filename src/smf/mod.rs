@@ -1,13 +1,31 @@
 pub mod util;
 pub mod play;
+pub mod synth;
+pub mod render;
+pub mod reader;
+pub mod division;
+pub mod rescale;
+pub mod writer;
+pub mod save;
+pub mod tempo;
+pub mod meter;
+pub mod key;
+pub mod marker;
+#[cfg(test)]
+mod roundtrip;
 
 use rimd::{SMF, TrackEvent};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use crate::error::{Error, Result};
+use division::Division;
 
 #[derive(Debug, Clone)]
 pub struct MidiWorkspace {
     midi: SMF,
-
+    /// the file it was opened from or last saved to
+    path: Option<PathBuf>,
+    /// edited since it was opened or saved
+    modified: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -152,6 +170,126 @@ impl AbsTrack {
         }
     }
 
+    /// shifts every note by `semitones`. Notes on the drum channel are left alone
+    /// and notes which would go out of range are clamped.
+    pub fn transpose(&mut self, semitones: i32) {
+        for event in self.events.iter_mut() {
+            if let rimd::Event::Midi(ref mut msg) = event.track_event.event {
+                if let Some((ch, note, _)) = util::note_on(msg).or_else(|| util::note_off(msg)) {
+                    if ch != util::DRUM_CHANNEL {
+                        msg.data[1] = (note as i32 + semitones).max(0).min(127) as u8;
+                    }
+                }
+            }
+        }
+    }
+
+    /// moves every note by `steps` notes of the scale of the key it starts in. Notes on the
+    /// drum channel are left alone, and a note held over a key change ends on the note it started on.
+    pub fn transpose_in_key(&mut self, steps: i32, keys: &KeySignatureInfo) {
+        use std::collections::HashMap;
+        // transposed notes which are on, oldest first, per (channel, note)
+        let mut sounding: HashMap<(u8, u8), Vec<u8>> = HashMap::new();
+        for event in self.events.iter_mut() {
+            if let rimd::Event::Midi(ref mut msg) = event.track_event.event {
+                if let Some((ch, note, _)) = util::note_on(msg) {
+                    if ch != util::DRUM_CHANNEL {
+                        let transposed = keys.key_at(event.abs_time).transpose(note, steps);
+                        sounding.entry((ch, note)).or_default().push(transposed);
+                        msg.data[1] = transposed;
+                    }
+                } else if let Some((ch, note, _)) = util::note_off(msg) {
+                    if ch != util::DRUM_CHANNEL {
+                        msg.data[1] = match sounding.get_mut(&(ch, note)).filter(|pending| !pending.is_empty()) {
+                            Some(pending) => pending.remove(0),
+                            None => keys.key_at(event.abs_time).transpose(note, steps)
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    /// moves note starts to the nearest multiple of `grid`, keeping note lengths.
+    pub fn quantize(&mut self, grid: u64) {
+        if grid == 0 {
+            return;
+        }
+        use std::collections::HashMap;
+        // shifts of notes which are on, oldest first, per (channel, note)
+        let mut shifts: HashMap<(u8, u8), Vec<i64>> = HashMap::new();
+        for event in self.events.iter_mut() {
+            if let rimd::Event::Midi(ref msg) = event.track_event.event {
+                if let Some((ch, note, _)) = util::note_on(msg) {
+                    let quantized = util::quantize_tick(event.abs_time, grid);
+                    shifts.entry((ch, note)).or_insert_with(Vec::new).push(quantized as i64 - event.abs_time as i64);
+                    event.abs_time = quantized;
+                } else if let Some((ch, note, _)) = util::note_off(msg) {
+                    if let Some(pending) = shifts.get_mut(&(ch, note)) {
+                        if !pending.is_empty() {
+                            let shift = pending.remove(0);
+                            event.abs_time = (event.abs_time as i64 + shift).max(0) as u64;
+                        }
+                    }
+                }
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// splits the track into one track per midi channel.
+    /// Meta and system events go to an extra first track, if there are any.
+    pub fn split_by_channel(&self) -> Vec<AbsTrack> {
+        use std::collections::BTreeMap;
+        let mut channels: BTreeMap<u8, Vec<AbsTrackEvent>> = BTreeMap::new();
+        let mut others = Vec::new();
+        for event in &self.events {
+            if event.is_end_of_track() {
+                continue;
+            }
+            match &event.track_event.event {
+                rimd::Event::Midi(msg) if msg.data.first().map_or(false, |status| *status < 0xF0) => {
+                    channels.entry(msg.data[0] & 0x0F).or_insert_with(Vec::new).push(event.clone());
+                },
+                _ => others.push(event.clone())
+            }
+        }
+
+        let mut tracks = Vec::new();
+        if !others.is_empty() || channels.is_empty() {
+            tracks.push(others);
+        }
+        tracks.extend(channels.into_iter().map(|(_, events)| events));
+        tracks.into_iter()
+            .map(|mut events| {
+                events.push(AbsTrackEvent::end_of_track(0));
+                let mut track = AbsTrack::new(events);
+                track.clean();
+                track
+            })
+            .collect()
+    }
+
+    /// merges several tracks into one which has a single EndOfTrack.
+    pub fn merge(tracks: Vec<AbsTrack>) -> AbsTrack {
+        let mut events: Vec<AbsTrackEvent> = tracks.into_iter()
+            .flat_map(|t| t.events.into_iter())
+            .filter(|e| !e.is_end_of_track())
+            .collect();
+        events.push(AbsTrackEvent::end_of_track(0));
+        let mut track = AbsTrack::new(events);
+        track.clean();
+        track
+    }
+
+    /// moves every event to `f(abs_time)`.
+    pub fn retime<F: FnMut(u64) -> u64>(&mut self, mut f: F) {
+        for event in self.events.iter_mut() {
+            event.abs_time = f(event.abs_time);
+        }
+        self.dirty = true;
+    }
+
     pub fn clean(&mut self) {
         if self.dirty {
             self.sort_rebuild_delta_time();
@@ -168,76 +306,49 @@ impl AbsTrack {
         };
 
         // tick が同じときは note_off が note_on の前に来なければならない!
-        use std::cmp::Ordering;
-        self.events.sort_unstable_by(|a, b| {
-
-            // EndOfTrack は常に最後へ
-            if check_end_of_track(a) {
-                return Ordering::Greater;
-            } else if check_end_of_track(b) {
-                return Ordering::Less
-            }
-
-            match a.abs_time.cmp(&b.abs_time) {
-                Ordering::Less => Ordering::Less,
-                Ordering::Greater => Ordering::Greater,
-                Ordering::Equal => {
-                    // note_off or note_on の場合は考慮する必要がある
-                    // TODO: note_off note_on 以外も同様のケースはあるか?
-                    match a.track_event.event {
-                        rimd::Event::Midi(ref ea) => {
-                            if let Some(_) = util::note_on(ea) {
-                                match b.track_event.event {
-                                    rimd::Event::Midi(ref eb) => {
-                                        if let Some(_) = util::note_off(eb) {
-                                            Ordering::Greater
-                                        } else {
-                                            Ordering::Equal
-                                        }
-                                    },
-                                    _ => Ordering::Equal
-                                }
-                            } else if let Some(_) = util::note_off(ea) {
-                                Ordering::Less
-                            } else {
-                                match b.track_event.event {
-                                    rimd::Event::Midi(ref eb) => {
-                                        if let Some(_) = util::note_off(eb) {
-                                            Ordering::Greater
-                                        } else {
-                                            Ordering::Equal
-                                        }
-                                    },
-                                    _ => Ordering::Equal
-                                }
-                            }
-                        },
-                        _ => Ordering::Equal
-                    }
-                }
-            }
+        // EndOfTrack は常に最後へ
+        // stable, so that events of the same tick and class keep their order
+        self.events.sort_by_key(|ate| {
+            let rank = match &ate.track_event.event {
+                rimd::Event::Midi(msg) if util::note_off(msg).is_some() => 0,
+                _ => 1
+            };
+            (check_end_of_track(ate), ate.abs_time, rank)
         });
 
         // end_of_track は最後に1つあるはず
         // end_of_track の abs_tick を修正する
-        let max_abs_time = self.events.get(self.events.len()-2).map(|e| e.abs_time).unwrap_or(0);
+        let max_abs_time = self.events.len().checked_sub(2)
+            .and_then(|i| self.events.get(i))
+            .map(|e| e.abs_time)
+            .unwrap_or(0);
+        // 後ろに移動したイベントがなければ長さはそのまま
         match self.events.last_mut() {
-            Some(eot) =>  eot.abs_time = max_abs_time,
+            Some(eot) =>  eot.abs_time = eot.abs_time.max(max_abs_time),
             None => {}
         }
 
         // deltatime を abstime により修正する
         let length = self.events.len();
-        for i in 0..length-1 {
-            let e1_abs = self.events.get(i).unwrap().abs_time;
-            let e2 = self.events.get_mut(i+1).unwrap();
+        if let Some(first) = self.events.first_mut() {
+            first.track_event.vtime = first.abs_time;
+        }
+        for i in 1..length {
+            let e1_abs = self.events.get(i-1).unwrap().abs_time;
+            let e2 = self.events.get_mut(i).unwrap();
             e2.track_event.vtime = e2.abs_time - e1_abs;
         }
     }
 
     /// エクスポートする前の最終的な整理を行う
     fn finalize(&mut self) {
-        self.sort_rebuild_delta_time();
+        // tracks read from a file are in order already. Sorting them again would put
+        // the note off of a zero length note before its note on
+        let in_order = self.events.windows(2)
+            .all(|w| w[0].abs_time <= w[1].abs_time && !w[0].is_end_of_track());
+        if self.dirty || !in_order {
+            self.sort_rebuild_delta_time();
+        }
     }
 }
 
@@ -263,15 +374,29 @@ impl AbsTrackEvent {
     pub fn new(abs_time: u64, track_event: rimd::TrackEvent) -> Self {
         AbsTrackEvent { abs_time, track_event }
     }
+
+    pub fn end_of_track(abs_time: u64) -> Self {
+        AbsTrackEvent::new(abs_time, TrackEvent {
+            vtime: 0,
+            event: rimd::Event::Meta(rimd::MetaEvent::end_of_track())
+        })
+    }
+
+    pub fn is_end_of_track(&self) -> bool {
+        match &self.track_event.event {
+            rimd::Event::Meta(meta) => meta.command == rimd::MetaCommand::EndOfTrack,
+            _ => false
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TempoInfo {
-    pub changes: Vec<(u64, u16)> // (abs_tick, bpm)
+    pub changes: Vec<(u64, u32)> // (abs_tick, microseconds per quarter note)
 }
 
 impl TempoInfo {
-    pub fn new(mut changes: Vec<(u64, u16)>, need_sort: bool) -> Self {
+    pub fn new(mut changes: Vec<(u64, u32)>, need_sort: bool) -> Self {
         if need_sort {
             changes.sort_by_key(|k| k.0);
         }
@@ -280,12 +405,12 @@ impl TempoInfo {
         }
     }
 
-    pub fn append(&mut self, change: (u64, u16)) {
+    pub fn append(&mut self, change: (u64, u32)) {
         self.changes.push(change);
         self.changes.sort_by_key(|k| k.0);
     }
 
-    pub fn delete(&mut self, deleted: (u64, u16)) {
+    pub fn delete(&mut self, deleted: (u64, u32)) {
         let mut i = 0;
         while i != self.changes.len() {
             if self.changes[i] == deleted {
@@ -296,21 +421,76 @@ impl TempoInfo {
         }
     }
 
-    pub fn tempo(&self, abs_tick: u64) -> Option<u16> {
+    /// microseconds per quarter note at `abs_tick`
+    pub fn tempo(&self, abs_tick: u64) -> Option<u32> {
         self.changes.iter()
             .filter(|(abs, _)| *abs <= abs_tick)
             .max_by_key(|(abs, _)| abs)
-            .map(|(_, bpm)| *bpm)
+            .map(|(_, usec)| *usec)
+    }
+
+    /// beats per minute at `abs_tick`
+    pub fn bpm(&self, abs_tick: u64) -> Option<f64> {
+        self.tempo(abs_tick).map(tempo::bpm)
+    }
+
+    /// seconds elapsed from tick 0 to `abs_tick`.
+    /// 120 bpm is assumed before the first tempo change.
+    pub fn seconds_at(&self, abs_tick: u64, resolution: u16) -> f64 {
+        let seconds_per_tick = |usec: u32| usec.max(1) as f64 / (1000000.0 * resolution.max(1) as f64);
+        let mut seconds = 0.0;
+        let mut last_tick = 0;
+        let mut usec = DEFAULT_TEMPO;
+        for &(tick, new_usec) in &self.changes {
+            if tick >= abs_tick {
+                break;
+            }
+            seconds += (tick - last_tick) as f64 * seconds_per_tick(usec);
+            last_tick = tick;
+            usec = new_usec;
+        }
+        seconds + (abs_tick - last_tick) as f64 * seconds_per_tick(usec)
+    }
+
+    /// moves the changes to the same positions in another resolution.
+    pub fn rescale(&mut self, from: u16, to: u16) {
+        for (abs, _) in self.changes.iter_mut() {
+            *abs = rescale::rescale_tick(*abs, from, to).0;
+        }
+    }
+
+    /// inverse of `seconds_at`, not rounded.
+    pub fn ticks_at(&self, seconds: f64, resolution: u16) -> f64 {
+        let ticks_per_second = |usec: u32| 1000000.0 * resolution.max(1) as f64 / usec.max(1) as f64;
+        let mut last_seconds = 0.0;
+        let mut last_tick = 0;
+        let mut usec = DEFAULT_TEMPO;
+        for &(tick, new_usec) in &self.changes {
+            let change_seconds = last_seconds + (tick - last_tick) as f64 / ticks_per_second(usec);
+            if change_seconds >= seconds {
+                break;
+            }
+            last_seconds = change_seconds;
+            last_tick = tick;
+            usec = new_usec;
+        }
+        last_tick as f64 + (seconds - last_seconds) * ticks_per_second(usec)
     }
 }
 
+/// tempo of a midi file which has no tempo setting, 120 bpm in microseconds per quarter note
+pub const DEFAULT_TEMPO: u32 = 500000;
+
+/// ticks per quarter note of new files
+pub const DEFAULT_RESOLUTION: u16 = 480;
+
 #[derive(Debug, Clone)]
 pub struct TimeSignatureInfo {
-    pub changes: Vec<(u64, (u8, u8))> // (abs_tick, (numerator, denominator))
+    pub changes: Vec<(u64, meter::TimeSignature)>
 }
 
 impl TimeSignatureInfo {
-    pub fn new(mut changes: Vec<(u64, (u8, u8))>, need_sort: bool) -> Self {
+    pub fn new(mut changes: Vec<(u64, meter::TimeSignature)>, need_sort: bool) -> Self {
         if need_sort {
             changes.sort_by_key(|(abs, _)| *abs)
         }
@@ -319,12 +499,12 @@ impl TimeSignatureInfo {
         }
     }
 
-    pub fn append(&mut self, change: (u64, (u8, u8))) {
+    pub fn append(&mut self, change: (u64, meter::TimeSignature)) {
         self.changes.push(change);
         self.changes.sort_by_key(|(abs, _)| *abs);
     }
 
-    pub fn delete(&mut self, deleted: (u64, (u8, u8))) {
+    pub fn delete(&mut self, deleted: (u64, meter::TimeSignature)) {
         let mut i = 0;
         while i != self.changes.len() {
             if self.changes[i] == deleted {
@@ -335,26 +515,65 @@ impl TimeSignatureInfo {
         }
     }
 
+    /// moves the changes to the same positions in another resolution.
+    pub fn rescale(&mut self, from: u16, to: u16) {
+        for (abs, _) in self.changes.iter_mut() {
+            *abs = rescale::rescale_tick(*abs, from, to).0;
+        }
+    }
+
+    /// (numerator, denominator) at `abs_tick`, none before the first change
     pub fn time_signature(&self, abs_tick: u64) -> Option<(u8, u8)> {
         self.changes.iter()
             .filter(|(abs, _)| *abs <= abs_tick)
             .max_by_key(|(abs, _)| *abs)
-            .map(|(_, ts)| *ts)
+            .map(|(_, ts)| ts.fraction())
     }
 
-    pub fn measure_of_abs_ticks(&self, abs_ticks: &Vec<u64>) -> Result<Vec<(u8, u8)>, ()> {
-        let mut first = true;
-        let mut curr_ts;
-        for abs_tick in abs_ticks {
-            if first {
-                match self.time_signature(*abs_tick) {
-                    Some(ts) => curr_ts = ts,
-                    None => return Err(())
-                }
-            }
+    /// time signature in effect at each of `abs_ticks`.
+    pub fn measure_of_abs_ticks(&self, abs_ticks: &Vec<u64>) -> Result<Vec<(u8, u8)>> {
+        abs_ticks.iter()
+            .map(|abs_tick| self.time_signature(*abs_tick).ok_or(Error::NoTimeSignature(*abs_tick)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeySignatureInfo {
+    pub changes: Vec<(u64, key::KeySignature)>
+}
+
+impl KeySignatureInfo {
+    pub fn new(mut changes: Vec<(u64, key::KeySignature)>, need_sort: bool) -> Self {
+        if need_sort {
+            changes.sort_by_key(|(abs, _)| *abs)
         }
+        KeySignatureInfo {
+            changes
+        }
+    }
+
+    pub fn append(&mut self, change: (u64, key::KeySignature)) {
+        self.changes.push(change);
+        self.changes.sort_by_key(|(abs, _)| *abs);
+    }
+}
 
-        todo!("not implemented yet")
+/// Markers, cue points and text events of a track, in the order they are in the track.
+#[derive(Debug, Clone)]
+pub struct MarkerInfo {
+    pub markers: Vec<marker::Marker>
+}
+
+impl MarkerInfo {
+    pub fn new(mut markers: Vec<marker::Marker>, need_sort: bool) -> Self {
+        if need_sort {
+            // stable, so that markers of the same tick keep their order
+            markers.sort_by_key(|m| m.abs_tick)
+        }
+        MarkerInfo {
+            markers
+        }
     }
 }
 
@@ -367,19 +586,57 @@ impl Into<rimd::TrackEvent> for AbsTrackEvent {
 impl Default for MidiWorkspace {
     fn default() -> Self {
         let format = rimd::SMFFormat::MultiTrack;
-        const DIVISION: i16 = 480;
         let tracks = Vec::new();
         MidiWorkspace {
-            midi: SMF { format, tracks, division: DIVISION }
+            midi: SMF { format, tracks, division: DEFAULT_RESOLUTION as i16 },
+            path: None,
+            modified: false,
         }
     }
 }
 
 impl MidiWorkspace {
-    pub fn from_smf_file(path: impl AsRef<Path>) -> Result<Self, String> {
-        Ok(MidiWorkspace {
-            midi: SMF::from_file(path.as_ref()).map_err(|e| e.to_string())?.to_multi_track().ok_or_else(|| String::from("failed to convert into type 1 smf"))?
-        })
+    pub fn from_smf_file(path: impl AsRef<Path>) -> Result<Self> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let mut ws = Self::from_smf(reader::read_strict(&bytes)?)?;
+        ws.path = Some(path.as_ref().to_path_buf());
+        Ok(ws)
+    }
+
+    /// like `from_smf_file`, but repairs what it can instead of failing.
+    /// Returns what was repaired along with the workspace.
+    pub fn from_smf_file_lenient(path: impl AsRef<Path>) -> Result<(Self, Vec<reader::ReadWarning>)> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let (smf, warnings) = reader::read_lenient(&bytes)?;
+        let mut ws = Self::from_smf(smf)?;
+        ws.path = Some(path.as_ref().to_path_buf());
+        Ok((ws, warnings))
+    }
+
+    pub(crate) fn from_smf(smf: SMF) -> Result<Self> {
+        Division::from_raw(smf.division)?;
+        let midi = match smf.format {
+            // kept as a single track so that it is saved as format 0 again
+            rimd::SMFFormat::Single => smf,
+            _ => smf.to_multi_track().ok_or_else(|| Error::Parse(String::from("failed to convert into type 1 smf")))?
+        };
+        Ok(MidiWorkspace { midi, path: None, modified: false })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// whether there are edits which have not been saved
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// sets the file the workspace belongs to and whether it differs from that file,
+    /// for a workspace which was read from somewhere else, e.g. a recovery session.
+    pub fn set_origin(&mut self, path: Option<PathBuf>, modified: bool) {
+        self.path = path;
+        self.modified = modified;
     }
 
     pub fn track_count(&self) -> usize {
@@ -413,73 +670,318 @@ impl MidiWorkspace {
         }
     }
 
-    pub fn replace_events<T: Into<rimd::TrackEvent>>(&mut self, track: usize, events: Vec<T>) -> Result<(), ()> {
-        let track = self.midi.tracks.get_mut(track);
-        match track {
-            Some(track) => {
-                track.events = events.into_iter().map(|i| i.into()).collect();
+    pub fn replace_events<T: Into<rimd::TrackEvent>>(&mut self, track: usize, events: Vec<T>) -> Result<()> {
+        match self.midi.tracks.get_mut(track) {
+            Some(t) => {
+                t.events = events.into_iter().map(|i| i.into()).collect();
+                self.modified = true;
                 Ok(())
             }
-            None => Err(())
+            None => Err(Error::NoSuchTrack(track))
         }
     }
 
-    pub fn create_tempo_info(&self, track: usize) -> Option<TempoInfo> {
-        match self.events_abs_tick(track) {
-            Some(events) => {
-                let tempo_changes = events.events().iter()
-                    .map(|ate| (ate.abs_time, &ate.track_event.event))
-                    .filter_map(|(abs, ev)| match ev {
-                        rimd::Event::Meta(meta) => Some((abs, meta)),
-                        _ => None
-                    })
-                    .filter_map(|(abs, meta)| {
-                        match meta.command {
-                            rimd::MetaCommand::TempoSetting => {
-                                let data = &meta.data;
-                                assert_eq!(data.len(), 3);
-                                let usec: u32 = (data.get(0).unwrap() << 16) as u32 + (data.get(1).unwrap() << 8) as u32 + (*data.get(0).unwrap()) as u32;
-                                Some((abs, (60 * 1000000 / usec) as u16))
-                            }
-                            _ => None
-                        }
-                    }).collect::<Vec<(u64, u16)>>();
-                Some(TempoInfo::new(tempo_changes, false))
-            }
-            None => None
+    pub fn create_tempo_info(&self, track: usize) -> Result<TempoInfo> {
+        let events = self.events_abs_tick(track).ok_or(Error::NoSuchTrack(track))?;
+        let tempo_changes = events.events().iter()
+            .filter_map(|ate| match &ate.track_event.event {
+                rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::TempoSetting => Some((ate.abs_time, meta)),
+                _ => None
+            })
+            .map(|(abs, meta)| match tempo::decode(&meta.data) {
+                Some(usec) => Ok((abs, usec)),
+                None => Err(Error::malformed_meta(meta, abs))
+            })
+            .collect::<Result<Vec<(u64, u32)>>>()?;
+        Ok(TempoInfo::new(tempo_changes, false))
+    }
+
+    /// replaces the tempo changes of the conductor track, track 0, with `tempo`.
+    pub fn set_tempo_info(&mut self, tempo: &TempoInfo) -> Result<()> {
+        let events = tempo.changes.iter()
+            .map(|&(abs, usec)| (abs, rimd::MetaEvent::tempo_setting(usec.clamp(1, tempo::MAX_TEMPO))))
+            .collect();
+        self.replace_meta_events(0, &[rimd::MetaCommand::TempoSetting], events)
+    }
+
+    pub fn create_key_signature_info(&self, track: usize) -> Result<KeySignatureInfo> {
+        let events = self.events_abs_tick(track).ok_or(Error::NoSuchTrack(track))?;
+        let key_changes = events.events().iter()
+            .filter_map(|ate| match &ate.track_event.event {
+                rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::KeySignature => Some((ate.abs_time, meta)),
+                _ => None
+            })
+            .map(|(abs, meta)| match key::KeySignature::from_meta_data(&meta.data) {
+                Some(key) => Ok((abs, key)),
+                None => Err(Error::malformed_meta(meta, abs))
+            })
+            .collect::<Result<Vec<(u64, key::KeySignature)>>>()?;
+        Ok(KeySignatureInfo::new(key_changes, false))
+    }
+
+    /// replaces the key signatures of the conductor track, track 0, with `keys`.
+    pub fn set_key_signature_info(&mut self, keys: &KeySignatureInfo) -> Result<()> {
+        let events = keys.changes.iter().map(|(abs, key)| (*abs, key.meta_event())).collect();
+        self.replace_meta_events(0, &[rimd::MetaCommand::KeySignature], events)
+    }
+
+    /// replaces the meta events of the types in `commands` in `track` with `events`, keeping everything else.
+    fn replace_meta_events(&mut self, track: usize, commands: &[rimd::MetaCommand], events: Vec<(u64, rimd::MetaEvent)>) -> Result<()> {
+        let abs_track = self.events_abs_tick(track).ok_or(Error::NoSuchTrack(track))?;
+        let mut kept: Vec<AbsTrackEvent> = abs_track.into();
+        kept.retain(|ate| !matches!(&ate.track_event.event, rimd::Event::Meta(meta) if commands.contains(&meta.command)));
+        kept.extend(events.into_iter().map(|(abs, meta)| AbsTrackEvent::new(abs, TrackEvent {
+            vtime: 0,
+            event: rimd::Event::Meta(meta)
+        })));
+        let mut abs_track = AbsTrack::new(kept);
+        abs_track.clean();
+        self.replace_events(track, abs_track.into())
+    }
+
+    pub fn create_time_signature_info(&self, track: usize) -> Result<TimeSignatureInfo> {
+        let events = self.events_abs_tick(track).ok_or(Error::NoSuchTrack(track))?;
+        let ts_changes = events.events().iter()
+            .filter_map(|ate| match &ate.track_event.event {
+                rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::TimeSignature => Some((ate.abs_time, meta)),
+                _ => None
+            })
+            .map(|(abs, meta)| match meter::TimeSignature::from_meta_data(&meta.data) {
+                Some(ts) => Ok((abs, ts)),
+                None => Err(Error::malformed_meta(meta, abs))
+            })
+            .collect::<Result<Vec<(u64, meter::TimeSignature)>>>()?;
+        Ok(TimeSignatureInfo::new(ts_changes, false))
+    }
+
+    /// replaces the time signature changes of the conductor track, track 0, with `ts`.
+    pub fn set_time_signature_info(&mut self, ts: &TimeSignatureInfo) -> Result<()> {
+        let events = ts.changes.iter().map(|(abs, ts)| (*abs, ts.meta_event())).collect();
+        self.replace_meta_events(0, &[rimd::MetaCommand::TimeSignature], events)
+    }
+
+    pub fn create_marker_info(&self, track: usize) -> Result<MarkerInfo> {
+        let events = self.events_abs_tick(track).ok_or(Error::NoSuchTrack(track))?;
+        let markers = events.events().iter()
+            .filter_map(|ate| match &ate.track_event.event {
+                rimd::Event::Meta(meta) => marker::Marker::from_meta(ate.abs_time, meta),
+                _ => None
+            })
+            .collect();
+        Ok(MarkerInfo::new(markers, false))
+    }
+
+    /// replaces the markers, cue points and text events of the conductor track, track 0, with `markers`.
+    pub fn set_marker_info(&mut self, markers: &MarkerInfo) -> Result<()> {
+        let commands: Vec<rimd::MetaCommand> = marker::MarkerKind::ALL.iter().map(|kind| kind.command()).collect();
+        let events = markers.markers.iter().map(|m| (m.abs_tick, m.meta_event())).collect();
+        self.replace_meta_events(0, &commands, events)
+    }
+
+    /// ticks per beat. For SMPTE divisions, a second is shown as a beat.
+    pub fn resolution(&self) -> i16 {
+        self.division().ticks_per_beat() as i16
+    }
+
+    pub fn division(&self) -> Division {
+        // checked when the file was opened
+        Division::from_raw(self.midi.division).unwrap_or(Division::TicksPerQuarter(DEFAULT_RESOLUTION))
+    }
+
+    /// tempo changes of track 0, or none if there are no tracks.
+    fn tempo_map(&self) -> Result<TempoInfo> {
+        if self.track_count() == 0 {
+            Ok(TempoInfo::new(Vec::new(), false))
+        } else {
+            self.create_tempo_info(0)
         }
     }
 
-    pub fn create_time_signature_info(&self, track: usize) -> Option<TimeSignatureInfo> {
-        match self.events_abs_tick(track) {
-            Some(events) => {
-                let ts_changes = events.events().iter()
-                    .map(|ate| (ate.abs_time, &ate.track_event.event))
-                    .filter_map(|(abs, ev)| match ev {
-                        rimd::Event::Meta(meta) => Some((abs, meta)),
-                        _ => None
-                    })
-                    .filter_map(|(abs, meta)| {
-                        match meta.command {
-                            rimd::MetaCommand::TimeSignature => {
-                                let data = &meta.data;
-                                assert_eq!(data.len(), 4);
-                                let nn = *data.get(0).unwrap();
-                                let dd = 2_i32.pow(*data.get(1).unwrap() as u32) as u8;
-                                Some((abs, (nn, dd)))
-                            }
-                            _ => None
-                        }
-                    })
-                    .collect::<Vec<(u64, (u8, u8))>>();
-                Some(TimeSignatureInfo::new(ts_changes, false))
-            }
-            None => None
+    /// seconds from the beginning to `abs_tick`.
+    pub fn seconds_at(&self, abs_tick: u64) -> Result<f64> {
+        Ok(self.division().seconds_at(abs_tick, &self.tempo_map()?))
+    }
+
+    /// hh:mm:ss:ff of the tick, for SMPTE based files.
+    pub fn timecode(&self, abs_tick: u64) -> Option<String> {
+        self.division().timecode(abs_tick)
+    }
+
+    /// changes the ticks per quarter note, moving every event to the nearest tick
+    /// in the new resolution. Returns what could not be mapped exactly.
+    pub fn set_resolution(&mut self, resolution: u16) -> Result<rescale::RescaleReport> {
+        let from = match self.division() {
+            Division::TicksPerQuarter(ppq) => ppq,
+            Division::Smpte(_, _) => return Err(Error::InvalidArgument(String::from("SMPTE based files have no resolution; convert the division instead")))
+        };
+        if resolution == 0 || resolution > i16::MAX as u16 {
+            return Err(Error::InvalidArgument(format!("invalid resolution: {}", resolution)));
+        }
+        let mut report = rescale::RescaleReport { from, to: resolution, ..Default::default() };
+        for i in 0..self.track_count() {
+            let mut abs_track = self.events_abs_tick(i).unwrap();
+            rescale::rescale_track(&mut abs_track, i, from, resolution, &mut report);
+            abs_track.clean();
+            self.replace_events(i, abs_track.into())?;
         }
+        self.midi.division = resolution as i16;
+        self.modified = true;
+        Ok(report)
     }
 
-    pub fn resolution(&self) -> i16 {
-        self.midi.division
+    /// moves every event to the tick which plays at the same time in the new division.
+    /// Converts between ticks per quarter and SMPTE, or between resolutions.
+    pub fn convert_division(&mut self, division: Division) -> Result<()> {
+        let from = self.division();
+        let tempo = self.tempo_map()?;
+        // where the tempo changes end up, for ticks per quarter
+        let mut new_tempo = TempoInfo::new(Vec::new(), false);
+        for &(tick, usec) in &tempo.changes {
+            let seconds = from.seconds_at(tick, &tempo);
+            let new_tick = division.ticks_at(seconds, &new_tempo).round() as u64;
+            new_tempo.append((new_tick, usec));
+        }
+        for i in 0..self.track_count() {
+            let mut abs_track = self.events_abs_tick(i).unwrap();
+            abs_track.retime(|abs| division.ticks_at(from.seconds_at(abs, &tempo), &new_tempo).round() as u64);
+            abs_track.clean();
+            self.replace_events(i, abs_track.into())?;
+        }
+        self.midi.division = division.to_raw();
+        self.modified = true;
+        Ok(())
+    }
+
+    /// the format the workspace is saved in, which is the format of the file it was opened from.
+    pub fn format(&self) -> rimd::SMFFormat {
+        self.midi.format
+    }
+
+    /// Format 0 to 1 splits the single track by channel (see `split_track_by_channel`),
+    /// format 1 to 0 merges all tracks into one.
+    pub fn convert_format(&mut self, format: rimd::SMFFormat) -> Result<()> {
+        use rimd::SMFFormat::{Single, MultiTrack};
+        match (self.midi.format, format) {
+            (Single, Single) | (MultiTrack, MultiTrack) => {},
+            (Single, MultiTrack) => {
+                if self.track_count() > 0 {
+                    self.split_track_by_channel(0)?;
+                }
+            },
+            (MultiTrack, Single) => {
+                let tracks: Vec<usize> = (0..self.track_count()).collect();
+                if !tracks.is_empty() {
+                    self.merge_tracks(&tracks)?;
+                }
+            },
+            (from, to) => return Err(Error::InvalidArgument(format!("cannot convert {:?} to {:?}", from, to)))
+        }
+        if !matches!((self.midi.format, format), (Single, Single) | (MultiTrack, MultiTrack)) {
+            self.modified = true;
+        }
+        self.midi.format = format;
+        Ok(())
+    }
+
+    pub fn track_name(&self, track: usize) -> Option<&str> {
+        self.track(track).and_then(|t| t.name.as_deref())
+    }
+
+    /// runs `f` on the given track, or on every track if `track` is None.
+    fn edit_tracks<F: FnMut(&mut AbsTrack)>(&mut self, track: Option<usize>, mut f: F) -> Result<()> {
+        let tracks = match track {
+            Some(track) if track < self.track_count() => vec![track],
+            Some(track) => return Err(Error::NoSuchTrack(track)),
+            None => (0..self.track_count()).collect()
+        };
+        for i in tracks {
+            let mut abs_track = self.events_abs_tick(i).unwrap();
+            f(&mut abs_track);
+            abs_track.clean();
+            self.replace_events(i, abs_track.into())?;
+        }
+        Ok(())
+    }
+
+    pub fn transpose(&mut self, track: Option<usize>, semitones: i32) -> Result<()> {
+        self.edit_tracks(track, |t| t.transpose(semitones))
+    }
+
+    /// moves the notes by `steps` notes of the scale of the key signatures of the conductor track
+    pub fn transpose_in_key(&mut self, track: Option<usize>, steps: i32) -> Result<()> {
+        let keys = self.create_key_signature_info(0)?;
+        self.edit_tracks(track, |t| t.transpose_in_key(steps, &keys))
+    }
+
+    pub fn quantize(&mut self, track: Option<usize>, grid: u64) -> Result<()> {
+        if grid == 0 {
+            return Err(Error::InvalidArgument(String::from("quantize grid must not be 0")));
+        }
+        self.edit_tracks(track, |t| t.quantize(grid))
+    }
+
+    /// merges the given tracks into the first one of them and removes the rest.
+    /// Returns the index of the merged track.
+    pub fn merge_tracks(&mut self, tracks: &[usize]) -> Result<usize> {
+        let mut tracks = tracks.to_vec();
+        tracks.sort();
+        tracks.dedup();
+        if let Some(track) = tracks.iter().find(|t| **t >= self.track_count()) {
+            return Err(Error::NoSuchTrack(*track));
+        }
+        let first = *tracks.first().ok_or_else(|| Error::InvalidArgument(String::from("no tracks to merge")))?;
+        let merged = AbsTrack::merge(tracks.iter().map(|t| self.events_abs_tick(*t).unwrap()).collect());
+        self.replace_events(first, merged.into())?;
+        for track in tracks.iter().skip(1).rev() {
+            self.midi.tracks.remove(*track);
+        }
+        Ok(first)
+    }
+
+    /// replaces the track with one track per midi channel it uses (see `AbsTrack::split_by_channel`).
+    /// A format 0 workspace becomes format 1.
+    /// Returns the number of tracks it was split into.
+    pub fn split_track_by_channel(&mut self, track: usize) -> Result<usize> {
+        let abs_track = self.events_abs_tick(track).ok_or(Error::NoSuchTrack(track))?;
+        let split = abs_track.split_by_channel();
+        let count = split.len();
+        let name = self.midi.tracks.remove(track).name;
+        for (i, t) in split.into_iter().enumerate() {
+            let events: Vec<AbsTrackEvent> = t.into();
+            self.midi.tracks.insert(track + i, rimd::Track {
+                copyright: None,
+                name: name.clone(),
+                events: events.into_iter().map(|e| e.into()).collect()
+            });
+        }
+        if let rimd::SMFFormat::Single = self.midi.format {
+            self.midi.format = rimd::SMFFormat::MultiTrack;
+        }
+        self.modified = true;
+        Ok(count)
+    }
+
+    /// Midi messages of all tracks paired with their time in seconds, in playback order.
+    /// Tempo changes are taken from track 0.
+    pub fn timed_midi_events(&self) -> Result<Vec<(f64, Vec<u8>)>> {
+        let mut events = Vec::new();
+        for i in 0..self.track_count() {
+            let track = self.events_abs_tick(i).unwrap();
+            events.extend(track.events().iter()
+                .filter_map(|ate| match &ate.track_event.event {
+                    rimd::Event::Midi(msg) => Some((ate.abs_time, msg.data.clone())),
+                    _ => None
+                }));
+        }
+        // note offs go first so that a note ending in one track does not cut
+        // the same note starting in another track at the same tick
+        events.sort_by_key(|(abs, data)| (*abs, util::note_off_bytes(data).is_none()));
+
+        let tempo = self.tempo_map()?;
+        let division = self.division();
+        Ok(events.into_iter()
+            .map(|(abs, data)| (division.seconds_at(abs, &tempo), data))
+            .collect())
     }
 
     // returns (track_number, track_description)
@@ -491,29 +993,51 @@ impl MidiWorkspace {
             .collect()
     }
 
-    pub fn write_all<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+    pub fn write_all<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
+        if let rimd::SMFFormat::Single = self.midi.format {
+            if self.track_count() != 1 {
+                return Err(Error::InvalidArgument(format!("a format 0 file has one track, not {}", self.track_count())));
+            }
+        }
         let mut smf_c = self.clone();
         smf_c.finalize();
-        let smf_writer = rimd::SMFWriter::from_smf(self.midi.clone());
-        smf_writer.write_all(writer)
+        writer::write_smf(&smf_c.midi, writer)
+    }
+
+    /// writes the workspace to `path` through a temporary file (see `save::write_atomic`),
+    /// so that a failed save never leaves a broken file behind.
+    /// With `backup`, the file being replaced is kept as `save::backup_path(path)`.
+    pub fn save(&mut self, path: impl AsRef<Path>, backup: bool) -> Result<()> {
+        let path = path.as_ref();
+        save::write_atomic(path, backup, |writer| self.write_all(writer))?;
+        self.path = Some(path.to_path_buf());
+        self.modified = false;
+        Ok(())
     }
 
     /// Create a new MidiWorkspace with two tracks.
     /// Track 0 for tempo changes and time signatures
     /// Track 1 for notes
     pub fn empty() -> Self {
+        Self::empty_with_resolution(DEFAULT_RESOLUTION)
+    }
+
+    /// same as `empty` with `resolution` ticks per quarter note.
+    pub fn empty_with_resolution(resolution: u16) -> Self {
         let mut smf = rimd::SMFBuilder::new();
         smf.add_track();
         smf.add_track();
         smf.add_meta_abs(0, 0, rimd::MetaEvent::time_signature(4, 2, 24, 8));
-        smf.add_meta_abs(0, 0, rimd::MetaEvent::tempo_setting(120));
+        smf.add_meta_abs(0, 0, rimd::MetaEvent::tempo_setting(DEFAULT_TEMPO));
         smf.add_meta_rel(0, 0, rimd::MetaEvent::end_of_track());
         smf.add_meta_rel(1, 0, rimd::MetaEvent::end_of_track());
         let mut smf = smf.result();
-        smf.division = 480;
+        smf.division = resolution as i16;
 
         MidiWorkspace {
             midi: smf,
+            path: None,
+            modified: false,
         }
     }
 
@@ -546,3 +1070,143 @@ fn test_note_append() {
 
     println!("{:#?}", &abs);
 }
+
+#[test]
+fn note_off_sorts_before_note_on() {
+    let ws = MidiWorkspace::empty();
+    let mut abs = ws.events_abs_tick(1).unwrap();
+    abs.append_notes(vec![(480, 60, 100, 0), (480, 62, 0, 0), (0, 62, 100, 0), (480, 64, 100, 0)]);
+    abs.clean();
+
+    let events: Vec<(u64, String)> = abs.events().iter()
+        .map(|e| {
+            let (r#type, data) = util::describe_event(&e.track_event.event);
+            (e.abs_time, format!("{} {}", r#type, data))
+        })
+        .collect();
+    assert_eq!(events, vec![
+        (0, String::from("note on 62 100")),
+        (480, String::from("note off 62")),
+        (480, String::from("note on 60 100")),
+        (480, String::from("note on 64 100")),
+        (480, String::from("EndOfTrack []")),
+    ]);
+    let vtimes: Vec<u64> = abs.events().iter().map(|e| e.track_event.vtime).collect();
+    assert_eq!(vtimes, vec![0, 480, 0, 0, 0]);
+}
+
+#[test]
+fn malformed_meta_is_an_error() {
+    let mut ws = MidiWorkspace::empty();
+    let bad_tempo = rimd::TrackEvent {
+        vtime: 10,
+        event: rimd::Event::Meta(rimd::MetaEvent { command: rimd::MetaCommand::TempoSetting, length: 2, data: vec![7, 161] })
+    };
+    let mut events = ws.events(0).unwrap();
+    events.insert(0, bad_tempo);
+    ws.replace_events(0, events).unwrap();
+
+    match ws.create_tempo_info(0) {
+        Err(Error::MalformedMeta { command: rimd::MetaCommand::TempoSetting, abs_tick: 10, .. }) => {},
+        other => panic!("unexpected {:?}", other)
+    }
+    assert!(ws.timed_midi_events().is_err());
+    match ws.create_time_signature_info(5) {
+        Err(Error::NoSuchTrack(5)) => {},
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[test]
+fn format_0_is_kept_and_converted() {
+    let midi_events = |ws: &MidiWorkspace| (0..ws.track_count())
+        .map(|i| ws.events(i).unwrap().iter().filter(|e| matches!(e.event, rimd::Event::Midi(_))).count())
+        .sum::<usize>();
+
+    let mut ws = MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    assert!(matches!(ws.format(), rimd::SMFFormat::MultiTrack));
+    let count = midi_events(&ws);
+
+    ws.convert_format(rimd::SMFFormat::Single).unwrap();
+    assert_eq!(ws.track_count(), 1);
+    assert_eq!(midi_events(&ws), count);
+
+    // saved and opened again as format 0
+    let mut bytes = Vec::new();
+    ws.write_all(&mut bytes).unwrap();
+    assert_eq!(&bytes[8..10], &[0, 0]);
+    let mut ws = MidiWorkspace::from_smf(reader::read_lenient(&bytes).unwrap().0).unwrap();
+    assert!(matches!(ws.format(), rimd::SMFFormat::Single));
+
+    ws.convert_format(rimd::SMFFormat::MultiTrack).unwrap();
+    assert!(ws.track_count() > 1);
+    assert_eq!(midi_events(&ws), count);
+}
+
+#[test]
+fn smpte_conversion_keeps_timing() {
+    use division::SmpteFps;
+    let mut ws = MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    let before = ws.timed_midi_events().unwrap();
+
+    ws.convert_division(Division::Smpte(SmpteFps::Fps25, 40)).unwrap();
+    assert_eq!(ws.division(), Division::Smpte(SmpteFps::Fps25, 40));
+    let smpte = ws.timed_midi_events().unwrap();
+    assert_eq!(smpte.len(), before.len());
+    for ((a, _), (b, _)) in before.iter().zip(&smpte) {
+        // one tick is a millisecond
+        assert!((a - b).abs() <= 0.0005 + 1e-9, "{} != {}", a, b);
+    }
+    assert_eq!(ws.timecode(1000 * 61 + 40 * 3), Some(String::from("00:01:01:03")));
+
+    ws.convert_division(Division::TicksPerQuarter(960)).unwrap();
+    let ppq = ws.timed_midi_events().unwrap();
+    for ((a, _), (b, _)) in before.iter().zip(&ppq) {
+        assert!((a - b).abs() < 0.001, "{} != {}", a, b);
+    }
+}
+
+#[test]
+fn set_resolution_reports_rounding() {
+    let mut ws = MidiWorkspace::empty();
+    let mut track = ws.events_abs_tick(1).unwrap();
+    track.append_notes(vec![(480, 60, 100, 0), (960, 60, 0, 0), (961, 62, 100, 0), (962, 62, 0, 0)]);
+    track.clean();
+    ws.replace_events(1, track.into()).unwrap();
+
+    let report = ws.set_resolution(96).unwrap();
+    assert_eq!(ws.resolution(), 96);
+    // 961 and 962 both fall between ticks, and end up on the same one
+    assert_eq!(report.rounded.len(), 2);
+    assert_eq!(report.lengthened_notes, 1);
+    let ticks: Vec<u64> = ws.events_abs_tick(1).unwrap().events().iter().map(|e| e.abs_time).collect();
+    assert_eq!(ticks, vec![96, 192, 192, 193, 193]);
+
+    let report = ws.set_resolution(960).unwrap();
+    assert!(report.is_exact());
+    let ticks: Vec<u64> = ws.events_abs_tick(1).unwrap().events().iter().map(|e| e.abs_time).collect();
+    assert_eq!(ticks, vec![960, 1920, 1920, 1930, 1930]);
+}
+
+#[test]
+fn save_writes_finalized_tracks() {
+    let mut ws = MidiWorkspace::empty();
+    assert!(!ws.is_modified());
+    // a note after the end of track, as left behind by an edit
+    let mut events = ws.events(1).unwrap();
+    events.push(rimd::TrackEvent { vtime: 480, event: rimd::Event::Midi(rimd::MidiMessage::note_on(60, 100, 0)) });
+    ws.replace_events(1, events).unwrap();
+    assert!(ws.is_modified());
+
+    let path = std::env::temp_dir().join(format!("midie-finalize-{}.mid", std::process::id()));
+    ws.save(&path, false).unwrap();
+    assert!(!ws.is_modified());
+    assert_eq!(ws.path(), Some(path.as_path()));
+
+    let saved = MidiWorkspace::from_smf_file(&path).unwrap();
+    let track = saved.events_abs_tick(1).unwrap();
+    let last = track.events().last().unwrap();
+    assert!(last.is_end_of_track());
+    assert_eq!(last.abs_time, 480);
+    std::fs::remove_file(&path).unwrap();
+}
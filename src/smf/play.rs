@@ -1,7 +1,64 @@
 //! Cross platform midi playback.
 
 use midir::{MidiOutput, MidiOutputConnection, MidiOutputPort};
-use std::sync::mpsc::{Receiver, Sender};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+use super::util;
+use super::MidiWorkspace;
+use super::synth::SynthDeviceSink;
+use crate::error::{Error, Result};
+
+/// Name of the output port midie creates when a virtual port is selected.
+pub const VIRTUAL_PORT_NAME: &str = "midie virtual output";
+
+/// index of the port called `name`. Failing that, the only port whose name differs just in
+/// the trailing ALSA `client:port` numbers, which change between runs. None if that is ambiguous.
+pub fn find_port(port_names: &[String], name: &str) -> Option<usize> {
+    if let Some(i) = port_names.iter().position(|p| p == name) {
+        return Some(i);
+    }
+    let name = without_client_port(name);
+    let mut matching = port_names.iter().enumerate().filter(|(_, p)| without_client_port(p) == name);
+    match (matching.next(), matching.next()) {
+        (Some((i, _)), None) => Some(i),
+        _ => None
+    }
+}
+
+/// `name` without a trailing ` 128:0` as ALSA appends it
+fn without_client_port(name: &str) -> &str {
+    let numbers = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    match name.rsplit_once(' ') {
+        Some((rest, suffix)) if matches!(suffix.split_once(':'), Some((client, port)) if numbers(client) && numbers(port)) => rest,
+        _ => name
+    }
+}
+
+/// What a `MidiReceiver` plays to, as shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputStatus {
+    /// no output is open
+    Disconnected,
+    Connected(String),
+    /// the port is gone, so the built-in synth plays until it is back
+    Fallback(String),
+    /// the port is gone and nothing plays until it is back
+    Missing(String),
+}
+
+impl std::fmt::Display for OutputStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OutputStatus::Disconnected => write!(f, "No midi output"),
+            OutputStatus::Connected(name) => write!(f, "Playing to {}", name),
+            OutputStatus::Fallback(name) => write!(f, "{} is not connected, playing to the built-in synth", name),
+            OutputStatus::Missing(name) => write!(f, "Waiting for {} to be connected", name),
+        }
+    }
+}
 
 pub struct MidiProber {
     #[allow(dead_code)]
@@ -10,7 +67,7 @@ pub struct MidiProber {
 }
 
 impl MidiProber {
-    pub fn new(client_name: impl Into<String>) -> Result<Self, midir::InitError> {
+    pub fn new(client_name: impl Into<String>) -> Result<Self> {
         let client_name = client_name.into();
         Ok(MidiProber {
             client_name: client_name.clone(),
@@ -22,14 +79,28 @@ impl MidiProber {
         self.midi_output.ports()
     }
 
-    pub fn create_midi_player(self, port_number: usize, port_name: &str) -> Result<MidiPlayer, String> {
+    pub fn create_midi_player(self, port_number: usize, port_name: &str) -> Result<MidiPlayer> {
         let ports = self.midi_output.ports();
-        let port = ports.get(port_number).ok_or_else(|| String::from("no such midi port"))?;
+        let port = ports.get(port_number).ok_or_else(|| Error::Port(format!("no such midi port: {}", port_number)))?;
         MidiPlayer::with_port(self.midi_output, port, port_name)
     }
 
-    pub fn port_name(&self, port: &MidiOutputPort) -> Result<String, midir::PortInfoError> {
-        self.midi_output.port_name(port)
+    /// creates a new output port which other applications can subscribe to,
+    /// instead of connecting to an existing one.
+    #[cfg(unix)]
+    pub fn create_virtual_midi_player(self, port_name: &str) -> Result<MidiPlayer> {
+        MidiPlayer::with_virtual_port(self.midi_output, port_name)
+    }
+
+    pub fn port_name(&self, port: &MidiOutputPort) -> Result<String> {
+        self.midi_output.port_name(port).map_err(|e| Error::Port(e.to_string()))
+    }
+
+    /// names of the output ports, in port number order
+    pub fn port_names(&self) -> Vec<String> {
+        self.list_ports().iter()
+            .map(|port| self.port_name(port).unwrap_or_else(|_| String::from("port name unknown")))
+            .collect()
     }
 }
 
@@ -40,11 +111,21 @@ pub struct MidiPlayer {
 }
 
 impl MidiPlayer {
-    fn with_port(client: MidiOutput, port: &MidiOutputPort, port_name: impl Into<String>) -> Result<Self, String> {
+    fn with_port(client: MidiOutput, port: &MidiOutputPort, port_name: impl Into<String>) -> Result<Self> {
         let port_name = port_name.into();
         Ok(MidiPlayer {
             port_name: port_name.clone(),
-            connection: client.connect(port, &port_name).map_err(|e| e.to_string())?
+            connection: client.connect(port, &port_name).map_err(|e| Error::Port(e.to_string()))?
+        })
+    }
+
+    #[cfg(unix)]
+    fn with_virtual_port(client: MidiOutput, port_name: impl Into<String>) -> Result<Self> {
+        use midir::os::unix::VirtualOutput;
+        let port_name = port_name.into();
+        Ok(MidiPlayer {
+            port_name: port_name.clone(),
+            connection: client.create_virtual(&port_name).map_err(|e| Error::Port(e.to_string()))?
         })
     }
 
@@ -54,68 +135,531 @@ impl MidiPlayer {
         self.connection.close();
     }
 
-    pub fn send(&mut self, message: &[u8]) -> Result<(), midir::SendError> {
-        self.connection.send(message)
+    pub fn send(&mut self, message: &[u8]) -> Result<()> {
+        Ok(self.connection.send(message)?)
+    }
+}
+
+/// An output midi bytes can be sent to, e.g. a hardware port or the built-in synth.
+pub trait MidiSink {
+    fn send(&mut self, message: &[u8]) -> Result<()>;
+
+    /// called right before the sink is disposed of.
+    fn close(self: Box<Self>) {}
+}
+
+impl MidiSink for MidiPlayer {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        MidiPlayer::send(self, message)
+    }
+
+    fn close(self: Box<Self>) {
+        MidiPlayer::close(*self)
+    }
+}
+
+impl MidiSink for Box<dyn MidiSink> {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        (**self).send(message)
+    }
+
+    fn close(self: Box<Self>) {
+        <dyn MidiSink as MidiSink>::close(*self)
+    }
+}
+
+/// Sinks a `MidiReceiver` can switch to on `ChangePort`, `ConnectPort`, `VirtualPort` and `BuiltinSynth`.
+pub trait OpenSink: MidiSink + Sized {
+    fn open_port(port_number: usize) -> Result<Self>;
+    /// opens the port `find_port` finds for `name`
+    fn open_named_port(name: &str) -> Result<Self>;
+    fn open_virtual_port(port_name: &str) -> Result<Self>;
+    fn open_builtin_synth() -> Result<Self>;
+}
+
+impl OpenSink for Box<dyn MidiSink> {
+    fn open_port(port_number: usize) -> Result<Self> {
+        let mb = MidiProber::new("midie")?;
+        Ok(Box::new(mb.create_midi_player(port_number, "midie output")?))
+    }
+
+    fn open_named_port(name: &str) -> Result<Self> {
+        let mb = MidiProber::new("midie")?;
+        let port_number = find_port(&mb.port_names(), name)
+            .ok_or_else(|| Error::Port(format!("midi output {} is not connected", name)))?;
+        Ok(Box::new(mb.create_midi_player(port_number, "midie output")?))
+    }
+
+    #[cfg(unix)]
+    fn open_virtual_port(port_name: &str) -> Result<Self> {
+        let mb = MidiProber::new("midie")?;
+        Ok(Box::new(mb.create_virtual_midi_player(port_name)?))
+    }
+
+    #[cfg(not(unix))]
+    fn open_virtual_port(port_name: &str) -> Result<Self> {
+        Err(Error::Port(format!("cannot create virtual port {}: not supported on this platform", port_name)))
+    }
+
+    fn open_builtin_synth() -> Result<Self> {
+        Ok(Box::new(SynthDeviceSink::new()?))
+    }
+}
+
+/// A message sent to a `RecordingSink`, and when it was sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedMessage {
+    pub at: Instant,
+    pub data: Vec<u8>,
+}
+
+/// Keeps everything sent to it in memory. Clones share the same recording,
+/// so one clone can be handed to a `MidiReceiver` while another is inspected.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    messages: Arc<Mutex<Vec<RecordedMessage>>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages(&self) -> Vec<RecordedMessage> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    /// recorded midi bytes without timestamps
+    pub fn data(&self) -> Vec<Vec<u8>> {
+        self.messages.lock().unwrap().iter().map(|m| m.data.clone()).collect()
+    }
+
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+}
+
+impl MidiSink for RecordingSink {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        let mut messages = self.messages.lock().map_err(|_| Error::Port(String::from("recording is poisoned")))?;
+        messages.push(RecordedMessage { at: Instant::now(), data: message.to_vec() });
+        Ok(())
+    }
+}
+
+impl OpenSink for RecordingSink {
+    fn open_port(port_number: usize) -> Result<Self> {
+        Err(Error::Port(format!("cannot open port {} for a recording sink", port_number)))
+    }
+
+    fn open_named_port(name: &str) -> Result<Self> {
+        Err(Error::Port(format!("cannot open port {} for a recording sink", name)))
+    }
+
+    fn open_virtual_port(port_name: &str) -> Result<Self> {
+        Err(Error::Port(format!("cannot open virtual port {} for a recording sink", port_name)))
+    }
+
+    fn open_builtin_synth() -> Result<Self> {
+        Err(Error::Port(String::from("cannot open the built-in synth for a recording sink")))
     }
 }
 
-pub struct MidiReceiver {
-    player: Option<MidiPlayer>
+pub struct MidiReceiver<S: OpenSink = Box<dyn MidiSink>> {
+    sink: Option<S>,
+    sounding: SoundingNotes,
+    queue: ScheduleQueue,
+    errors: Option<Sender<Error>>,
+    /// the port chosen with `ConnectPort`, which is reconnected when it comes back
+    port: Option<String>,
+    status: OutputStatus,
+    statuses: Option<Sender<OutputStatus>>,
 }
 
+/// The receiver sleeps until this long before the next scheduled message
+/// and busy-waits for the rest, since thread wakeups are far too coarse.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
 impl MidiReceiver {
+    /// Runs the timing loop on the current thread until every sender is dropped.
+    /// Nothing is played until an output is chosen with `ChangePort`, `VirtualPort` or `BuiltinSynth`.
     pub fn start(rx: Receiver<MidiMessage>) {
-        let mut receiver = Self::new();
+        MidiReceiver::<Box<dyn MidiSink>>::new(None).run(rx);
+    }
+
+    /// same as `start`, but outputs which fail to open are also sent to `errors`
+    /// so that they can be shown to the user.
+    pub fn start_with_errors(rx: Receiver<MidiMessage>, errors: Sender<Error>) {
+        MidiReceiver::<Box<dyn MidiSink>>::new(None).report_errors(errors).run(rx);
+    }
+
+    /// same as `start_with_errors`, and every change of the output is sent to `statuses`.
+    pub fn start_with_status(rx: Receiver<MidiMessage>, errors: Sender<Error>, statuses: Sender<OutputStatus>) {
+        MidiReceiver::<Box<dyn MidiSink>>::new(None).report_errors(errors).report_status(statuses).run(rx);
+    }
+}
+
+impl<S: OpenSink> MidiReceiver<S> {
+    pub fn new(sink: Option<S>) -> Self {
+        MidiReceiver {
+            sink,
+            sounding: SoundingNotes::default(),
+            queue: ScheduleQueue::default(),
+            errors: None,
+            port: None,
+            status: OutputStatus::Disconnected,
+            statuses: None,
+        }
+    }
+
+    /// sends errors which would otherwise only be logged to `errors`.
+    pub fn report_errors(mut self, errors: Sender<Error>) -> Self {
+        self.errors = Some(errors);
+        self
+    }
+
+    /// sends the status of the output to `statuses` whenever it changes.
+    pub fn report_status(mut self, statuses: Sender<OutputStatus>) -> Self {
+        self.statuses = Some(statuses);
+        self
+    }
+
+    pub fn status(&self) -> &OutputStatus {
+        &self.status
+    }
+
+    /// Runs the timing loop on the current thread until every sender is dropped.
+    pub fn run(mut self, rx: Receiver<MidiMessage>) {
         debug!("midi receiver start");
-        while let Ok(msg) = rx.recv() {
-            match msg {
-                MidiMessage::ChangePort(port_number) => {
-                    receiver.change_port(port_number);
-                },
-                MidiMessage::Close => {
-                    receiver.player = None;
+        loop {
+            let msg = match self.queue.next_deadline() {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline <= now {
+                        self.dispatch_due(now);
+                        continue;
+                    }
+                    let wait = deadline - now;
+                    if wait <= SPIN_THRESHOLD {
+                        while Instant::now() < deadline {
+                            std::hint::spin_loop();
+                        }
+                        continue;
+                    }
+                    match rx.recv_timeout(wait - SPIN_THRESHOLD) {
+                        Ok(msg) => msg,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break
+                    }
                 },
-                MidiMessage::Midi(ref midi_msg) => {
-                    receiver.send_message(midi_msg);
+                None => match rx.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break
                 }
+            };
+            self.handle(msg);
+        }
+        // every sender is gone; do not leave anything hanging on the device
+        self.release_sounding_notes();
+        self.replace_sink(None);
+        debug!("midi receiver stop");
+    }
+
+    pub fn handle(&mut self, msg: MidiMessage) {
+        match msg {
+            MidiMessage::ChangePort(port_number) => {
+                self.port = None;
+                let name = format!("port {}", port_number);
+                self.switch_sink(|| S::open_port(port_number), name);
+            },
+            MidiMessage::ConnectPort(name) => {
+                self.port = Some(name);
+                self.connect_port();
+            },
+            MidiMessage::PortsChanged(port_names) => {
+                self.ports_changed(&port_names);
+            },
+            MidiMessage::VirtualPort(ref port_name) => {
+                self.port = None;
+                self.switch_sink(|| S::open_virtual_port(port_name), port_name.clone());
+            },
+            MidiMessage::BuiltinSynth => {
+                self.port = None;
+                self.switch_sink(S::open_builtin_synth, String::from("the built-in synth"));
+            },
+            MidiMessage::Close => {
+                self.queue.clear();
+                self.release_sounding_notes();
+                self.replace_sink(None);
+                self.port = None;
+                self.set_status(OutputStatus::Disconnected);
+            },
+            MidiMessage::Stop => {
+                self.queue.clear();
+                self.release_sounding_notes();
+            },
+            MidiMessage::Panic => {
+                self.queue.clear();
+                self.panic();
+            },
+            MidiMessage::Midi(ref midi_msg) => {
+                self.send_message(midi_msg);
+            },
+            MidiMessage::MidiAt(at, midi_msg) => {
+                self.queue.push(at, midi_msg);
             }
         }
     }
 
-    fn new() -> Self {
-        MidiReceiver {
-            player: None
+    /// sends every queued message whose time has come.
+    pub fn dispatch_due(&mut self, now: Instant) {
+        while let Some(data) = self.queue.pop_due(now) {
+            self.send_message(&data);
+        }
+    }
+
+    /// closes the current sink and switches to the new one.
+    fn replace_sink(&mut self, sink: Option<S>) {
+        if let Some(old) = self.sink.take() {
+            Box::new(old).close();
         }
+        self.sink = sink;
     }
 
-    fn change_port(&mut self, port_number: usize) {
-        match MidiProber::new("midie") {
-            Ok(mb) => match mb.create_midi_player(port_number, "midie output") {
-                Ok(p) => self.player = Some(p),
-                Err(e) => error!("{}", e)
+    /// releases the notes of the current sink, then opens a new one called `name`.
+    fn switch_sink<F: FnOnce() -> Result<S>>(&mut self, open: F, name: String) {
+        self.release_sounding_notes();
+        self.replace_sink(None);
+        match open() {
+            Ok(sink) => {
+                self.sink = Some(sink);
+                self.set_status(OutputStatus::Connected(name));
             },
-            Err(e) => error!("{}", e)
+            Err(e) => {
+                self.report(e);
+                self.set_status(OutputStatus::Disconnected);
+            }
+        }
+    }
+
+    /// opens the port chosen with `ConnectPort`, or the built-in synth while it is not there.
+    fn connect_port(&mut self) {
+        let name = match self.port.clone() {
+            Some(name) => name,
+            None => return
+        };
+        self.release_sounding_notes();
+        self.replace_sink(None);
+        match S::open_named_port(&name) {
+            Ok(sink) => {
+                self.sink = Some(sink);
+                self.set_status(OutputStatus::Connected(name));
+            },
+            Err(e) => {
+                warn!("{}", e);
+                match S::open_builtin_synth() {
+                    Ok(sink) => {
+                        self.sink = Some(sink);
+                        self.set_status(OutputStatus::Fallback(name));
+                    },
+                    Err(e) => {
+                        self.report(e);
+                        self.set_status(OutputStatus::Missing(name));
+                    }
+                }
+            }
+        }
+    }
+
+    /// reconnects when the port chosen with `ConnectPort` was unplugged or plugged back in.
+    fn ports_changed(&mut self, port_names: &[String]) {
+        let name = match &self.port {
+            Some(name) => name,
+            None => return
+        };
+        let available = find_port(port_names, name).is_some();
+        let connected = matches!(self.status, OutputStatus::Connected(_));
+        if available != connected {
+            debug!("midi output {} was {}", name, if available { "plugged in" } else { "unplugged" });
+            self.connect_port();
+        }
+    }
+
+    fn set_status(&mut self, status: OutputStatus) {
+        if status == self.status {
+            return;
+        }
+        self.status = status;
+        if let Some(statuses) = &self.statuses {
+            let _ = statuses.send(self.status.clone());
+        }
+    }
+
+    fn report(&self, e: Error) {
+        error!("{}", e);
+        if let Some(errors) = &self.errors {
+            // nobody listening any more is not an error of its own
+            let _ = errors.send(e);
         }
     }
 
     fn send_message(&mut self, data: &[u8]) {
-        if let Some(p) = self.player.as_mut() {
-            match p.send(data) {
+        self.sounding.update(data);
+        self.send_raw(data);
+    }
+
+    fn send_raw(&mut self, data: &[u8]) {
+        if let Some(s) = self.sink.as_mut() {
+            match s.send(data) {
                 Ok(_) => {},
                 Err(e) => warn!("midi send error: {}", e)
             }
         }
     }
+
+    /// sends a note off for every note which is still sounding.
+    fn release_sounding_notes(&mut self) {
+        for msg in self.sounding.drain_note_offs() {
+            self.send_raw(&msg);
+        }
+    }
+
+    /// releases every sounding note, then sends All Sound Off, All Notes Off
+    /// and Reset All Controllers on all 16 channels.
+    fn panic(&mut self) {
+        debug!("midi panic");
+        self.release_sounding_notes();
+        for ch in 0..16 {
+            for &controller in &[CC_ALL_SOUND_OFF, CC_ALL_NOTES_OFF, CC_RESET_ALL_CONTROLLERS] {
+                let msg = rimd::MidiMessage::control_change(controller, 0, ch);
+                self.send_raw(&msg.data);
+            }
+        }
+    }
+}
+
+const CC_ALL_SOUND_OFF: u8 = 120;
+const CC_RESET_ALL_CONTROLLERS: u8 = 121;
+const CC_ALL_NOTES_OFF: u8 = 123;
+
+/// Notes which have been turned on but not yet turned off, per channel.
+#[derive(Debug, Clone)]
+struct SoundingNotes {
+    notes: Vec<HashSet<u8>>,
+}
+
+impl Default for SoundingNotes {
+    fn default() -> Self {
+        SoundingNotes {
+            notes: vec![HashSet::new(); 16]
+        }
+    }
+}
+
+impl SoundingNotes {
+    fn update(&mut self, data: &[u8]) {
+        if let Some((ch, note, _)) = util::note_on_bytes(data) {
+            self.notes[ch as usize].insert(note);
+        } else if let Some((ch, note, _)) = util::note_off_bytes(data) {
+            self.notes[ch as usize].remove(&note);
+        }
+    }
+
+    /// returns note off messages for all sounding notes and forgets them.
+    fn drain_note_offs(&mut self) -> Vec<Vec<u8>> {
+        let mut msgs = Vec::new();
+        for (ch, notes) in self.notes.iter_mut().enumerate() {
+            for note in notes.drain() {
+                msgs.push(rimd::MidiMessage::note_off(note, 0, ch as u8).data);
+            }
+        }
+        msgs
+    }
+}
+
+/// Messages which play every track of the workspace through a `MidiReceiver`, starting at `start`.
+pub fn playback_messages(ws: &MidiWorkspace, start: Instant) -> Result<Vec<MidiMessage>> {
+    Ok(ws.timed_midi_events()?.into_iter()
+        .map(|(secs, data)| MidiMessage::MidiAt(start + Duration::from_secs_f64(secs), data))
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ScheduledMessage {
+    at: Instant,
+    // insertion order, so that messages for the same instant keep their order
+    seq: u64,
+    data: Vec<u8>,
+}
+
+impl Ord for ScheduledMessage {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // reversed so that BinaryHeap pops the earliest message first
+        other.at.cmp(&self.at).then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for ScheduledMessage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Messages waiting for their timestamp, earliest first.
+#[derive(Debug, Default)]
+struct ScheduleQueue {
+    heap: BinaryHeap<ScheduledMessage>,
+    next_seq: u64,
+}
+
+impl ScheduleQueue {
+    fn push(&mut self, at: Instant, data: Vec<u8>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.heap.push(ScheduledMessage { at, seq, data });
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|m| m.at)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.heap.peek() {
+            Some(m) if m.at <= now => self.heap.pop().map(|m| m.data),
+            _ => None
+        }
+    }
+
+    fn clear(&mut self) {
+        self.heap.clear();
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MidiMessage {
     ChangePort(usize),
+    /// open the port with this name (see `find_port`), and keep reconnecting to it
+    /// as it is unplugged and plugged back in
+    ConnectPort(String),
+    /// the names of the output ports there are now
+    PortsChanged(Vec<String>),
+    /// open a virtual output port with the given name (unix only)
+    VirtualPort(String),
+    /// play through the built-in synth on the default audio device
+    BuiltinSynth,
+    /// send right away
     Midi(Vec<u8>),
+    /// send at the given time
+    MidiAt(Instant, Vec<u8>),
+    /// turn off every note sent so far and drop everything still scheduled
+    Stop,
+    /// like `Stop`, and also reset all controllers on all channels
+    Panic,
     Close
 }
 
 #[test]
+#[ignore] // needs a midi output device on port 0
 fn open_port_twice() {
     let mb = MidiProber::new("midie").unwrap();
     let _ = mb.list_ports();
@@ -127,3 +671,243 @@ fn open_port_twice() {
     let p = mb.create_midi_player(0, "p1").unwrap();
     p.close();
 }
+
+#[test]
+fn sounding_notes_released() {
+    let mut sounding = SoundingNotes::default();
+    sounding.update(&rimd::MidiMessage::note_on(60, 100, 0).data);
+    sounding.update(&rimd::MidiMessage::note_on(64, 100, 9).data);
+    sounding.update(&rimd::MidiMessage::note_on(67, 100, 0).data);
+    sounding.update(&rimd::MidiMessage::note_off(67, 0, 0).data);
+
+    let mut offs = sounding.drain_note_offs();
+    offs.sort();
+    assert_eq!(offs, vec![vec![0x80, 60, 0], vec![0x89, 64, 0]]);
+    assert!(sounding.drain_note_offs().is_empty());
+}
+
+#[test]
+fn schedule_queue_order() {
+    let now = Instant::now();
+    let mut queue = ScheduleQueue::default();
+    queue.push(now + Duration::from_millis(20), vec![3]);
+    queue.push(now + Duration::from_millis(10), vec![1]);
+    queue.push(now + Duration::from_millis(10), vec![2]);
+
+    assert_eq!(queue.next_deadline(), Some(now + Duration::from_millis(10)));
+    assert_eq!(queue.pop_due(now), None);
+    let later = now + Duration::from_millis(15);
+    assert_eq!(queue.pop_due(later), Some(vec![1]));
+    assert_eq!(queue.pop_due(later), Some(vec![2]));
+    assert_eq!(queue.pop_due(later), None);
+    assert_eq!(queue.pop_due(now + Duration::from_millis(20)), Some(vec![3]));
+}
+
+#[test]
+fn panic_releases_notes_and_resets_channels() {
+    let sink = RecordingSink::new();
+    let mut receiver = MidiReceiver::new(Some(sink.clone()));
+    receiver.handle(MidiMessage::Midi(vec![0x91, 60, 100]));
+    receiver.handle(MidiMessage::Panic);
+
+    let data = sink.data();
+    assert_eq!(data.len(), 2 + 16 * 3);
+    assert_eq!(data[0], vec![0x91, 60, 100]);
+    assert_eq!(data[1], vec![0x81, 60, 0]);
+    assert_eq!(data[2], vec![0xB0, CC_ALL_SOUND_OFF, 0]);
+    assert_eq!(data[3], vec![0xB0, CC_ALL_NOTES_OFF, 0]);
+    assert_eq!(data[4], vec![0xB0, CC_RESET_ALL_CONTROLLERS, 0]);
+    assert_eq!(data.last().unwrap(), &vec![0xBF, CC_RESET_ALL_CONTROLLERS, 0]);
+}
+
+#[test]
+fn preview_note_off_is_scheduled() {
+    let sink = RecordingSink::new();
+    let mut receiver = MidiReceiver::new(Some(sink.clone()));
+    let now = Instant::now();
+    let off_at = now + Duration::from_secs(1);
+    receiver.handle(MidiMessage::Midi(vec![0x90, 64, 100]));
+    receiver.handle(MidiMessage::MidiAt(off_at, vec![0x80, 64, 0]));
+
+    receiver.dispatch_due(now + Duration::from_millis(999));
+    assert_eq!(sink.data(), vec![vec![0x90, 64, 100]]);
+    receiver.dispatch_due(off_at);
+    assert_eq!(sink.data(), vec![vec![0x90, 64, 100], vec![0x80, 64, 0]]);
+}
+
+#[test]
+fn stop_drops_scheduled_and_releases() {
+    let sink = RecordingSink::new();
+    let mut receiver = MidiReceiver::new(Some(sink.clone()));
+    let now = Instant::now();
+    receiver.handle(MidiMessage::Midi(vec![0x90, 64, 100]));
+    receiver.handle(MidiMessage::MidiAt(now + Duration::from_millis(10), vec![0x80, 64, 0]));
+    receiver.handle(MidiMessage::MidiAt(now + Duration::from_millis(20), vec![0x90, 65, 100]));
+    receiver.handle(MidiMessage::Stop);
+    receiver.dispatch_due(now + Duration::from_secs(1));
+
+    assert_eq!(sink.data(), vec![vec![0x90, 64, 100], vec![0x80, 64, 0]]);
+}
+
+#[test]
+fn port_change_releases_notes() {
+    let sink = RecordingSink::new();
+    let mut receiver = MidiReceiver::new(Some(sink.clone()));
+    receiver.handle(MidiMessage::Midi(vec![0x90, 64, 100]));
+    // a recording sink cannot be switched, so nothing is sent afterwards
+    receiver.handle(MidiMessage::ChangePort(1));
+    receiver.handle(MidiMessage::Midi(vec![0x90, 65, 100]));
+
+    assert_eq!(sink.data(), vec![vec![0x90, 64, 100], vec![0x80, 64, 0]]);
+}
+
+#[test]
+fn playback_through_receiver_thread() {
+    let ws = MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    let expected: Vec<Vec<u8>> = ws.timed_midi_events().unwrap().into_iter()
+        .take_while(|(secs, _)| *secs < 0.5)
+        .map(|(_, data)| data)
+        .collect();
+
+    let sink = RecordingSink::new();
+    let (tx, rx) = std::sync::mpsc::channel();
+    let receiver = MidiReceiver::new(Some(sink.clone()));
+    let handle = std::thread::spawn(move || receiver.run(rx));
+
+    let start = Instant::now() + Duration::from_millis(10);
+    let messages: Vec<MidiMessage> = playback_messages(&ws, start).unwrap().into_iter()
+        .take(expected.len())
+        .collect();
+    for msg in messages.iter().cloned() {
+        tx.send(msg).unwrap();
+    }
+    // however slow the machine is, until everything was played
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while sink.messages().len() < expected.len() {
+        assert!(Instant::now() < deadline, "only {} of {} messages were played", sink.messages().len(), expected.len());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(tx);
+    handle.join().unwrap();
+
+    let recorded = sink.messages();
+    assert_eq!(recorded.iter().take(expected.len()).map(|m| m.data.clone()).collect::<Vec<_>>(), expected);
+    for (msg, rec) in messages.iter().zip(recorded.iter()) {
+        match msg {
+            MidiMessage::MidiAt(at, _) => assert!(rec.at >= *at),
+            _ => unreachable!()
+        }
+    }
+}
+
+#[test]
+fn failed_port_is_reported() {
+    let (errors_tx, errors_rx) = std::sync::mpsc::channel();
+    let mut receiver = MidiReceiver::new(Some(RecordingSink::new())).report_errors(errors_tx);
+    receiver.handle(MidiMessage::ChangePort(3));
+    match errors_rx.try_recv() {
+        Ok(Error::Port(_)) => {},
+        other => panic!("unexpected {:?}", other)
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static PLUGGED: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// A sink for ports in `PLUGGED`, or the built-in synth.
+#[cfg(test)]
+struct PluggableSink(String);
+
+#[cfg(test)]
+impl MidiSink for PluggableSink {
+    fn send(&mut self, _message: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl OpenSink for PluggableSink {
+    fn open_port(port_number: usize) -> Result<Self> {
+        PLUGGED.with(|p| p.borrow().get(port_number).cloned())
+            .map(PluggableSink)
+            .ok_or_else(|| Error::Port(format!("no such midi port: {}", port_number)))
+    }
+
+    fn open_named_port(name: &str) -> Result<Self> {
+        let port_number = PLUGGED.with(|p| find_port(&p.borrow(), name))
+            .ok_or_else(|| Error::Port(format!("midi output {} is not connected", name)))?;
+        Self::open_port(port_number)
+    }
+
+    fn open_virtual_port(port_name: &str) -> Result<Self> {
+        Ok(PluggableSink(String::from(port_name)))
+    }
+
+    fn open_builtin_synth() -> Result<Self> {
+        Ok(PluggableSink(String::from("synth")))
+    }
+}
+
+#[test]
+fn unplugged_port_is_reconnected_by_name() {
+    let plug = |names: &[&str]| {
+        let names: Vec<String> = names.iter().map(|n| String::from(*n)).collect();
+        PLUGGED.with(|p| *p.borrow_mut() = names.clone());
+        MidiMessage::PortsChanged(names)
+    };
+    let sink_name = |receiver: &MidiReceiver<PluggableSink>| receiver.sink.as_ref().map(|s| s.0.clone());
+    let (status_tx, status_rx) = std::sync::mpsc::channel();
+    let mut receiver = MidiReceiver::<PluggableSink>::new(None).report_status(status_tx);
+    let name = String::from("Keys:Keys MIDI 1 20:0");
+
+    receiver.handle(plug(&["Midi Through:Midi Through Port-0 14:0", &name]));
+    receiver.handle(MidiMessage::ConnectPort(name.clone()));
+    assert_eq!(receiver.status(), &OutputStatus::Connected(name.clone()));
+    assert_eq!(sink_name(&receiver), Some(name.clone()));
+
+    let unplugged = plug(&["Midi Through:Midi Through Port-0 14:0"]);
+    receiver.handle(unplugged);
+    assert_eq!(receiver.status(), &OutputStatus::Fallback(name.clone()));
+    assert_eq!(sink_name(&receiver).as_deref(), Some("synth"));
+
+    // plugged back in, now with another client number
+    receiver.handle(plug(&["Keys:Keys MIDI 1 24:0", "Midi Through:Midi Through Port-0 14:0"]));
+    assert_eq!(receiver.status(), &OutputStatus::Connected(name.clone()));
+    assert_eq!(sink_name(&receiver).as_deref(), Some("Keys:Keys MIDI 1 24:0"));
+    // the list changing otherwise does not reconnect
+    receiver.handle(plug(&["Keys:Keys MIDI 1 24:0"]));
+
+    receiver.handle(MidiMessage::BuiltinSynth);
+    receiver.handle(plug(&[]));
+    assert_eq!(sink_name(&receiver).as_deref(), Some("synth"));
+
+    let statuses: Vec<OutputStatus> = status_rx.try_iter().collect();
+    assert_eq!(statuses, vec![
+        OutputStatus::Connected(name.clone()),
+        OutputStatus::Fallback(name.clone()),
+        OutputStatus::Connected(name),
+        OutputStatus::Connected(String::from("the built-in synth")),
+    ]);
+}
+
+#[test]
+fn find_port_ignores_only_alsa_numbers() {
+    let ports: Vec<String> = vec!["Midi Through:Midi Through Port-0 14:0".into(), "FLUID Synth (1234):Synth input port (1234:0) 128:0".into(),
+        "Synth 1 20:0".into(), "Synth 2 24:0".into()];
+    assert_eq!(find_port(&ports, "Synth 2 24:0"), Some(3));
+    // client numbers change between runs
+    assert_eq!(find_port(&ports, "FLUID Synth (1234):Synth input port (1234:0) 129:0"), Some(1));
+    assert_eq!(find_port(&ports, "Midi Through:Midi Through Port-0 15:0"), Some(0));
+    // other numbers in the name are part of it
+    assert_eq!(find_port(&ports, "Synth 3 24:0"), None);
+    assert_eq!(find_port(&ports, "FLUID Synth (5678):Synth input port (5678:0) 128:0"), None);
+}
+
+#[test]
+fn find_port_refuses_ambiguous_names() {
+    let ports: Vec<String> = vec!["USB MIDI 20:0".into(), "USB MIDI 24:0".into()];
+    assert_eq!(find_port(&ports, "USB MIDI 24:0"), Some(1));
+    assert_eq!(find_port(&ports, "USB MIDI 28:0"), None);
+}
@@ -0,0 +1,433 @@
+//! A forgiving standard midi file reader.
+//!
+//! rimd rejects a file as soon as anything is off. This reader fixes what it can
+//! (bad chunk lengths, missing EndOfTrack, truncated tracks, stray running status,
+//! unknown chunks) and tells what it fixed, so that real-world files still open.
+
+use crate::error::{Error, Result};
+use rimd::{Event, MetaCommand, MetaEvent, MidiMessage, SMFFormat, Track, TrackEvent, SMF};
+use std::fmt;
+
+/// Something wrong with the file which the lenient reader worked around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadWarning {
+    /// the length in the chunk header does not match where the track actually ends
+    ChunkLength { track: usize, declared: usize, actual: usize },
+    /// the track has no EndOfTrack event; one was added after the last event
+    MissingEndOfTrack { track: usize },
+    /// the file ends, or a message is cut off, in the middle of the track.
+    /// Events from `offset` on are lost.
+    TruncatedTrack { track: usize, offset: usize },
+    /// data bytes without a status byte to go with them
+    StrayRunningStatus { track: usize, offset: usize },
+    /// a chunk which is not MTrk was skipped
+    UnknownChunk { id: String, offset: usize },
+    /// garbage between chunks was skipped
+    SkippedBytes { offset: usize, count: usize },
+    /// the header says there are `declared` tracks, but `found` were read
+    TrackCount { declared: usize, found: usize },
+    /// unknown format in the header; format 1 was assumed
+    UnknownFormat(u16),
+}
+
+impl fmt::Display for ReadWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadWarning::ChunkLength { track, declared, actual } =>
+                write!(f, "track {}: chunk length is {} bytes, but the track is {} bytes long", track, declared, actual),
+            ReadWarning::MissingEndOfTrack { track } =>
+                write!(f, "track {}: missing end of track", track),
+            ReadWarning::TruncatedTrack { track, offset } =>
+                write!(f, "track {}: truncated at byte {}", track, offset),
+            ReadWarning::StrayRunningStatus { track, offset } =>
+                write!(f, "track {}: data without a status byte at byte {}", track, offset),
+            ReadWarning::UnknownChunk { id, offset } =>
+                write!(f, "skipped unknown chunk {:?} at byte {}", id, offset),
+            ReadWarning::SkippedBytes { offset, count } =>
+                write!(f, "skipped {} bytes of garbage at byte {}", count, offset),
+            ReadWarning::TrackCount { declared, found } =>
+                write!(f, "header declares {} tracks, but {} were found", declared, found),
+            ReadWarning::UnknownFormat(format) =>
+                write!(f, "unknown format {}, read as format 1", format),
+        }
+    }
+}
+
+/// Reads a standard midi file, recovering from as many problems as possible.
+/// Only a missing or broken header makes it fail.
+pub fn read_lenient(bytes: &[u8]) -> Result<(SMF, Vec<ReadWarning>)> {
+    let mut warnings = Vec::new();
+    if bytes.len() < 14 || &bytes[0..4] != b"MThd" {
+        return Err(Error::Parse(String::from("no MThd header")));
+    }
+    let header_len = be_u32(&bytes[4..8]) as usize;
+    if header_len < 6 {
+        return Err(Error::Parse(format!("MThd header is only {} bytes", header_len)));
+    }
+    let format = match be_u16(&bytes[8..10]) {
+        0 => SMFFormat::Single,
+        1 => SMFFormat::MultiTrack,
+        2 => SMFFormat::MultiSong,
+        f => {
+            warnings.push(ReadWarning::UnknownFormat(f));
+            SMFFormat::MultiTrack
+        }
+    };
+    let declared_tracks = be_u16(&bytes[10..12]) as usize;
+    let division = be_u16(&bytes[12..14]) as i16;
+
+    let mut tracks = Vec::new();
+    let mut pos = 8 + header_len;
+    while pos + 8 <= bytes.len() {
+        let id = &bytes[pos..pos + 4];
+        let declared = be_u32(&bytes[pos + 4..pos + 8]) as usize;
+        if id != b"MTrk" {
+            if id.iter().all(|b| b.is_ascii_alphanumeric()) {
+                warnings.push(ReadWarning::UnknownChunk { id: String::from_utf8_lossy(id).into_owned(), offset: pos });
+                pos = pos.saturating_add(8).saturating_add(declared);
+            } else {
+                // we lost track of the chunks; look for the next track
+                let next = find_chunk(bytes, pos + 1).unwrap_or(bytes.len());
+                warnings.push(ReadWarning::SkippedBytes { offset: pos, count: next - pos });
+                pos = next;
+            }
+            continue;
+        }
+
+        let start = pos + 8;
+        let declared_end = start.saturating_add(declared);
+        let mut parser = TrackParser {
+            bytes,
+            pos: start,
+            declared_end,
+            track: tracks.len(),
+            running_status: None,
+            warnings: &mut warnings,
+        };
+        let (track, found_end) = parser.parse();
+        let end = parser.pos;
+        tracks.push(track);
+
+        if declared_end <= bytes.len() && starts_chunk(bytes, declared_end) && found_end {
+            // trust the header when it points at the next chunk
+            pos = declared_end;
+        } else {
+            if end != declared_end {
+                warnings.push(ReadWarning::ChunkLength { track: tracks.len() - 1, declared, actual: end - start });
+            }
+            pos = end;
+        }
+    }
+    if pos < bytes.len() {
+        warnings.push(ReadWarning::SkippedBytes { offset: pos, count: bytes.len() - pos });
+    }
+    if tracks.len() != declared_tracks {
+        warnings.push(ReadWarning::TrackCount { declared: declared_tracks, found: tracks.len() });
+    }
+
+    Ok((SMF { format, tracks, division }, warnings))
+}
+
+/// Reads a standard midi file and fails on anything `read_lenient` would have to repair.
+/// Unknown chunks are allowed, the standard says to skip them.
+pub fn read_strict(bytes: &[u8]) -> Result<SMF> {
+    let (smf, warnings) = read_lenient(bytes)?;
+    match warnings.iter().find(|w| !matches!(w, ReadWarning::UnknownChunk { .. })) {
+        Some(warning) => Err(Error::Parse(warning.to_string())),
+        None => Ok(smf)
+    }
+}
+
+struct TrackParser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    declared_end: usize,
+    track: usize,
+    running_status: Option<u8>,
+    warnings: &'a mut Vec<ReadWarning>,
+}
+
+impl<'a> TrackParser<'a> {
+    /// returns the track and whether it ended where a chunk should begin.
+    fn parse(&mut self) -> (Track, bool) {
+        let mut events = Vec::new();
+        let mut pending_vtime = 0;
+        // after skipped bytes we are at a status byte, not at a delta time
+        let mut at_status = false;
+        let mut ended = false;
+        while self.pos < self.bytes.len() {
+            // a track without EndOfTrack runs into the next chunk
+            if self.pos >= self.declared_end && starts_chunk(self.bytes, self.pos) {
+                break;
+            }
+            let event_start = self.pos;
+            let vtime = if at_status {
+                0
+            } else {
+                match self.read_varlen() {
+                    Some(vtime) => vtime,
+                    None => {
+                        self.truncated(event_start);
+                        break;
+                    }
+                }
+            };
+            at_status = false;
+            match self.read_event() {
+                Ok(Some(event)) => {
+                    let is_end = match &event {
+                        Event::Meta(meta) => meta.command == MetaCommand::EndOfTrack,
+                        _ => false
+                    };
+                    events.push(TrackEvent { vtime: pending_vtime + vtime, event });
+                    pending_vtime = 0;
+                    if is_end {
+                        ended = true;
+                        break;
+                    }
+                },
+                // nothing usable, but the time still passes
+                Ok(None) => {
+                    pending_vtime += vtime;
+                    at_status = true;
+                },
+                Err(()) => {
+                    self.truncated(event_start);
+                    break;
+                }
+            }
+        }
+        if !ended {
+            self.warnings.push(ReadWarning::MissingEndOfTrack { track: self.track });
+            events.push(TrackEvent { vtime: pending_vtime, event: Event::Meta(MetaEvent::end_of_track()) });
+        }
+
+        let text = |command: MetaCommand| events.iter().find_map(|e| match &e.event {
+            Event::Meta(meta) if meta.command == command => Some(String::from_utf8_lossy(&meta.data).into_owned()),
+            _ => None
+        });
+        let track = Track {
+            copyright: text(MetaCommand::CopyrightNotice),
+            name: text(MetaCommand::SequenceOrTrackName),
+            events,
+        };
+        (track, ended || starts_chunk(self.bytes, self.pos))
+    }
+
+    fn truncated(&mut self, offset: usize) {
+        self.warnings.push(ReadWarning::TruncatedTrack { track: self.track, offset });
+        // nothing after this point can be trusted; resume at the next track, if any
+        self.pos = find_chunk(self.bytes, offset).unwrap_or(self.bytes.len());
+    }
+
+    /// Ok(None) if bytes were skipped without producing an event,
+    /// Err if the data ended in the middle of the event.
+    fn read_event(&mut self) -> std::result::Result<Option<Event>, ()> {
+        let status = *self.bytes.get(self.pos).ok_or(())?;
+        match status {
+            0xFF => {
+                // meta and sysex events cancel running status
+                self.running_status = None;
+                self.pos += 1;
+                let command = *self.bytes.get(self.pos).ok_or(())?;
+                self.pos += 1;
+                let mut data = self.read_data()?;
+                let length = data.len() as u64;
+                let meta_command = meta_command(command);
+                // rimd has no room for a type it does not know; keep it in
+                // front of the data so that it can be written back
+                if meta_command == MetaCommand::Unknown {
+                    data.insert(0, command);
+                }
+                Ok(Some(Event::Meta(MetaEvent {
+                    command: meta_command,
+                    length,
+                    data,
+                })))
+            },
+            0xF0 | 0xF7 => {
+                self.running_status = None;
+                self.pos += 1;
+                let mut data = vec![status];
+                data.extend(self.read_data()?);
+                Ok(Some(Event::Midi(MidiMessage::from_bytes(data))))
+            },
+            0xF1..=0xFE => {
+                // system common and realtime messages have no place in a file
+                self.warnings.push(ReadWarning::StrayRunningStatus { track: self.track, offset: self.pos });
+                self.pos += 1;
+                Ok(None)
+            },
+            0x80..=0xEF => {
+                self.pos += 1;
+                self.running_status = Some(status);
+                Ok(self.read_channel_data(status))
+            },
+            _ => match self.running_status {
+                Some(status) => Ok(self.read_channel_data(status)),
+                None => {
+                    self.warnings.push(ReadWarning::StrayRunningStatus { track: self.track, offset: self.pos });
+                    // skip to the next status byte, or the next track
+                    while self.bytes.get(self.pos).map_or(false, |b| *b < 0x80) && !starts_chunk(self.bytes, self.pos) {
+                        self.pos += 1;
+                    }
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    fn read_channel_data(&mut self, status: u8) -> Option<Event> {
+        let len = match status & 0xF0 {
+            0xC0 | 0xD0 => 1,
+            _ => 2
+        };
+        let mut data = vec![status];
+        for _ in 0..len {
+            match self.bytes.get(self.pos) {
+                Some(b) if *b < 0x80 => {
+                    data.push(*b);
+                    self.pos += 1;
+                },
+                // a status byte where data should be; drop the message
+                // and read that byte as the next event
+                _ => {
+                    self.warnings.push(ReadWarning::StrayRunningStatus { track: self.track, offset: self.pos });
+                    return None;
+                }
+            }
+        }
+        Some(Event::Midi(MidiMessage::from_bytes(data)))
+    }
+
+    fn read_data(&mut self) -> std::result::Result<Vec<u8>, ()> {
+        let len = self.read_varlen().ok_or(())? as usize;
+        let end = self.pos.checked_add(len).ok_or(())?;
+        let data = self.bytes.get(self.pos..end).ok_or(())?.to_vec();
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn read_varlen(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let b = *self.bytes.get(self.pos)?;
+            self.pos += 1;
+            value = (value << 7) | (b & 0x7F) as u64;
+            if b & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+fn meta_command(command: u8) -> MetaCommand {
+    match command {
+        0x00 => MetaCommand::SequenceNumber,
+        0x01 => MetaCommand::TextEvent,
+        0x02 => MetaCommand::CopyrightNotice,
+        0x03 => MetaCommand::SequenceOrTrackName,
+        0x04 => MetaCommand::InstrumentName,
+        0x05 => MetaCommand::LyricText,
+        0x06 => MetaCommand::MarkerText,
+        0x07 => MetaCommand::CuePoint,
+        0x20 => MetaCommand::MIDIChannelPrefixAssignment,
+        0x21 => MetaCommand::MIDIPortPrefixAssignment,
+        0x2F => MetaCommand::EndOfTrack,
+        0x51 => MetaCommand::TempoSetting,
+        0x54 => MetaCommand::SMPTEOffset,
+        0x58 => MetaCommand::TimeSignature,
+        0x59 => MetaCommand::KeySignature,
+        0x7F => MetaCommand::SequencerSpecificEvent,
+        _ => MetaCommand::Unknown
+    }
+}
+
+fn starts_chunk(bytes: &[u8], pos: usize) -> bool {
+    bytes.get(pos..pos + 4) == Some(b"MTrk")
+}
+
+fn find_chunk(bytes: &[u8], from: usize) -> Option<usize> {
+    (from..bytes.len()).find(|pos| starts_chunk(bytes, *pos))
+}
+
+fn be_u16(b: &[u8]) -> u16 {
+    ((b[0] as u16) << 8) | b[1] as u16
+}
+
+fn be_u32(b: &[u8]) -> u32 {
+    ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32
+}
+
+#[cfg(test)]
+fn smf_bytes(tracks: &[(&[u8], u32)]) -> Vec<u8> {
+    let mut bytes = b"MThd\0\0\0\x06\0\x01".to_vec();
+    bytes.extend(&(tracks.len() as u16).to_be_bytes());
+    bytes.extend(&480u16.to_be_bytes());
+    for (data, len) in tracks {
+        bytes.extend(b"MTrk");
+        bytes.extend(&len.to_be_bytes());
+        bytes.extend(*data);
+    }
+    bytes
+}
+
+#[test]
+fn lenient_reads_broken_tracks() {
+    // no EndOfTrack, and running status after a meta event, which cancels it
+    let track0: &[u8] = &[0x00, 0xFF, 0x03, 0x01, b'a', 0x00, 0x90, 60, 100, 0x10, 0xFF, 0x01, 0x00, 0x10, 60, 0];
+    // chunk length is short by 2 bytes
+    let track1: &[u8] = &[0x00, 0x91, 62, 100, 0x20, 0x81, 62, 0, 0x00, 0xFF, 0x2F, 0x00];
+    let mut bytes = smf_bytes(&[(track0, track0.len() as u32), (track1, track1.len() as u32 - 2)]);
+    // an unknown chunk at the end
+    bytes.extend(b"XFIH\0\0\0\x02ab");
+
+    let (smf, warnings) = read_lenient(&bytes).unwrap();
+    assert_eq!(smf.tracks.len(), 2);
+    assert_eq!(smf.tracks[0].name.as_deref(), Some("a"));
+    let data: Vec<Vec<u8>> = smf.tracks[0].events.iter()
+        .filter_map(|e| match &e.event {
+            Event::Midi(msg) => Some(msg.data.clone()),
+            _ => None
+        })
+        .collect();
+    assert_eq!(data, vec![vec![0x90, 60, 100]]);
+    assert_eq!(smf.tracks[1].events.len(), 3);
+    assert!(warnings.contains(&ReadWarning::MissingEndOfTrack { track: 0 }));
+    assert!(warnings.contains(&ReadWarning::StrayRunningStatus { track: 0, offset: 22 + 14 }));
+    assert!(warnings.contains(&ReadWarning::ChunkLength { track: 1, declared: track1.len() - 2, actual: track1.len() }));
+    assert!(warnings.contains(&ReadWarning::UnknownChunk { id: String::from("XFIH"), offset: bytes.len() - 10 }));
+}
+
+#[test]
+fn lenient_keeps_truncated_track() {
+    let track: &[u8] = &[0x00, 0x90, 60, 100, 0x10, 0x80, 60, 0, 0x00, 0xFF, 0x51, 0x03, 0x07];
+    let bytes = smf_bytes(&[(track, 40)]);
+    let (smf, warnings) = read_lenient(&bytes).unwrap();
+    // both notes, then the added EndOfTrack
+    assert_eq!(smf.tracks[0].events.len(), 3);
+    assert_eq!(smf.tracks[0].events[1].vtime, 0x10);
+    assert!(warnings.contains(&ReadWarning::TruncatedTrack { track: 0, offset: 22 + 8 }));
+    assert!(warnings.contains(&ReadWarning::MissingEndOfTrack { track: 0 }));
+}
+
+#[test]
+fn lenient_skips_stray_data() {
+    let track: &[u8] = &[0x00, 0x40, 0x41, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00];
+    let bytes = smf_bytes(&[(track, track.len() as u32)]);
+    let (smf, warnings) = read_lenient(&bytes).unwrap();
+    assert_eq!(smf.tracks[0].events.len(), 2);
+    assert_eq!(warnings, vec![ReadWarning::StrayRunningStatus { track: 0, offset: 23 }]);
+}
+
+#[test]
+fn lenient_matches_strict_on_good_file() {
+    let (smf, warnings) = read_lenient(&std::fs::read("test_midi0.mid").unwrap()).unwrap();
+    assert_eq!(warnings, vec![]);
+    let strict = SMF::from_file(std::path::Path::new("test_midi0.mid")).unwrap();
+    assert_eq!(smf.tracks.len(), strict.tracks.len());
+    for (lenient, strict) in smf.tracks.iter().zip(&strict.tracks) {
+        assert_eq!(lenient.events.len(), strict.events.len());
+    }
+}
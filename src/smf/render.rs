@@ -0,0 +1,88 @@
+//! Offline rendering of a workspace to a wav file with the built-in synth.
+
+use super::MidiWorkspace;
+use super::synth::Synth;
+use crate::error::Result;
+use std::io::Write;
+use std::path::Path;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// length of silence rendered after the last event so that released notes can fade out
+pub(crate) const TAIL_SECS: f64 = 1.0;
+
+/// Plays the whole workspace through the built-in synth.
+/// Returns interleaved stereo samples.
+pub fn render_workspace(ws: &MidiWorkspace, sample_rate: u32) -> Result<Vec<f32>> {
+    let mut synth = Synth::new(sample_rate);
+    let mut samples = Vec::new();
+    let mut rendered_frames = 0;
+    for (time, data) in ws.timed_midi_events()? {
+        let frame = (time * sample_rate as f64).round() as usize;
+        if frame > rendered_frames {
+            render_frames(&mut synth, &mut samples, frame - rendered_frames);
+            rendered_frames = frame;
+        }
+        synth.handle(&data);
+    }
+    synth.all_notes_off();
+    render_frames(&mut synth, &mut samples, (TAIL_SECS * sample_rate as f64) as usize);
+    Ok(samples)
+}
+
+pub(crate) fn render_frames(synth: &mut Synth, samples: &mut Vec<f32>, frames: usize) {
+    let start = samples.len();
+    samples.resize(start + frames * 2, 0.0);
+    synth.render(&mut samples[start..]);
+}
+
+/// Writes interleaved stereo samples as a 16 bit PCM wav stream.
+pub fn write_wav<W: Write>(writer: &mut W, samples: &[f32], sample_rate: u32) -> std::io::Result<()> {
+    const CHANNELS: u16 = 2;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let data_len = (samples.len() * 2) as u32;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // PCM
+    writer.write_all(&CHANNELS.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.max(-1.0).min(1.0) * std::i16::MAX as f32) as i16;
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Renders the workspace and writes the result to a wav file.
+pub fn render_to_wav_file(ws: &MidiWorkspace, path: impl AsRef<Path>, sample_rate: u32) -> Result<()> {
+    let samples = render_workspace(ws, sample_rate)?;
+    let file = std::fs::File::create(path)?;
+    let mut writer = std::io::BufWriter::new(file);
+    write_wav(&mut writer, &samples, sample_rate)?;
+    Ok(writer.flush()?)
+}
+
+#[test]
+fn render_test_midi0() {
+    let ws = MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    let samples = render_workspace(&ws, 8000).unwrap();
+    assert!(samples.len() >= (TAIL_SECS * 8000.0) as usize * 2);
+    assert!(samples.iter().any(|s| *s != 0.0));
+
+    let mut wav = Vec::new();
+    write_wav(&mut wav, &samples, 8000).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(wav.len(), 44 + samples.len() * 2);
+}
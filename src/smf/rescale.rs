@@ -0,0 +1,96 @@
+//! Changing the resolution (ticks per quarter note) of a workspace.
+
+use super::{util, AbsTrack};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// An event whose tick had to be rounded because it falls between two ticks
+/// of the new resolution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundedEvent {
+    pub track: usize,
+    /// tick in the old resolution
+    pub from_tick: u64,
+    /// nearest tick in the new resolution
+    pub to_tick: u64,
+}
+
+/// What could not be mapped exactly by `MidiWorkspace::set_resolution`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RescaleReport {
+    pub from: u16,
+    pub to: u16,
+    pub rounded: Vec<RoundedEvent>,
+    /// notes which became shorter than a tick and were made 1 tick long
+    pub lengthened_notes: usize,
+}
+
+impl RescaleReport {
+    pub fn is_exact(&self) -> bool {
+        self.rounded.is_empty() && self.lengthened_notes == 0
+    }
+}
+
+impl fmt::Display for RescaleReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "resolution changed from {} to {}", self.from, self.to)?;
+        if self.is_exact() {
+            return write!(f, "; every event was mapped exactly");
+        }
+        write!(f, "; {} events were moved to the nearest tick", self.rounded.len())?;
+        if self.lengthened_notes > 0 {
+            write!(f, ", {} notes were lengthened to 1 tick", self.lengthened_notes)?;
+        }
+        Ok(())
+    }
+}
+
+/// `tick` in the new resolution, rounded to the nearest tick, and whether that was exact.
+pub fn rescale_tick(tick: u64, from: u16, to: u16) -> (u64, bool) {
+    let scaled = tick as u128 * to as u128;
+    let from = from.max(1) as u128;
+    (((scaled + from / 2) / from) as u64, scaled % from == 0)
+}
+
+pub(crate) fn rescale_track(track: &mut AbsTrack, track_index: usize, from: u16, to: u16, report: &mut RescaleReport) {
+    // note ons waiting for their note off, oldest first
+    let mut sounding: HashMap<(u8, u8), VecDeque<usize>> = HashMap::new();
+    let mut pairs = Vec::new();
+    for (i, event) in track.events.iter().enumerate() {
+        if let rimd::Event::Midi(msg) = &event.track_event.event {
+            if let Some((ch, note, _)) = util::note_on(msg) {
+                sounding.entry((ch, note)).or_insert_with(VecDeque::new).push_back(i);
+            } else if let Some((ch, note, _)) = util::note_off(msg) {
+                if let Some(on) = sounding.get_mut(&(ch, note)).and_then(|q| q.pop_front()) {
+                    pairs.push((on, i));
+                }
+            }
+        }
+    }
+
+    let old_ticks: Vec<u64> = track.events.iter().map(|e| e.abs_time).collect();
+    for event in track.events.iter_mut() {
+        let (tick, exact) = rescale_tick(event.abs_time, from, to);
+        if !exact && !event.is_end_of_track() {
+            report.rounded.push(RoundedEvent { track: track_index, from_tick: event.abs_time, to_tick: tick });
+        }
+        event.abs_time = tick;
+    }
+    for (on, off) in pairs {
+        if old_ticks[on] < old_ticks[off] && track.events[off].abs_time <= track.events[on].abs_time {
+            // otherwise the note off would be sorted before its note on
+            track.events[off].abs_time = track.events[on].abs_time + 1;
+            report.lengthened_notes += 1;
+        }
+    }
+    track.dirty = true;
+}
+
+#[test]
+fn rescale_rounds_to_nearest() {
+    assert_eq!(rescale_tick(960, 480, 96), (192, true));
+    assert_eq!(rescale_tick(5, 480, 96), (1, true));
+    assert_eq!(rescale_tick(3, 480, 96), (1, false));
+    assert_eq!(rescale_tick(2, 480, 96), (0, false));
+    assert_eq!(rescale_tick(7, 96, 960), (70, true));
+}
@@ -0,0 +1,177 @@
+//! Round trip tests over the files in `test_corpus/`.
+//!
+//! format0.mid, format1.mid, sysex.mid, meta_events.mid and tempo.mid are written the
+//! way `writer` writes them and must come back byte for byte. running_status.mid and
+//! odd_delta.mid use running status, padded delta times and a delta close to the
+//! largest one allowed; they must come back with the same events at the same ticks.
+//! stray_running_status.mid carries running status over a meta event, which the strict
+//! reader must reject.
+
+use super::{reader, writer, MidiWorkspace};
+use rimd::{Event, SMFFormat, SMF};
+
+const CANONICAL: &[&str] = &["format0.mid", "format1.mid", "sysex.mid", "meta_events.mid", "tempo.mid"];
+const NON_CANONICAL: &[&str] = &["running_status.mid", "odd_delta.mid"];
+
+type TrackBytes = Vec<(u64, Vec<u8>)>;
+type Edit = Box<dyn Fn(&mut MidiWorkspace)>;
+
+fn corpus(name: &str) -> Vec<u8> {
+    std::fs::read(format!("test_corpus/{}", name)).unwrap()
+}
+
+fn open(bytes: &[u8]) -> MidiWorkspace {
+    MidiWorkspace::from_smf(reader::read_strict(bytes).unwrap()).unwrap()
+}
+
+fn save(ws: &MidiWorkspace) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    ws.write_all(&mut bytes).unwrap();
+    bytes
+}
+
+/// every event as (absolute tick, bytes), meta events as FF command data
+fn semantic(smf: &SMF) -> (u16, i16, Vec<TrackBytes>) {
+    let format = match smf.format {
+        SMFFormat::Single => 0,
+        SMFFormat::MultiTrack => 1,
+        SMFFormat::MultiSong => 2,
+    };
+    let tracks = smf.tracks.iter().map(|track| {
+        let mut abs = 0;
+        track.events.iter().map(|e| {
+            abs += e.vtime;
+            let bytes = match &e.event {
+                Event::Midi(msg) => msg.data.clone(),
+                Event::Meta(meta) => {
+                    let (command, data) = writer::meta_bytes(meta).unwrap();
+                    let mut bytes = vec![0xFF, command];
+                    bytes.extend(data);
+                    bytes
+                }
+            };
+            (abs, bytes)
+        }).collect()
+    }).collect();
+    (format, smf.division, tracks)
+}
+
+/// exactly one EndOfTrack, which is the last event and not before any other
+fn assert_end_of_track(ws: &MidiWorkspace, what: &str) {
+    for i in 0..ws.track_count() {
+        let track = ws.events_abs_tick(i).unwrap();
+        let events = track.events();
+        let ends = events.iter().filter(|e| e.is_end_of_track()).count();
+        assert_eq!(ends, 1, "{}: track {} has {} end of track events", what, i, ends);
+        let last = events.last().unwrap();
+        assert!(last.is_end_of_track(), "{}: track {} does not end with end of track", what, i);
+        assert!(events.iter().all(|e| e.abs_time <= last.abs_time), "{}: track {} has events after its end", what, i);
+    }
+}
+
+#[test]
+fn canonical_files_are_written_unchanged() {
+    for name in CANONICAL {
+        let bytes = corpus(name);
+        assert_eq!(save(&open(&bytes)), bytes, "{}", name);
+    }
+}
+
+#[test]
+fn other_files_keep_their_events() {
+    for name in NON_CANONICAL.iter().chain(CANONICAL).chain(&["../test_midi0.mid"]) {
+        let bytes = corpus(name);
+        let saved = save(&open(&bytes));
+        assert_eq!(semantic(&reader::read_strict(&saved).unwrap()), semantic(&reader::read_strict(&bytes).unwrap()), "{}", name);
+        // what we write is canonical, so it stays the same from now on
+        assert_eq!(save(&open(&saved)), saved, "{}", name);
+    }
+}
+
+#[test]
+fn odd_encodings_are_read() {
+    let smf = reader::read_strict(&corpus("odd_delta.mid")).unwrap();
+    let ticks: Vec<u64> = semantic(&smf).2[0].iter().map(|(tick, _)| *tick).collect();
+    assert_eq!(ticks, vec![0, 0, 96, 96 + 0x0FFF_0000, 96 + 0x0FFF_0000, 1096 + 0x0FFF_0000]);
+
+    let smf = reader::read_strict(&corpus("running_status.mid")).unwrap();
+    let events = &semantic(&smf).2[0];
+    assert_eq!(events[2], (120, vec![0x90, 60, 0]));
+    // a meta event cancels running status, so the note after it has its own
+    assert_eq!(events[5], (240, vec![0x90, 62, 0]));
+    assert_eq!(events.last().unwrap(), &(265, vec![0xFF, 0x2F]));
+
+    let smf = reader::read_strict(&corpus("sysex.mid")).unwrap();
+    let sysex: Vec<Vec<u8>> = semantic(&smf).2.remove(0).into_iter()
+        .map(|(_, bytes)| bytes)
+        .filter(|bytes| bytes[0] == 0xF0 || bytes[0] == 0xF7)
+        .collect();
+    assert_eq!(sysex[0], vec![0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]);
+    assert_eq!(sysex[2], vec![0xF7, 0x00, 0x00, 0x7E, 0xF7]);
+    assert_eq!(sysex[3], vec![0xF7, 0xFA]);
+}
+
+#[test]
+fn running_status_ends_at_meta_events() {
+    let bytes = corpus("stray_running_status.mid");
+    assert!(reader::read_strict(&bytes).is_err());
+    let (smf, warnings) = reader::read_lenient(&bytes).unwrap();
+    assert_eq!(warnings, vec![reader::ReadWarning::StrayRunningStatus { track: 0, offset: 22 + 46 }]);
+    // the note off after the meta event is dropped
+    let events = &semantic(&smf).2[0];
+    assert_eq!(events[5], (240, vec![0xB0, 1, 0]));
+}
+
+#[test]
+fn edits_keep_end_of_track_last() {
+    for name in CANONICAL.iter().chain(NON_CANONICAL) {
+        let ws = open(&corpus(name));
+        let resolution = ws.resolution() as u16;
+        let mut edits: Vec<(&str, Edit)> = vec![
+            ("transpose", Box::new(|ws| ws.transpose(None, 5).unwrap())),
+            ("quantize", Box::new(move |ws| ws.quantize(None, resolution as u64 / 4).unwrap())),
+            ("merge", Box::new(|ws| { ws.merge_tracks(&(0..ws.track_count()).collect::<Vec<_>>()).unwrap(); })),
+            ("split", Box::new(|ws| { ws.split_track_by_channel(0).unwrap(); })),
+            ("set_resolution", Box::new(move |ws| { ws.set_resolution(resolution / 2).unwrap(); })),
+        ];
+        edits.push(("convert_format", Box::new(|ws| {
+            let to = match ws.format() {
+                SMFFormat::Single => SMFFormat::MultiTrack,
+                _ => SMFFormat::Single,
+            };
+            ws.convert_format(to).unwrap();
+        })));
+        for (what, edit) in &edits {
+            let mut ws = ws.clone();
+            edit(&mut ws);
+            let what = format!("{} {}", what, name);
+            assert_end_of_track(&ws, &what);
+            // and still after saving and opening again
+            assert_end_of_track(&open(&save(&ws)), &what);
+        }
+    }
+}
+
+#[test]
+fn unknown_meta_types_are_kept() {
+    let mut bytes = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x01\xE0MTrk".to_vec();
+    let track: &[u8] = &[
+        0x00, 0xFF, 0x08, 0x04, b'P', b'i', b'a', b'n',
+        0x00, 0xFF, 0x09, 0x03, b'D', b'e', b'v',
+        0x00, 0x90, 60, 100,
+        0x83, 0x60, 0xFF, 0x4B, 0x02, 0x01, 0x02,
+        0x00, 0x80, 60, 0,
+        0x00, 0xFF, 0x2F, 0x00,
+    ];
+    bytes.extend(&(track.len() as u32).to_be_bytes());
+    bytes.extend(track);
+    assert_eq!(save(&open(&bytes)), bytes);
+
+    // and after an edit which rebuilds the track
+    let mut ws = open(&bytes);
+    ws.transpose(None, 2).unwrap();
+    let events = semantic(&reader::read_strict(&save(&ws)).unwrap()).2.remove(0);
+    assert_eq!(events[0], (0, b"\xFF\x08Pian".to_vec()));
+    assert_eq!(events[1], (0, b"\xFF\x09Dev".to_vec()));
+    assert!(events.contains(&(480, vec![0xFF, 0x4B, 0x01, 0x02])));
+}
@@ -0,0 +1,82 @@
+//! Saving files so that the old file survives a save which fails half way.
+
+use crate::error::Result;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// where the previous version of `path` is kept: `song.mid` becomes `song.mid.bak`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|n| n.to_os_string()).unwrap_or_default();
+    name.push(".bak");
+    path.with_file_name(name)
+}
+
+/// the temporary file is in the same directory, so that it can be renamed over `path`
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Writes a file with `write` into a temporary file next to `path`, flushes it to disk
+/// and renames it over `path`. Until the rename the old file is untouched, and if
+/// anything fails the temporary file is removed.
+/// With `backup`, a copy of the old file is kept at `backup_path(path)`.
+pub fn write_atomic<F>(path: &Path, backup: bool, write: F) -> Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> Result<()>
+{
+    let temp = temp_path(path);
+    let result = write_temp(path, &temp, write).and_then(|_| {
+        if backup && path.exists() {
+            fs::copy(path, backup_path(path))?;
+        }
+        fs::rename(&temp, path)?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn write_temp<F>(path: &Path, temp: &Path, write: F) -> Result<()>
+    where F: FnOnce(&mut BufWriter<File>) -> Result<()>
+{
+    // a temporary file left over from a crash is overwritten
+    let file = OpenOptions::new().create(true).write(true).truncate(true).open(temp)?;
+    if let Ok(metadata) = fs::metadata(path) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    let file = writer.into_inner().map_err(io::Error::from)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[test]
+fn atomic_save_replaces_and_backs_up() {
+    use std::io::Write;
+    let dir = std::env::temp_dir().join(format!("midie-save-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("song.mid");
+    fs::write(&path, b"a longer old file").unwrap();
+
+    write_atomic(&path, true, |w| Ok(w.write_all(b"new")?)).unwrap();
+    // nothing of the longer old file is left at the end
+    assert_eq!(fs::read(&path).unwrap(), b"new");
+    assert_eq!(fs::read(backup_path(&path)).unwrap(), b"a longer old file");
+    assert!(!temp_path(&path).exists());
+
+    let failed = write_atomic(&path, false, |w| {
+        w.write_all(b"half")?;
+        Err(crate::Error::InvalidArgument(String::from("failed")))
+    });
+    assert!(failed.is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"new");
+    assert!(!temp_path(&path).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,466 @@
+//! A very small polyphonic software synthesizer.
+//!
+//! Good enough to check an arrangement without any synth attached:
+//! every GM program family is mapped to a basic waveform, channel 10 plays noise drums.
+
+use super::util::{self, DRUM_CHANNEL};
+use super::play::MidiSink;
+use super::render;
+use crate::error::{Error, Result};
+use std::f32::consts::PI;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const MAX_VOICES: usize = 64;
+const MASTER_GAIN: f32 = 0.2;
+
+const ATTACK_SECS: f32 = 0.005;
+const DECAY_SECS: f32 = 0.1;
+const SUSTAIN_LEVEL: f32 = 0.7;
+const RELEASE_SECS: f32 = 0.2;
+const DRUM_DECAY_SECS: f32 = 0.15;
+
+/// pitch bend range in semitones
+const BEND_RANGE: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Waveform {
+    Sine,
+    Triangle,
+    Square,
+    Saw,
+    Noise,
+}
+
+impl Waveform {
+    /// picks a waveform for a GM program number
+    fn for_program(program: u8) -> Self {
+        match program / 8 {
+            0 | 4 => Waveform::Triangle,      // piano, bass
+            2 | 7 | 8 | 10 => Waveform::Square, // organ, brass, reed, synth lead
+            3 | 5 | 6 | 11 => Waveform::Saw,  // guitar, strings, ensemble, synth pad
+            _ => Waveform::Sine
+        }
+    }
+
+    fn sample(self, phase: f32, noise: &mut u32) -> f32 {
+        match self {
+            Waveform::Sine => (phase * 2.0 * PI).sin(),
+            Waveform::Triangle => 4.0 * (phase - (phase + 0.5).floor()).abs() - 1.0,
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+            Waveform::Saw => 2.0 * phase - 1.0,
+            Waveform::Noise => {
+                // xorshift32
+                *noise ^= *noise << 13;
+                *noise ^= *noise >> 17;
+                *noise ^= *noise << 5;
+                (*noise as f32 / std::u32::MAX as f32) * 2.0 - 1.0
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChannelState {
+    program: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    sustain: bool,
+    bend: f32, // in semitones
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        ChannelState {
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            sustain: false,
+            bend: 0.0,
+        }
+    }
+}
+
+impl ChannelState {
+    /// (left, right) gain
+    fn gains(&self) -> (f32, f32) {
+        let volume = (self.volume as f32 / 127.0).powi(2) * (self.expression as f32 / 127.0);
+        let pan = self.pan as f32 / 127.0 * PI / 2.0;
+        (volume * pan.cos(), volume * pan.sin())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Done,
+}
+
+#[derive(Debug, Clone)]
+struct Voice {
+    channel: u8,
+    note: u8,
+    velocity: f32,
+    waveform: Waveform,
+    phase: f32,
+    stage: EnvelopeStage,
+    level: f32,
+    released_by_pedal: bool,
+}
+
+impl Voice {
+    fn frequency(&self, bend: f32) -> f32 {
+        440.0 * 2f32.powf((self.note as f32 - 69.0 + bend) / 12.0)
+    }
+
+    fn release(&mut self) {
+        if self.stage != EnvelopeStage::Done {
+            self.stage = EnvelopeStage::Release;
+        }
+    }
+
+    /// advances the envelope by one sample and returns the current level
+    fn envelope(&mut self, sample_rate: f32) -> f32 {
+        let step = |secs: f32| 1.0 / (secs * sample_rate);
+        if self.channel == DRUM_CHANNEL {
+            self.level -= step(DRUM_DECAY_SECS);
+            if self.level <= 0.0 {
+                self.level = 0.0;
+                self.stage = EnvelopeStage::Done;
+            }
+            return self.level;
+        }
+        match self.stage {
+            EnvelopeStage::Attack => {
+                self.level += step(ATTACK_SECS);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            },
+            EnvelopeStage::Decay => {
+                self.level -= step(DECAY_SECS) * (1.0 - SUSTAIN_LEVEL);
+                if self.level <= SUSTAIN_LEVEL {
+                    self.level = SUSTAIN_LEVEL;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            },
+            EnvelopeStage::Sustain => {},
+            EnvelopeStage::Release => {
+                self.level -= step(RELEASE_SECS);
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = EnvelopeStage::Done;
+                }
+            },
+            EnvelopeStage::Done => {}
+        }
+        self.level
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Synth {
+    sample_rate: f32,
+    channels: [ChannelState; 16],
+    voices: Vec<Voice>,
+    noise: u32,
+}
+
+impl Synth {
+    pub fn new(sample_rate: u32) -> Self {
+        Synth {
+            sample_rate: sample_rate as f32,
+            channels: [ChannelState::default(); 16],
+            voices: Vec::new(),
+            noise: 0x1234_5678,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    /// number of notes which still produce sound
+    pub fn active_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// feeds a raw midi message to the synth.
+    pub fn handle(&mut self, data: &[u8]) {
+        if let Some((ch, note, velocity)) = util::note_on_bytes(data) {
+            self.note_on(ch, note, velocity);
+            return;
+        }
+        if let Some((ch, note, _)) = util::note_off_bytes(data) {
+            self.note_off(ch, note);
+            return;
+        }
+        if data.is_empty() || data[0] >= 0xF0 {
+            return;
+        }
+        let ch = data[0] & 0x0F;
+        match (data[0] & 0xF0, data.get(1), data.get(2)) {
+            (0xB0, Some(&controller), Some(&value)) => self.control_change(ch, controller, value),
+            (0xC0, Some(&program), _) => self.channels[ch as usize].program = program,
+            (0xE0, Some(&lsb), Some(&msb)) => {
+                let value = ((msb as i32) << 7 | lsb as i32) - 8192;
+                self.channels[ch as usize].bend = value as f32 / 8192.0 * BEND_RANGE;
+            },
+            _ => {}
+        }
+    }
+
+    fn note_on(&mut self, ch: u8, note: u8, velocity: u8) {
+        if self.voices.len() >= MAX_VOICES {
+            // steal the oldest voice
+            self.voices.remove(0);
+        }
+        let waveform = if ch == DRUM_CHANNEL {
+            Waveform::Noise
+        } else {
+            Waveform::for_program(self.channels[ch as usize].program)
+        };
+        self.voices.push(Voice {
+            channel: ch,
+            note,
+            velocity: velocity as f32 / 127.0,
+            waveform,
+            phase: 0.0,
+            stage: EnvelopeStage::Attack,
+            level: if ch == DRUM_CHANNEL { 1.0 } else { 0.0 },
+            released_by_pedal: false,
+        });
+    }
+
+    fn note_off(&mut self, ch: u8, note: u8) {
+        let sustain = self.channels[ch as usize].sustain;
+        for voice in self.voices.iter_mut()
+            .filter(|v| v.channel == ch && v.note == note && !v.released_by_pedal) {
+            if sustain {
+                voice.released_by_pedal = true;
+            } else {
+                voice.release();
+            }
+        }
+    }
+
+    fn control_change(&mut self, ch: u8, controller: u8, value: u8) {
+        let state = &mut self.channels[ch as usize];
+        match controller {
+            7 => state.volume = value,
+            10 => state.pan = value,
+            11 => state.expression = value,
+            64 => {
+                state.sustain = value >= 64;
+                if !state.sustain {
+                    for voice in self.voices.iter_mut().filter(|v| v.channel == ch && v.released_by_pedal) {
+                        voice.release();
+                    }
+                }
+            },
+            120 => self.voices.retain(|v| v.channel != ch), // all sound off
+            121 => {
+                // reset all controllers keeps the program
+                let program = state.program;
+                *state = ChannelState { program, ..ChannelState::default() };
+            },
+            123 => {
+                // all notes off
+                for voice in self.voices.iter_mut().filter(|v| v.channel == ch) {
+                    voice.release();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /// releases every voice on every channel.
+    pub fn all_notes_off(&mut self) {
+        for ch in 0..16 {
+            self.channels[ch].sustain = false;
+        }
+        for voice in self.voices.iter_mut() {
+            voice.release();
+        }
+    }
+
+    /// mixes the next `out.len() / 2` frames into `out` as interleaved stereo samples.
+    pub fn render(&mut self, out: &mut [f32]) {
+        let sample_rate = self.sample_rate;
+        for frame in out.chunks_mut(2) {
+            let mut left = 0.0;
+            let mut right = 0.0;
+            for voice in self.voices.iter_mut() {
+                let channel = &self.channels[voice.channel as usize];
+                let level = voice.envelope(sample_rate);
+                let value = voice.waveform.sample(voice.phase, &mut self.noise) * level * voice.velocity;
+                voice.phase = (voice.phase + voice.frequency(channel.bend) / sample_rate).fract();
+                let (l, r) = channel.gains();
+                left += value * l;
+                right += value * r;
+            }
+            frame[0] += left * MASTER_GAIN;
+            if let Some(r) = frame.get_mut(1) {
+                *r += right * MASTER_GAIN;
+            }
+        }
+        self.voices.retain(|v| v.stage != EnvelopeStage::Done);
+    }
+}
+
+/// Plays the built-in synth on the default audio output device.
+pub struct SynthDeviceSink {
+    synth: Arc<Mutex<Synth>>,
+    // audio stops as soon as the stream is dropped
+    _stream: cpal::Stream,
+}
+
+impl SynthDeviceSink {
+    pub fn new() -> Result<Self> {
+        use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+        let device = cpal::default_host().default_output_device()
+            .ok_or_else(|| Error::Audio(String::from("no audio output device")))?;
+        let supported = device.default_output_config().map_err(|e| Error::Audio(e.to_string()))?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+        let synth = Arc::new(Mutex::new(Synth::new(config.sample_rate.0)));
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => build_output_stream::<f32>(&device, &config, Arc::clone(&synth)),
+            cpal::SampleFormat::I16 => build_output_stream::<i16>(&device, &config, Arc::clone(&synth)),
+            cpal::SampleFormat::U16 => build_output_stream::<u16>(&device, &config, Arc::clone(&synth)),
+            f => Err(Error::Audio(format!("unsupported sample format {:?}", f)))
+        }?;
+        stream.play().map_err(|e| Error::Audio(e.to_string()))?;
+        debug!("built-in synth started at {} Hz", config.sample_rate.0);
+        Ok(SynthDeviceSink {
+            synth,
+            _stream: stream
+        })
+    }
+}
+
+fn build_output_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, synth: Arc<Mutex<Synth>>) -> Result<cpal::Stream>
+    where T: cpal::SizedSample + cpal::FromSample<f32> {
+    use cpal::traits::DeviceTrait;
+    let channels = config.channels as usize;
+    let mut stereo = Vec::new();
+    device.build_output_stream(config, move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        stereo.clear();
+        stereo.resize(data.len() / channels * 2, 0.0);
+        if let Ok(mut synth) = synth.lock() {
+            synth.render(&mut stereo);
+        }
+        for (frame, lr) in data.chunks_mut(channels).zip(stereo.chunks(2)) {
+            for (i, sample) in frame.iter_mut().enumerate() {
+                let value = match (channels, i) {
+                    (1, _) => (lr[0] + lr[1]) / 2.0,
+                    (_, 0) => lr[0],
+                    (_, 1) => lr[1],
+                    _ => 0.0
+                };
+                *sample = T::from_sample(value);
+            }
+        }
+    }, |e| error!("audio stream error: {}", e), None).map_err(|e| Error::Audio(e.to_string()))
+}
+
+impl MidiSink for SynthDeviceSink {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        let mut synth = self.synth.lock().map_err(|_| Error::Audio(String::from("synth is poisoned")))?;
+        synth.handle(message);
+        Ok(())
+    }
+}
+
+/// Renders whatever is sent to it through the built-in synth, in real time,
+/// and writes the result to a wav file when closed.
+pub struct SynthFileSink {
+    synth: Synth,
+    path: PathBuf,
+    started: Instant,
+    samples: Vec<f32>,
+}
+
+impl SynthFileSink {
+    pub fn new(path: impl Into<PathBuf>, sample_rate: u32) -> Self {
+        SynthFileSink {
+            synth: Synth::new(sample_rate),
+            path: path.into(),
+            started: Instant::now(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// renders up to the current time
+    fn catch_up(&mut self) {
+        let frames = (self.started.elapsed().as_secs_f64() * self.synth.sample_rate() as f64) as usize;
+        let rendered = self.samples.len() / 2;
+        if frames > rendered {
+            render::render_frames(&mut self.synth, &mut self.samples, frames - rendered);
+        }
+    }
+}
+
+impl MidiSink for SynthFileSink {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        self.catch_up();
+        self.synth.handle(message);
+        Ok(())
+    }
+
+    fn close(mut self: Box<Self>) {
+        self.catch_up();
+        self.synth.all_notes_off();
+        let tail = (render::TAIL_SECS * self.synth.sample_rate() as f64) as usize;
+        render::render_frames(&mut self.synth, &mut self.samples, tail);
+        let result = std::fs::File::create(&self.path)
+            .and_then(|file| {
+                let mut writer = std::io::BufWriter::new(file);
+                render::write_wav(&mut writer, &self.samples, self.synth.sample_rate())?;
+                // dropping the writer would swallow an error writing the rest
+                writer.flush()
+            });
+        match result {
+            Ok(_) => debug!("synth output written to {:?}", self.path),
+            Err(e) => error!("failed to write {:?}: {}", self.path, e)
+        }
+    }
+}
+
+#[test]
+fn synth_releases_voices() {
+    let mut synth = Synth::new(44100);
+    synth.handle(&[0x90, 60, 100]);
+    synth.handle(&[0x99, 38, 100]);
+    let mut buf = vec![0.0; 2 * 4410];
+    synth.render(&mut buf);
+    assert_eq!(synth.active_voices(), 2);
+    assert!(buf.iter().any(|s| *s != 0.0));
+
+    synth.handle(&[0x80, 60, 0]);
+    let mut buf = vec![0.0; 2 * 44100];
+    synth.render(&mut buf);
+    assert_eq!(synth.active_voices(), 0);
+}
+
+#[test]
+fn synth_file_sink_writes_wav() {
+    let path = std::env::temp_dir().join(format!("midie-synth-file-sink-{}.wav", std::process::id()));
+    let mut sink: Box<dyn MidiSink> = Box::new(SynthFileSink::new(&path, 8000));
+    sink.send(&[0x90, 60, 100]).unwrap();
+    sink.send(&[0x80, 60, 0]).unwrap();
+    sink.close();
+
+    let wav = std::fs::read(&path).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert!(wav.len() > 44);
+    std::fs::remove_file(&path).unwrap();
+}
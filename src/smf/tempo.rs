@@ -0,0 +1,171 @@
+//! Tempo settings, and gradual tempo changes written as a staircase of them.
+//!
+//! Tempo is kept as the file stores it, in microseconds per quarter note, so that
+//! it survives editing unchanged. Beats per minute are only for showing and entering it.
+
+use super::{TempoInfo, DEFAULT_TEMPO};
+
+/// the slowest tempo a tempo setting can hold, about 3.6 bpm
+pub const MAX_TEMPO: u32 = 0xFFFFFF;
+
+/// microseconds per quarter note of the data of a tempo setting, which has to be three bytes
+pub fn decode(data: &[u8]) -> Option<u32> {
+    match *data {
+        [d0, d1, d2] => Some(((d0 as u32) << 16) | ((d1 as u32) << 8) | d2 as u32).filter(|&usec| usec > 0),
+        _ => None
+    }
+}
+
+pub fn bpm(usec: u32) -> f64 {
+    60_000_000.0 / usec.max(1) as f64
+}
+
+/// the nearest tempo a tempo setting can hold
+pub fn usec_per_quarter(bpm: f64) -> u32 {
+    (60_000_000.0 / bpm).round().max(1.0).min(MAX_TEMPO as f64) as u32
+}
+
+/// beats per minute with up to three decimals, e.g. "97.3" or "120"
+pub fn format_bpm(usec: u32) -> String {
+    let bpm = format!("{:.3}", bpm(usec));
+    bpm.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// How the tempo moves between the two ends of an accelerando or ritardando.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TempoCurve {
+    Linear,
+    /// changes slowly at first, then faster
+    EaseIn,
+    /// changes quickly at first, then settles
+    EaseOut,
+}
+
+impl TempoCurve {
+    pub const ALL: [TempoCurve; 3] = [TempoCurve::Linear, TempoCurve::EaseIn, TempoCurve::EaseOut];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TempoCurve::Linear => "Linear",
+            TempoCurve::EaseIn => "Ease in",
+            TempoCurve::EaseOut => "Ease out",
+        }
+    }
+
+    /// how far the tempo has moved at `t`, both from 0.0 to 1.0
+    fn at(self, t: f64) -> f64 {
+        match self {
+            TempoCurve::Linear => t,
+            TempoCurve::EaseIn => t * t,
+            TempoCurve::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+        }
+    }
+}
+
+/// tempo changes every `step` ticks from `start` to `end`, moving from `from` to `to` bpm
+/// along `curve`, reaching `to` at `end`. Steps which would not change the tempo are left out.
+pub fn ramp(start: u64, end: u64, from: f64, to: f64, curve: TempoCurve, step: u64) -> Vec<(u64, u32)> {
+    let to_usec = usec_per_quarter(to);
+    if end <= start {
+        return vec![(end, to_usec)];
+    }
+    let mut changes: Vec<(u64, u32)> = Vec::new();
+    for tick in (start..end).step_by(step.max(1) as usize) {
+        let t = (tick - start) as f64 / (end - start) as f64;
+        let usec = usec_per_quarter(from + (to - from) * curve.at(t));
+        if changes.last().map(|&(_, last)| last) != Some(usec) {
+            changes.push((tick, usec));
+        }
+    }
+    if changes.last().map(|&(_, last)| last) != Some(to_usec) {
+        changes.push((end, to_usec));
+    }
+    changes
+}
+
+impl TempoInfo {
+    /// sets the tempo at `abs_tick`, replacing a change which is already there.
+    pub fn set(&mut self, abs_tick: u64, usec: u32) {
+        self.changes.retain(|(abs, _)| *abs != abs_tick);
+        self.append((abs_tick, usec));
+    }
+
+    /// the change at `abs_tick`, if there is one
+    pub fn change_at(&self, abs_tick: u64) -> Option<(u64, u32)> {
+        self.changes.iter().find(|(abs, _)| *abs == abs_tick).copied()
+    }
+
+    /// replaces the changes from `start` to `end` with a gradual change along `curve`
+    /// to `to` bpm, starting from the tempo at `start`.
+    pub fn insert_ramp(&mut self, start: u64, end: u64, to: f64, curve: TempoCurve, step: u64) {
+        let (start, end) = (start.min(end), start.max(end));
+        let from = bpm(self.tempo(start).unwrap_or(DEFAULT_TEMPO));
+        self.changes.retain(|(abs, _)| *abs < start || *abs > end);
+        for change in ramp(start, end, from, to, curve, step) {
+            self.append(change);
+        }
+    }
+}
+
+#[test]
+fn ramps_are_stepped() {
+    let usec = |bpms: &[(u64, f64)]| bpms.iter().map(|&(abs, bpm)| (abs, usec_per_quarter(bpm))).collect::<Vec<_>>();
+    assert_eq!(ramp(0, 480, 100.0, 140.0, TempoCurve::Linear, 120),
+        usec(&[(0, 100.0), (120, 110.0), (240, 120.0), (360, 130.0), (480, 140.0)]));
+    assert_eq!(ramp(0, 480, 100.0, 140.0, TempoCurve::EaseIn, 120),
+        usec(&[(0, 100.0), (120, 102.5), (240, 110.0), (360, 122.5), (480, 140.0)]));
+    assert_eq!(ramp(0, 480, 140.0, 100.0, TempoCurve::EaseOut, 120),
+        usec(&[(0, 140.0), (120, 122.5), (240, 110.0), (360, 102.5), (480, 100.0)]));
+    // steps which would not change anything
+    assert_eq!(ramp(0, 480, 97.3, 97.3, TempoCurve::Linear, 60), usec(&[(0, 97.3)]));
+
+    let mut tempo = TempoInfo::new(usec(&[(0, 100.0), (240, 90.0), (960, 80.0)]), false);
+    tempo.insert_ramp(0, 480, 140.0, TempoCurve::Linear, 240);
+    assert_eq!(tempo.changes, usec(&[(0, 100.0), (240, 120.0), (480, 140.0), (960, 80.0)]));
+    tempo.set(480, usec_per_quarter(150.0));
+    assert_eq!(tempo.change_at(480), Some((480, 400000)));
+    assert_eq!(tempo.changes.len(), 4);
+}
+
+#[test]
+fn tempo_changes_are_written_to_the_conductor_track() {
+    let mut ws = super::MidiWorkspace::empty();
+    assert_eq!(ws.create_tempo_info(0).unwrap().changes, vec![(0, DEFAULT_TEMPO)]);
+    let mut tempo = TempoInfo::new(vec![(0, DEFAULT_TEMPO)], false);
+    tempo.insert_ramp(0, 1920, 60.0, TempoCurve::Linear, 480);
+    ws.set_tempo_info(&tempo).unwrap();
+    assert!(ws.is_modified());
+    assert_eq!(ws.create_tempo_info(0).unwrap().changes, vec![(0, 500000), (480, 571429), (960, 666667), (1440, 800000), (1920, 1000000)]);
+    let track = ws.events_abs_tick(0).unwrap();
+    assert!(track.events().last().unwrap().is_end_of_track());
+    assert_eq!(ws.create_time_signature_info(0).unwrap().time_signature(0), Some((4, 4)));
+}
+
+#[test]
+fn fractional_tempos_are_kept() {
+    assert_eq!(decode(&[0x07, 0xa1, 0x20]), Some(500000));
+    assert_eq!(decode(&[0x09, 0x68, 0xca]), Some(616650));
+    assert_eq!(decode(&[0, 0, 0]), None);
+    assert_eq!(decode(&[0x07, 0xa1]), None);
+    assert_eq!(format_bpm(500000), "120");
+    assert_eq!(format_bpm(616650), "97.3");
+    assert_eq!(usec_per_quarter(97.3), 616650);
+
+    let ws = super::MidiWorkspace::from_smf_file("test_midi0.mid").unwrap();
+    assert_eq!(ws.create_tempo_info(0).unwrap().changes, vec![(0, 500000)]);
+
+    let mut ws = super::MidiWorkspace::from_smf_file("test_corpus/tempo.mid").unwrap();
+    let tempo = ws.create_tempo_info(0).unwrap();
+    assert_eq!(tempo.changes, vec![(0, 616650), (1920, 991736), (3840, 500000)]);
+    let bpms: Vec<String> = tempo.changes.iter().map(|&(_, usec)| format_bpm(usec)).collect();
+    assert_eq!(bpms, vec!["97.3", "60.5", "120"]);
+    let expected = 4.0 * 0.616650 + 4.0 * 0.991736 + 0.5 * 0.5;
+    assert!((ws.seconds_at(4080).unwrap() - expected).abs() < 1e-9);
+
+    // written back as they were read
+    let original = std::fs::read("test_corpus/tempo.mid").unwrap();
+    ws.set_tempo_info(&tempo).unwrap();
+    let mut bytes = Vec::new();
+    ws.write_all(&mut bytes).unwrap();
+    assert_eq!(bytes, original);
+}
@@ -1,8 +1,19 @@
 use rimd::MidiMessage;
 
+/// channel 10, which GM reserves for percussion
+pub const DRUM_CHANNEL: u8 = 9;
+
 /// returns (channel, note, velocity)
 pub fn note_on(msg: &MidiMessage) -> Option<(u8, u8, u8)> {
-    let data = &msg.data;
+    note_on_bytes(&msg.data)
+}
+
+pub fn note_off(msg: &MidiMessage) -> Option<(u8, u8, u8)> {
+    note_off_bytes(&msg.data)
+}
+
+/// same as `note_on` but works on raw midi bytes.
+pub fn note_on_bytes(data: &[u8]) -> Option<(u8, u8, u8)> {
     if data.len() != 3 {
         return None;
     }
@@ -22,8 +33,8 @@ pub fn note_on(msg: &MidiMessage) -> Option<(u8, u8, u8)> {
     None
 }
 
-pub fn note_off(msg: &MidiMessage) -> Option<(u8, u8, u8)> {
-    let data = &msg.data;
+/// same as `note_off` but works on raw midi bytes.
+pub fn note_off_bytes(data: &[u8]) -> Option<(u8, u8, u8)> {
     if data.len() != 3 {
         return None;
     }
@@ -45,3 +56,65 @@ pub fn note_off(msg: &MidiMessage) -> Option<(u8, u8, u8)> {
 
     None
 }
+
+/// rounds `abs_tick` to the nearest multiple of `unit`.
+pub fn quantize_tick(abs_tick: u64, unit: u64) -> u64 {
+    let diff = abs_tick % unit;
+    if diff > (unit / 2) {
+        abs_tick + unit - diff
+    } else {
+        abs_tick - diff
+    }
+}
+
+/// returns (Type, Data) strings describing the event.
+pub fn describe_event(event: &rimd::Event) -> (String, String) {
+    match event {
+        rimd::Event::Midi(msg) => {
+            if let Some((_, note, velocity)) = note_on(msg) {
+                (String::from("note on"), format!("{} {}", note, velocity))
+            } else if let Some((_, note, _)) = note_off(msg) {
+                (String::from("note off"), format!("{}", note))
+            } else {
+                (String::from("midi message"), format!("{:?}", msg.data.iter().take(5).collect::<Vec<&u8>>()))
+            }
+        },
+        rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::TempoSetting => {
+            let data = match super::tempo::decode(&meta.data) {
+                Some(usec) => format!("{} bpm", super::tempo::format_bpm(usec)),
+                None => format!("{:?}", meta.data)
+            };
+            (format!("{:?}", meta.command), data)
+        },
+        rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::TimeSignature => {
+            let data = match super::meter::TimeSignature::from_meta_data(&meta.data) {
+                Some(ts) => format!("{}, {} clocks per click, {} 32nds per quarter", ts, ts.clocks_per_click, ts.notated_32nds),
+                None => format!("{:?}", meta.data)
+            };
+            (format!("{:?}", meta.command), data)
+        },
+        rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::KeySignature => {
+            let data = match super::key::KeySignature::from_meta_data(&meta.data) {
+                Some(key) => key.to_string(),
+                None => format!("{:?}", meta.data)
+            };
+            (format!("{:?}", meta.command), data)
+        },
+        rimd::Event::Meta(meta) if meta.command == rimd::MetaCommand::Unknown && !meta.data.is_empty() => {
+            // the type byte is kept in front of the data
+            (format!("Unknown (0x{:02X})", meta.data[0]), format!("{:?}", meta.data.iter().skip(1).take(5).collect::<Vec<&u8>>()))
+        },
+        rimd::Event::Meta(meta) if is_text(meta.command) => {
+            (format!("{:?}", meta.command), format!("{:?}", String::from_utf8_lossy(&meta.data)))
+        },
+        rimd::Event::Meta(meta) => {
+            (format!("{:?}", meta.command), format!("{:?}", meta.data.iter().take(5).collect::<Vec<&u8>>()))
+        }
+    }
+}
+
+/// whether the data of meta events of type `command` is text
+pub fn is_text(command: rimd::MetaCommand) -> bool {
+    use rimd::MetaCommand::*;
+    matches!(command, TextEvent | CopyrightNotice | SequenceOrTrackName | InstrumentName | LyricText | MarkerText | CuePoint)
+}
@@ -0,0 +1,120 @@
+//! Writes standard midi files in a canonical form: no running status,
+//! shortest delta times, SysEx with its length. The counterpart of `reader`.
+
+use crate::error::{Error, Result};
+use rimd::{Event, MetaCommand, MetaEvent, SMFFormat, SMF};
+use std::io::Write;
+
+/// the largest number a variable length quantity can hold
+const MAX_VARLEN: u64 = 0x0FFF_FFFF;
+
+pub fn write_smf<W: Write>(smf: &SMF, writer: &mut W) -> Result<()> {
+    let format: u16 = match smf.format {
+        SMFFormat::Single => 0,
+        SMFFormat::MultiTrack => 1,
+        SMFFormat::MultiSong => 2,
+    };
+    writer.write_all(b"MThd")?;
+    writer.write_all(&6u32.to_be_bytes())?;
+    writer.write_all(&format.to_be_bytes())?;
+    writer.write_all(&(smf.tracks.len() as u16).to_be_bytes())?;
+    writer.write_all(&smf.division.to_be_bytes())?;
+
+    for (i, track) in smf.tracks.iter().enumerate() {
+        let mut data = Vec::new();
+        // delta time of dropped events, added to the next one
+        let mut carry = 0;
+        for event in &track.events {
+            let vtime = event.vtime + carry;
+            carry = 0;
+            if vtime > MAX_VARLEN {
+                return Err(Error::InvalidArgument(format!("track {}: delta time {} is too large for a midi file", i, vtime)));
+            }
+            match &event.event {
+                Event::Midi(msg) => {
+                    write_varlen(&mut data, vtime);
+                    match msg.data.first() {
+                        // SysEx and escaped bytes carry their length in the file
+                        Some(&status) if status == 0xF0 || status == 0xF7 => {
+                            data.push(status);
+                            write_varlen(&mut data, (msg.data.len() - 1) as u64);
+                            data.extend(&msg.data[1..]);
+                        },
+                        _ => data.extend(&msg.data)
+                    }
+                },
+                Event::Meta(meta) => match meta_bytes(meta) {
+                    Some((command, meta_data)) => {
+                        write_varlen(&mut data, vtime);
+                        data.push(0xFF);
+                        data.push(command);
+                        write_varlen(&mut data, meta_data.len() as u64);
+                        data.extend(meta_data);
+                    },
+                    None => {
+                        warn!("dropped a meta event of unknown type");
+                        carry = vtime;
+                    }
+                }
+            }
+        }
+        writer.write_all(b"MTrk")?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&data)?;
+    }
+    Ok(())
+}
+
+/// The type byte and the data of a meta event, as they are written. Meta events
+/// of a type rimd does not know carry the type byte in front of their data.
+pub(crate) fn meta_bytes(meta: &MetaEvent) -> Option<(u8, &[u8])> {
+    match meta_command_byte(meta.command) {
+        Some(command) => Some((command, &meta.data)),
+        None => meta.data.split_first().map(|(command, data)| (*command, data))
+    }
+}
+
+fn meta_command_byte(command: MetaCommand) -> Option<u8> {
+    match command {
+        MetaCommand::SequenceNumber => Some(0x00),
+        MetaCommand::TextEvent => Some(0x01),
+        MetaCommand::CopyrightNotice => Some(0x02),
+        MetaCommand::SequenceOrTrackName => Some(0x03),
+        MetaCommand::InstrumentName => Some(0x04),
+        MetaCommand::LyricText => Some(0x05),
+        MetaCommand::MarkerText => Some(0x06),
+        MetaCommand::CuePoint => Some(0x07),
+        MetaCommand::MIDIChannelPrefixAssignment => Some(0x20),
+        MetaCommand::MIDIPortPrefixAssignment => Some(0x21),
+        MetaCommand::EndOfTrack => Some(0x2F),
+        MetaCommand::TempoSetting => Some(0x51),
+        MetaCommand::SMPTEOffset => Some(0x54),
+        MetaCommand::TimeSignature => Some(0x58),
+        MetaCommand::KeySignature => Some(0x59),
+        MetaCommand::SequencerSpecificEvent => Some(0x7F),
+        MetaCommand::Unknown => None,
+    }
+}
+
+fn write_varlen(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push(((value & 0x7F) as u8) | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+#[test]
+fn varlen_encoding() {
+    let encode = |v| {
+        let mut out = Vec::new();
+        write_varlen(&mut out, v);
+        out
+    };
+    assert_eq!(encode(0), vec![0x00]);
+    assert_eq!(encode(0x7F), vec![0x7F]);
+    assert_eq!(encode(0x80), vec![0x81, 0x00]);
+    assert_eq!(encode(0x0FFFFFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+}